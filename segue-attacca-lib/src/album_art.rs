@@ -0,0 +1,71 @@
+use std::{
+    fs::{create_dir_all, read_dir},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use tracing::warn;
+
+use crate::{files::write_atomically, tags::EmbeddedPicture};
+
+/// where extracted art goes, inside the library folder
+pub const ART_CACHE: &str = "album_art";
+
+/// file names that count as a folder's cover, best first
+const FOLDER_ART_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+const FOLDER_ART_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// The art for a track: its embedded cover if it has one, otherwise a cover image next to it.
+pub fn find(library: &Path, track: &Path, cover: Option<&EmbeddedPicture>) -> Option<PathBuf> {
+    if let Some(cover) = cover {
+        match cache_picture(library, cover) {
+            Ok(path) => return Some(path),
+            Err(e) => warn!("couldn't cache album art of {}: {e}", track.display()),
+        }
+    }
+    folder_art(track)
+}
+
+/// Writes a picture into the art cache. It's named after what's in it, so tracks that share a
+/// cover share the file and it's only written once.
+pub fn cache_picture(library: &Path, picture: &EmbeddedPicture) -> Result<PathBuf> {
+    let extension = match picture.media_type.as_ref() {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/bmp" => "bmp",
+        "image/webp" => "webp",
+        _ => "img",
+    };
+    let dir = library.join(ART_CACHE);
+    let path = dir.join(format!(
+        "{}.{extension}",
+        blake3::hash(&picture.data).to_hex()
+    ));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    create_dir_all(&dir)?;
+    // so a half written file never looks cached
+    write_atomically(&path, &picture.data)?;
+    Ok(path)
+}
+
+/// `cover.jpg`, `folder.png` and the like in the track's folder, whatever their case
+pub fn folder_art(track: &Path) -> Option<PathBuf> {
+    let dir = read_dir(track.parent()?).ok()?;
+    dir.flatten()
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?.to_lowercase();
+            let extension = path.extension()?.to_str()?.to_lowercase();
+            if !FOLDER_ART_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let rank = FOLDER_ART_NAMES.iter().position(|name| *name == stem)?;
+            Some((rank, path))
+        })
+        .min()
+        .map(|(_, path)| path)
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, metadata},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::{Result, eyre::bail};
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error, formats::FormatOptions,
+    io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+use tracing::warn;
+
+use crate::formats;
+
+mod key;
+mod loudness;
+mod silence;
+mod tempo;
+
+pub use key::Key;
+use key::KeyDetector;
+use loudness::LoudnessMeter;
+pub use loudness::{Loudness, REFERENCE_LUFS, ReplayGain};
+use silence::SilenceDetector;
+pub use silence::Sound;
+pub use tempo::Tempo;
+use tempo::TempoDetector;
+
+/// Bumped whenever analysis learns something new, so files analysed before then get done
+/// again.
+pub const ANALYSIS_VERSION: u32 = 5;
+
+/// What analysing a file found out about it. It's cached by the file's hash, so it's only done
+/// again when the file changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Analysis {
+    /// the `ANALYSIS_VERSION` it was done with
+    pub version: u32,
+    pub properties: AudioProperties,
+    /// `None` if it has no steady beat, or couldn't be decoded
+    #[serde(default)]
+    pub tempo: Option<Tempo>,
+    /// `None` if there's nothing tonal in it, or it couldn't be decoded
+    #[serde(default)]
+    pub key: Option<Key>,
+    /// `None` if it's silent, or couldn't be decoded
+    #[serde(default)]
+    pub loudness: Option<Loudness>,
+    /// where the silence at either end stops, `None` if it's silent throughout
+    #[serde(default)]
+    pub sound: Option<Sound>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AudioProperties {
+    pub duration: Duration,
+    pub sample_rate: u32,
+    /// only lossless codecs have one
+    pub bit_depth: Option<u32>,
+    pub channels: u32,
+    pub codec: Box<str>,
+    /// bits per second over the whole file, counting its tags and art
+    pub bitrate: Option<u32>,
+}
+
+impl AudioProperties {
+    /// the properties of the part of the file between `start` and `end`, for cue tracks and
+    /// cue points
+    pub fn range(&self, start: Duration, end: Option<Duration>) -> Self {
+        let end = end.unwrap_or(self.duration).min(self.duration);
+        Self {
+            duration: end.saturating_sub(start),
+            ..self.clone()
+        }
+    }
+}
+
+/// Analysis results by file hash, shared between the library and the jobs filling it in.
+#[derive(Debug, Default)]
+pub struct AnalysisCache {
+    pub results: HashMap<Box<str>, Analysis>,
+    /// files that are waiting to be analysed, so they aren't queued twice
+    pub(crate) queued: HashSet<Box<str>>,
+    /// how loud each album is, by its name and album artist, worked out from its tracks'
+    /// results whenever they change
+    pub(crate) albums: HashMap<(Arc<str>, Option<Arc<str>>), Loudness>,
+}
+
+/// Decodes the file the whole way through to work out everything in `Analysis`. Files there's
+/// no decoder for still get the properties their headers give.
+pub fn analyze(path: &Path) -> Result<Analysis> {
+    let size = metadata(path)?.len();
+    let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let Some(track) = format.default_track() else {
+        bail!("there's no audio in it");
+    };
+    let id = track.id;
+    let params = track.codec_params.clone();
+    let Some(sample_rate) = params.sample_rate else {
+        bail!("it doesn't say what its sample rate is");
+    };
+    let codecs = symphonia::default::get_codecs();
+    // symphonia doesn't know the names of codecs it can't decode
+    let codec = codecs
+        .get_codec(params.codec)
+        .map(|codec| codec.short_name)
+        .or_else(|| formats::identify(path).map(|format| format.name))
+        .unwrap_or("unknown");
+
+    let mut tempo = TempoDetector::new(sample_rate);
+    let mut key = KeyDetector::new(sample_rate);
+    let mut loudness = LoudnessMeter::new(sample_rate);
+    let mut silence = SilenceDetector::new(sample_rate);
+    let mut decoded_frames = 0;
+    match codecs.make(&params, &DecoderOptions::default()) {
+        Ok(mut decoder) => {
+            let mut buffer: Option<SampleBuffer<f32>> = None;
+            loop {
+                let packet = match format.next_packet() {
+                    Ok(packet) => packet,
+                    Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                if packet.track_id() != id {
+                    continue;
+                }
+                let decoded = match decoder.decode(&packet) {
+                    Ok(decoded) => decoded,
+                    // a broken packet here and there doesn't make the rest wrong
+                    Err(Error::DecodeError(_)) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let spec = *decoded.spec();
+                let frames = decoded.frames();
+                decoded_frames += frames as u64;
+                let buffer = match &mut buffer {
+                    Some(buffer) if buffer.capacity() >= frames * spec.channels.count() => buffer,
+                    _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+                };
+                buffer.copy_interleaved_ref(decoded);
+                tempo.push(buffer.samples(), spec.channels.count());
+                key.push(buffer.samples(), spec.channels.count());
+                loudness.push(buffer.samples(), spec.channels.count());
+                silence.push(buffer.samples(), spec.channels.count());
+            }
+        }
+        Err(e) if params.n_frames.is_some() => warn!("can't decode {}: {e}", path.display()),
+        Err(e) => return Err(e.into()),
+    }
+    // what the headers say is more exact, decoders can pad the end
+    let frames = params.n_frames.unwrap_or(decoded_frames);
+
+    let rate = u64::from(sample_rate);
+    let duration = Duration::from_secs(frames / rate)
+        + Duration::from_nanos(frames % rate * 1_000_000_000 / rate);
+    let bitrate =
+        (!duration.is_zero()).then(|| (size as f64 * 8.0 / duration.as_secs_f64()).round() as u32);
+
+    Ok(Analysis {
+        version: ANALYSIS_VERSION,
+        properties: AudioProperties {
+            duration,
+            sample_rate,
+            bit_depth: params.bits_per_sample,
+            channels: params
+                .channels
+                .map_or(0, |channels| channels.count() as u32),
+            codec: codec.into(),
+            bitrate,
+        },
+        tempo: tempo.finish(),
+        key: key.finish(),
+        loudness: loudness.finish(),
+        sound: silence.finish(),
+    })
+}
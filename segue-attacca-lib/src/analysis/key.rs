@@ -0,0 +1,383 @@
+use std::{f64::consts::PI, fmt::Display, str::FromStr};
+
+use color_eyre::{Report, Result, eyre::bail};
+use serde::{Deserialize, Serialize};
+
+/// roughly what the audio is brought down to before listening for notes, which keeps
+/// everything up to a few octaves above middle C
+const LISTEN_RATE: u32 = 11025;
+/// samples per chroma frame at `LISTEN_RATE`, about a third of a second
+const FRAME: usize = 4096;
+/// the notes listened for, as midi numbers: C2 to B6
+const LOWEST_NOTE: u32 = 36;
+const HIGHEST_NOTE: u32 = 95;
+/// frames quieter than this don't count, about -60 dBFS
+const SILENCE: f64 = 1e-6;
+
+/// how much each scale degree belongs in a major and a minor key, from Krumhansl and Kessler's
+/// probe tone experiments
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+const MAJOR_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+const MINOR_NAMES: [&str; 12] = [
+    "Cm", "C#m", "Dm", "Ebm", "Em", "Fm", "F#m", "Gm", "G#m", "Am", "Bbm", "Bm",
+];
+
+/// A musical key. Shown the usual way, like `Am` or `F#`, or on the Camelot wheel, like `8A`,
+/// where keys next to each other mix well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Key {
+    /// the tonic, 0 for C up to 11 for B
+    pub pitch: u8,
+    pub minor: bool,
+}
+
+impl Key {
+    /// its number on the Camelot wheel, 1 to 12. Going up one is going up a fifth.
+    pub fn camelot_number(&self) -> u8 {
+        // minor keys share their number with their relative major
+        let major = if self.minor {
+            (self.pitch + 3) % 12
+        } else {
+            self.pitch
+        };
+        let fifths = major * 7 % 12;
+        (fifths + 7) % 12 + 1
+    }
+
+    /// like `8A`, A for minor and B for major
+    pub fn camelot(&self) -> String {
+        let letter = if self.minor { 'A' } else { 'B' };
+        format!("{}{letter}", self.camelot_number())
+    }
+
+    fn from_camelot(number: u8, minor: bool) -> Self {
+        let fifths = (number + 4) % 12;
+        let major = fifths * 7 % 12;
+        let pitch = if minor { (major + 9) % 12 } else { major };
+        Self { pitch, minor }
+    }
+
+    /// Whether going from one to the other sounds right: the same key, its relative major or
+    /// minor, or a fifth up or down.
+    pub fn is_compatible(&self, other: &Key) -> bool {
+        let (a, b) = (self.camelot_number(), other.camelot_number());
+        let distance = (a + 12 - b) % 12;
+        if self.minor == other.minor {
+            matches!(distance, 0 | 1 | 11)
+        } else {
+            distance == 0
+        }
+    }
+
+    /// where it goes when sorting by key, around the Camelot wheel
+    pub fn wheel_position(&self) -> u8 {
+        self.camelot_number() * 2 + u8::from(!self.minor)
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = if self.minor { MINOR_NAMES } else { MAJOR_NAMES };
+        write!(f, "{}", names[self.pitch as usize % 12])
+    }
+}
+
+/// Reads either notation: `8A`, `Am`, `A minor`, `Bbm`, `C#`, `F# major` and so on.
+impl FromStr for Key {
+    type Err = Report;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let text = text.trim();
+        if let Some(number) = text
+            .get(..text.len().saturating_sub(1))
+            .and_then(|number| number.parse::<u8>().ok())
+        {
+            let minor = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+                Some('A') => true,
+                Some('B') => false,
+                _ => bail!("{text} isn't a key"),
+            };
+            if !(1..=12).contains(&number) {
+                bail!("the Camelot wheel only goes up to 12");
+            }
+            return Ok(Self::from_camelot(number, minor));
+        }
+
+        let mut chars = text.chars();
+        let pitch = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => bail!("{text} isn't a key"),
+        };
+        let mut rest = chars.as_str();
+        let pitch = if let Some(after) = rest.strip_prefix(['#', '♯']) {
+            rest = after;
+            (pitch + 1) % 12
+        } else if let Some(after) = rest.strip_prefix(['b', '♭']) {
+            rest = after;
+            (pitch + 11) % 12
+        } else {
+            pitch
+        };
+        let minor = match rest.trim().to_lowercase().as_str() {
+            "" | "maj" | "major" => false,
+            "m" | "min" | "minor" => true,
+            _ => bail!("{text} isn't a key"),
+        };
+        Ok(Self { pitch, minor })
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = Report;
+
+    fn try_from(text: String) -> Result<Self> {
+        text.parse()
+    }
+}
+
+/// Works out a file's key from its samples as they're decoded. It adds up how strongly each
+/// of the twelve notes sounds over the whole track, and picks the key whose scale fits that
+/// best.
+#[derive(Debug)]
+pub struct KeyDetector {
+    /// how many samples are averaged into one, to get down to about `LISTEN_RATE`
+    decimation: usize,
+    pending: f64,
+    pending_count: usize,
+    frame: Vec<f64>,
+    window: Vec<f64>,
+    /// for each note listened for, the goertzel coefficient and its pitch class
+    notes: Vec<(f64, usize)>,
+    chroma: [f64; 12],
+}
+
+impl KeyDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        let decimation = (sample_rate / LISTEN_RATE).max(1) as usize;
+        let rate = sample_rate as f64 / decimation as f64;
+        let notes = (LOWEST_NOTE..=HIGHEST_NOTE)
+            .map(|note| {
+                let frequency = 440.0 * 2f64.powf((note as f64 - 69.0) / 12.0);
+                let coefficient = 2.0 * (2.0 * PI * frequency / rate).cos();
+                (coefficient, note as usize % 12)
+            })
+            .collect();
+        let window = (0..FRAME)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / FRAME as f64).cos())
+            .collect();
+        Self {
+            decimation,
+            pending: 0.0,
+            pending_count: 0,
+            frame: Vec::with_capacity(FRAME),
+            window,
+            notes,
+            chroma: [0.0; 12],
+        }
+    }
+
+    /// takes interleaved samples
+    pub fn push(&mut self, samples: &[f32], channels: usize) {
+        for frame in samples.chunks_exact(channels.max(1)) {
+            self.pending += frame.iter().sum::<f32>() as f64 / frame.len() as f64;
+            self.pending_count += 1;
+            if self.pending_count < self.decimation {
+                continue;
+            }
+            self.frame.push(self.pending / self.decimation as f64);
+            self.pending = 0.0;
+            self.pending_count = 0;
+            if self.frame.len() == FRAME {
+                self.listen();
+                self.frame.clear();
+            }
+        }
+    }
+
+    /// adds how strong each note is in the current frame to the chroma
+    fn listen(&mut self) {
+        let energy = self.frame.iter().map(|sample| sample * sample).sum::<f64>() / FRAME as f64;
+        if energy < SILENCE {
+            return;
+        }
+        let windowed: Vec<f64> = self
+            .frame
+            .iter()
+            .zip(&self.window)
+            .map(|(sample, weight)| sample * weight)
+            .collect();
+        let mut chroma = [0.0; 12];
+        for &(coefficient, pitch_class) in &self.notes {
+            let (mut previous, mut before) = (0.0, 0.0);
+            for sample in &windowed {
+                let current = sample + coefficient * previous - before;
+                before = previous;
+                previous = current;
+            }
+            let power = previous * previous + before * before - coefficient * previous * before;
+            chroma[pitch_class] += power.max(0.0).sqrt();
+        }
+        // every frame gets the same say, however loud it is
+        let loudest = chroma.iter().copied().fold(0.0, f64::max);
+        if loudest > 0.0 {
+            for (total, strength) in self.chroma.iter_mut().zip(chroma) {
+                *total += strength / loudest;
+            }
+        }
+    }
+
+    /// `None` if nothing in the track sounded like notes
+    pub fn finish(self) -> Option<Key> {
+        if self.chroma.iter().all(|strength| *strength <= 0.0) {
+            return None;
+        }
+        (0..12u8)
+            .flat_map(|pitch| [(pitch, false), (pitch, true)])
+            .map(|(pitch, minor)| {
+                let profile = if minor { MINOR_PROFILE } else { MAJOR_PROFILE };
+                let rotated: Vec<f64> = (0..12)
+                    .map(|note| profile[(note + 12 - pitch as usize) % 12])
+                    .collect();
+                (Key { pitch, minor }, correlation(&self.chroma, &rotated))
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .filter(|(_, fit)| *fit > 0.0)
+            .map(|(key, _)| key)
+    }
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / a.len() as f64;
+    let mean_b = b.iter().sum::<f64>() / b.len() as f64;
+    let (mut product, mut square_a, mut square_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        product += (x - mean_a) * (y - mean_b);
+        square_a += (x - mean_a).powi(2);
+        square_b += (y - mean_b).powi(2);
+    }
+    if square_a == 0.0 || square_b == 0.0 {
+        return 0.0;
+    }
+    product / (square_a * square_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the whole wheel, 1A to 12A then 1B to 12B
+    const WHEEL: [&str; 24] = [
+        "G#m", "Ebm", "Bbm", "Fm", "Cm", "Gm", "Dm", "Am", "Em", "Bm", "F#m", "C#m", "B", "F#",
+        "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E",
+    ];
+
+    fn key(text: &str) -> Key {
+        text.parse().unwrap()
+    }
+
+    /// a few seconds of the notes together, at midi numbers
+    fn chord(sample_rate: u32, notes: &[u32]) -> Vec<f32> {
+        (0..sample_rate * 4)
+            .map(|i| {
+                let time = i as f64 / sample_rate as f64;
+                let sum: f64 = notes
+                    .iter()
+                    .map(|&note| {
+                        let frequency = 440.0 * 2f64.powf((note as f64 - 69.0) / 12.0);
+                        (2.0 * PI * frequency * time).sin()
+                    })
+                    .sum();
+                (sum * 0.2) as f32
+            })
+            .collect()
+    }
+
+    fn detect(sample_rate: u32, notes: &[u32]) -> Option<Key> {
+        let mut detector = KeyDetector::new(sample_rate);
+        detector.push(&chord(sample_rate, notes), 1);
+        detector.finish()
+    }
+
+    #[test]
+    fn every_key_has_its_place_on_the_wheel() {
+        for (i, name) in WHEEL.into_iter().enumerate() {
+            let camelot = format!("{}{}", i % 12 + 1, if i < 12 { 'A' } else { 'B' });
+            assert_eq!(key(name).camelot(), camelot, "{name}");
+            assert_eq!(key(&camelot).to_string(), name, "{camelot}");
+            assert_eq!(key(&camelot.to_lowercase()), key(name));
+        }
+    }
+
+    #[test]
+    fn both_notations_are_read() {
+        assert_eq!(
+            key("8A"),
+            Key {
+                pitch: 9,
+                minor: true
+            }
+        );
+        assert_eq!(
+            key("C#"),
+            Key {
+                pitch: 1,
+                minor: false
+            }
+        );
+        assert_eq!(
+            key("C♯m"),
+            Key {
+                pitch: 1,
+                minor: true
+            }
+        );
+        assert_eq!(key("Bb minor"), key("A#m"));
+        assert_eq!(key(" F# major "), key("Gb"));
+        for text in ["13A", "0B", "8C", "", "H", "Cx", "C#mm"] {
+            assert!(text.parse::<Key>().is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn neighbours_on_the_wheel_are_compatible() {
+        let am = key("Am");
+        for other in ["Am", "C", "Em", "Dm"] {
+            assert!(am.is_compatible(&key(other)), "{other}");
+            assert!(key(other).is_compatible(&am), "{other}");
+        }
+        for other in ["G", "F", "Bm", "F#m", "Eb"] {
+            assert!(!am.is_compatible(&key(other)), "{other}");
+        }
+        // 12 and 1 are next to each other too
+        assert!(key("12A").is_compatible(&key("1A")));
+    }
+
+    #[test]
+    fn triads_are_heard_in_their_key() {
+        // C E G and A C E
+        assert_eq!(detect(44_100, &[60, 64, 67]).unwrap().camelot(), "8B");
+        assert_eq!(detect(48_000, &[57, 60, 64]).unwrap().camelot(), "8A");
+        assert_eq!(detect(44_100, &[]), None);
+    }
+}
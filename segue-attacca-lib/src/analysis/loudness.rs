@@ -0,0 +1,418 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+/// the level ReplayGain gains bring things to, in LUFS
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// loudness is measured over 100 ms steps, and blocks of several of them
+const STEPS_PER_SECOND: f64 = 10.0;
+/// momentary blocks are 400 ms, overlapping by 300 ms
+const MOMENTARY_STEPS: usize = 4;
+/// short term blocks are 3 s, one every second, for the loudness range
+const SHORT_TERM_STEPS: usize = 30;
+const SHORT_TERM_HOP: usize = 10;
+/// blocks quieter than this are silence and never count
+const ABSOLUTE_GATE: f64 = -70.0;
+/// how far under the ungated loudness blocks can be and still count, for integrated loudness
+/// and the loudness range
+const INTEGRATED_GATE: f64 = -10.0;
+const RANGE_GATE: f64 = -20.0;
+/// taps per phase of the true peak interpolator
+const TAPS: usize = 12;
+
+/// How loud a file is, measured the EBU R128 way.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /// in LUFS
+    pub integrated: f64,
+    /// in LU, how far apart the quiet and loud parts are
+    pub range: f64,
+    /// in dBTP, the highest the signal gets between samples as well as on them
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// the gain in dB that brings it to `REFERENCE_LUFS`
+    pub fn gain(&self) -> f64 {
+        REFERENCE_LUFS - self.integrated
+    }
+
+    /// the true peak as a linear amplitude
+    pub fn peak(&self) -> f64 {
+        10f64.powf(self.true_peak / 20.0)
+    }
+}
+
+/// ReplayGain values, from a file's tags or worked out from its `Loudness`. Gains are in dB and
+/// bring it to `REFERENCE_LUFS`, peaks are linear with 1 being full scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    /// takes whatever this doesn't have yet from `other`
+    pub fn or(self, other: ReplayGain) -> Self {
+        Self {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        }
+    }
+}
+
+/// a biquad in transposed direct form II
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filter from BS.1770, a high shelf for how the head boosts the highs and a
+/// high pass for how little the lows count. Its coefficients are only given for 48 kHz, so
+/// they're worked out again for other rates the way libebur128 does.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// how much each channel counts. Surrounds count for more, and the LFE not at all, going by
+/// the usual 5.1 order.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4 | 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// Measures loudness, loudness range and true peak from a file's samples as they're decoded.
+#[derive(Debug)]
+pub struct LoudnessMeter {
+    step_len: usize,
+    filters: Vec<[Biquad; 2]>,
+    /// the sum of squares of each channel over the step that's filling up
+    energy: Vec<f64>,
+    filled: usize,
+    /// the weighted mean square of every 100 ms step so far
+    steps: Vec<f64>,
+    peak: TruePeak,
+    sample_rate: u32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            step_len: ((sample_rate as f64 / STEPS_PER_SECOND).round() as usize).max(1),
+            filters: Vec::new(),
+            energy: Vec::new(),
+            filled: 0,
+            steps: Vec::new(),
+            peak: TruePeak::new(sample_rate),
+            sample_rate,
+        }
+    }
+
+    /// takes interleaved samples
+    pub fn push(&mut self, samples: &[f32], channels: usize) {
+        let channels = channels.max(1);
+        if self.filters.len() != channels {
+            self.filters = vec![k_weighting(self.sample_rate); channels];
+            self.energy = vec![0.0; channels];
+        }
+        self.peak.push(samples, channels);
+        for frame in samples.chunks_exact(channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let weighted = high_pass.process(shelf.process(*sample as f64));
+                self.energy[channel] += weighted * weighted;
+            }
+            self.filled += 1;
+            if self.filled == self.step_len {
+                let power = self
+                    .energy
+                    .iter()
+                    .enumerate()
+                    .map(|(channel, energy)| channel_weight(channel, channels) * energy)
+                    .sum::<f64>()
+                    / self.step_len as f64;
+                self.steps.push(power);
+                self.energy.fill(0.0);
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// `None` when the file is silent, or too short to measure
+    pub fn finish(self) -> Option<Loudness> {
+        let momentary = blocks(&self.steps, MOMENTARY_STEPS, 1);
+        let gated = gate(&momentary, INTEGRATED_GATE);
+        if gated.is_empty() {
+            return None;
+        }
+        let integrated = lufs(gated.iter().sum::<f64>() / gated.len() as f64);
+
+        // the loudness range is the spread between the 10th and 95th percentile of the short
+        // term loudness, from EBU Tech 3342
+        let mut short_term: Vec<f64> = gate(
+            &blocks(&self.steps, SHORT_TERM_STEPS, SHORT_TERM_HOP),
+            RANGE_GATE,
+        )
+        .into_iter()
+        .map(lufs)
+        .collect();
+        short_term.sort_by(f64::total_cmp);
+        let percentile =
+            |amount: f64| short_term[((short_term.len() - 1) as f64 * amount).round() as usize];
+        let range = if short_term.len() < 2 {
+            0.0
+        } else {
+            percentile(0.95) - percentile(0.10)
+        };
+
+        Some(Loudness {
+            integrated,
+            range,
+            true_peak: 20.0 * self.peak.peak.max(f64::MIN_POSITIVE).log10(),
+        })
+    }
+}
+
+/// mean power over blocks `len` steps long, one every `hop` steps
+fn blocks(steps: &[f64], len: usize, hop: usize) -> Vec<f64> {
+    if steps.len() < len {
+        return Vec::new();
+    }
+    (len..=steps.len())
+        .step_by(hop)
+        .map(|end| steps[end - len..end].iter().sum::<f64>() / len as f64)
+        .collect()
+}
+
+/// the blocks that aren't silence and aren't more than `relative` under the rest
+fn gate(blocks: &[f64], relative: f64) -> Vec<f64> {
+    let audible: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|power| lufs(*power) > ABSOLUTE_GATE)
+        .collect();
+    if audible.is_empty() {
+        return audible;
+    }
+    let threshold = lufs(audible.iter().sum::<f64>() / audible.len() as f64) + relative;
+    audible
+        .into_iter()
+        .filter(|power| lufs(*power) > threshold)
+        .collect()
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.max(f64::MIN_POSITIVE).log10()
+}
+
+/// Finds the highest the signal gets between samples by oversampling it, four times below
+/// 96 kHz and twice below 192 kHz, as BS.1770 suggests.
+#[derive(Debug)]
+struct TruePeak {
+    factor: usize,
+    /// the interpolation filter split into one set of taps per phase
+    phases: Vec<[f64; TAPS]>,
+    /// the most any phase can amplify by, so quiet stretches can skip interpolating
+    bound: f64,
+    /// the last `TAPS` samples of each channel, newest first
+    history: Vec<[f64; TAPS]>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(sample_rate: u32) -> Self {
+        let factor = match sample_rate {
+            ..96_000 => 4,
+            96_000..192_000 => 2,
+            _ => 1,
+        };
+        // a windowed sinc cutting off at the original Nyquist frequency
+        let len = factor * TAPS;
+        let center = (len - 1) as f64 / 2.0;
+        let phases: Vec<[f64; TAPS]> = (0..factor)
+            .map(|phase| {
+                let mut taps = [0.0; TAPS];
+                for (tap, value) in taps.iter_mut().enumerate() {
+                    let n = phase + tap * factor;
+                    let x = (n as f64 - center) / factor as f64;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    let angle = 2.0 * PI * n as f64 / (len - 1) as f64;
+                    let blackman = 0.42 - 0.5 * angle.cos() + 0.08 * (2.0 * angle).cos();
+                    *value = sinc * blackman;
+                }
+                taps
+            })
+            .collect();
+        let bound = phases
+            .iter()
+            .map(|taps| taps.iter().map(|tap| tap.abs()).sum::<f64>())
+            .fold(1.0, f64::max);
+        Self {
+            factor,
+            phases,
+            bound,
+            history: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    fn push(&mut self, samples: &[f32], channels: usize) {
+        if self.history.len() != channels {
+            self.history = vec![[0.0; TAPS]; channels];
+        }
+        for frame in samples.chunks_exact(channels) {
+            for (history, sample) in self.history.iter_mut().zip(frame) {
+                let sample = *sample as f64;
+                history.copy_within(..TAPS - 1, 1);
+                history[0] = sample;
+                self.peak = self.peak.max(sample.abs());
+                if self.factor == 1 {
+                    continue;
+                }
+                let loudest = history.iter().fold(0.0, |max: f64, x| max.max(x.abs()));
+                if loudest * self.bound <= self.peak {
+                    continue;
+                }
+                for taps in &self.phases {
+                    let value: f64 = taps.iter().zip(history.iter()).map(|(t, x)| t * x).sum();
+                    self.peak = self.peak.max(value.abs());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `seconds` of a sine at `amplitude`, starting `phase` radians in
+    fn sine(
+        sample_rate: u32,
+        seconds: f64,
+        frequency: f64,
+        amplitude: f64,
+        phase: f64,
+    ) -> Vec<f32> {
+        (0..(sample_rate as f64 * seconds) as usize)
+            .map(|i| {
+                let angle = 2.0 * PI * frequency * i as f64 / sample_rate as f64 + phase;
+                (amplitude * angle.sin()) as f32
+            })
+            .collect()
+    }
+
+    fn measure(sample_rate: u32, samples: &[f32], channels: usize) -> Option<Loudness> {
+        let mut meter = LoudnessMeter::new(sample_rate);
+        // in uneven pieces, the way a decoder hands them over
+        for chunk in samples.chunks(channels * 1000 + channels) {
+            meter.push(chunk, channels);
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn full_scale_sine_in_one_channel_is_minus_three() {
+        // the calibration from BS.1770: a 997 Hz sine at 0 dBFS in one channel is -3.01 LKFS
+        for sample_rate in [44_100, 48_000] {
+            let left = sine(sample_rate, 10.0, 997.0, 1.0, 0.0);
+            let stereo: Vec<f32> = left.iter().flat_map(|&sample| [sample, 0.0]).collect();
+            let loudness = measure(sample_rate, &stereo, 2).unwrap();
+            assert!(
+                (loudness.integrated + 3.01).abs() < 0.05,
+                "{sample_rate}: {loudness:?}"
+            );
+            assert!(loudness.range.abs() < 0.1, "{sample_rate}: {loudness:?}");
+            assert!(
+                loudness.true_peak.abs() < 0.1,
+                "{sample_rate}: {loudness:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn true_peak_is_found_between_samples() {
+        // a quarter of the sample rate, a quarter turn off the samples: every sample is at
+        // 0.707 of the amplitude, and the peaks all fall between them
+        let samples = sine(48_000, 1.0, 12_000.0, 0.5, PI / 4.0);
+        let sample_peak = samples.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        assert!((20.0 * (sample_peak as f64).log10() + 9.03).abs() < 0.01);
+
+        let loudness = measure(48_000, &samples, 1).unwrap();
+        assert!((loudness.true_peak + 6.02).abs() < 0.3, "{loudness:?}");
+        assert!((loudness.peak() - 0.5).abs() < 0.02, "{loudness:?}");
+    }
+
+    #[test]
+    fn silence_is_gated_out() {
+        assert_eq!(measure(48_000, &[0.0; 48_000 * 5], 1), None);
+        // too short for a single block
+        assert_eq!(
+            measure(48_000, &sine(48_000, 0.3, 997.0, 0.5, 0.0), 1),
+            None
+        );
+
+        // Silence around a tone doesn't bring its loudness down, which averaged over the whole
+        // 45 s would be nearly 10 dB lower. Only the blocks partly over the tone count for less.
+        let tone = sine(48_000, 5.0, 997.0, 0.1, 0.0);
+        let alone = measure(48_000, &tone, 1).unwrap();
+        let mut padded = vec![0.0; 48_000 * 20];
+        padded.extend(&tone);
+        padded.extend(vec![0.0; 48_000 * 20]);
+        let padded = measure(48_000, &padded, 1).unwrap();
+        assert!(
+            (padded.integrated - alone.integrated).abs() < 0.5,
+            "{padded:?} {alone:?}"
+        );
+        assert!((alone.integrated + 23.0).abs() < 0.1, "{alone:?}");
+    }
+}
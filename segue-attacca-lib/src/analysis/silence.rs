@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// anything quieter than this, in dBFS, counts as silence
+const THRESHOLD_DB: f32 = -60.0;
+/// how finely the edges of the sound are found
+const WINDOW: Duration = Duration::from_millis(10);
+
+/// Where the sound in a file starts and stops, leaving out the silence either side of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sound {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// Finds the leading and trailing silence in a file from its samples as they're decoded.
+#[derive(Debug)]
+pub struct SilenceDetector {
+    window_len: usize,
+    threshold: f32,
+    sample_rate: u32,
+    /// frames so far
+    frames: u64,
+    /// the first and last windows with something above the threshold in them
+    first: Option<u64>,
+    last: Option<u64>,
+}
+
+impl SilenceDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            window_len: ((sample_rate as f64 * WINDOW.as_secs_f64()).round() as usize).max(1),
+            threshold: 10f32.powf(THRESHOLD_DB / 20.0),
+            sample_rate,
+            frames: 0,
+            first: None,
+            last: None,
+        }
+    }
+
+    /// takes interleaved samples
+    pub fn push(&mut self, samples: &[f32], channels: usize) {
+        for frame in samples.chunks_exact(channels.max(1)) {
+            if frame.iter().any(|sample| sample.abs() > self.threshold) {
+                let window = self.frames / self.window_len as u64;
+                self.first.get_or_insert(window);
+                self.last = Some(window);
+            }
+            self.frames += 1;
+        }
+    }
+
+    /// `None` if it's silent the whole way through
+    pub fn finish(self) -> Option<Sound> {
+        let to_duration = |frames: u64| {
+            let rate = u64::from(self.sample_rate.max(1));
+            Duration::from_secs(frames / rate)
+                + Duration::from_nanos(frames % rate * 1_000_000_000 / rate)
+        };
+        let window = self.window_len as u64;
+        Some(Sound {
+            start: to_duration(self.first? * window),
+            end: to_duration(((self.last? + 1) * window).min(self.frames)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    /// stereo, silent but for a tone between `from` and `to` seconds, and hiss at `hiss`
+    /// below full scale throughout
+    fn padded_tone(seconds: f32, from: f32, to: f32, hiss: f32) -> Vec<f32> {
+        let rate = SAMPLE_RATE as f32;
+        (0..(seconds * rate) as usize)
+            .flat_map(|i| {
+                let time = i as f32 / rate;
+                let hiss = if i % 2 == 0 { hiss } else { -hiss };
+                let tone = if (from..to).contains(&time) {
+                    (time * 440.0 * TAU).sin() * 0.5
+                } else {
+                    0.0
+                };
+                [tone + hiss; 2]
+            })
+            .collect()
+    }
+
+    fn detect(samples: &[f32]) -> Option<Sound> {
+        let mut detector = SilenceDetector::new(SAMPLE_RATE);
+        for chunk in samples.chunks(1_000) {
+            detector.push(chunk, 2);
+        }
+        detector.finish()
+    }
+
+    #[test]
+    fn finds_the_sound_between_the_silence() {
+        // hiss at -70 dBFS is still silence
+        let hiss = 10f32.powf(-70.0 / 20.0);
+        let sound = detect(&padded_tone(3.0, 0.5, 2.0, hiss));
+        assert_eq!(
+            sound,
+            Some(Sound {
+                start: Duration::from_millis(500),
+                end: Duration::from_millis(2000),
+            })
+        );
+
+        // sound right up to the end of a file that stops partway through a window
+        let samples = padded_tone(2.995, 1.0, 3.0, 0.0);
+        let frames = samples.len() as u64 / 2;
+        let sound = detect(&samples).unwrap();
+        assert_eq!(sound.start, Duration::from_secs(1));
+        assert_eq!(
+            sound.end,
+            Duration::from_nanos(frames * 1_000_000_000 / u64::from(SAMPLE_RATE))
+        );
+    }
+
+    #[test]
+    fn silence_has_no_sound() {
+        assert_eq!(detect(&padded_tone(1.0, 2.0, 3.0, 0.0001)), None);
+        assert_eq!(detect(&[]), None);
+    }
+}
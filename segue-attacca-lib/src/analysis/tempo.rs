@@ -0,0 +1,325 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// how many onset frames there are a second
+const FRAME_RATE: f64 = 100.0;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+/// Tempos near this one are preferred when a track could be read at half or double speed,
+/// the way a listener would tap along.
+const PREFERRED_BPM: f64 = 120.0;
+const BEATS_PER_BAR: usize = 4;
+/// anything quieter than this counts as silence, about -60 dBFS
+const ENERGY_FLOOR: f64 = 1e-6;
+
+/// A track's tempo and where its bars start, enough to lay a beat grid over it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tempo {
+    pub bpm: f64,
+    /// where the first bar starts, from the start of the file
+    pub downbeat: Duration,
+}
+
+impl Tempo {
+    pub fn bar(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.bpm * BEATS_PER_BAR as f64)
+    }
+
+    /// The first downbeat at or after `start`, counted from `start`. For tracks that start
+    /// partway into a file, which assumes the tempo holds steady up to there.
+    pub fn downbeat_after(&self, start: Duration) -> Duration {
+        let Some(since) = start.checked_sub(self.downbeat) else {
+            return self.downbeat - start;
+        };
+        let bar = self.bar().as_secs_f64();
+        let bars = (since.as_secs_f64() / bar).ceil();
+        Duration::from_secs_f64(self.downbeat.as_secs_f64() + bars * bar).saturating_sub(start)
+    }
+}
+
+/// Works out the tempo from a file's samples as they're decoded. It listens for onsets, where
+/// the level jumps, and finds the beat that lines up with the most of them.
+#[derive(Debug)]
+pub struct TempoDetector {
+    hop: usize,
+    /// the energy of the frame that's filling up, and how many samples are in it
+    energy: f64,
+    filled: usize,
+    previous: f64,
+    onsets: Vec<f64>,
+}
+
+impl TempoDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            hop: ((sample_rate as f64 / FRAME_RATE).round() as usize).max(1),
+            energy: 0.0,
+            filled: 0,
+            previous: ENERGY_FLOOR.ln(),
+            onsets: Vec::new(),
+        }
+    }
+
+    /// takes interleaved samples
+    pub fn push(&mut self, samples: &[f32], channels: usize) {
+        for frame in samples.chunks_exact(channels.max(1)) {
+            let mono = frame.iter().sum::<f32>() as f64 / frame.len() as f64;
+            self.energy += mono * mono;
+            self.filled += 1;
+            if self.filled == self.hop {
+                let level = (self.energy / self.hop as f64).max(ENERGY_FLOOR).ln();
+                self.onsets.push((level - self.previous).max(0.0));
+                self.previous = level;
+                self.energy = 0.0;
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// `None` when there isn't a steady beat to be found, or not enough of the track to tell
+    pub fn finish(self) -> Option<Tempo> {
+        let onsets = self.onsets;
+        let longest = FRAME_RATE * 60.0 / MIN_BPM;
+        if (onsets.len() as f64) < longest * BEATS_PER_BAR as f64 * 2.0 {
+            return None;
+        }
+        let mean = onsets.iter().sum::<f64>() / onsets.len() as f64;
+
+        // Autocorrelation finds how often things repeat, but can't tell a beat from two of
+        // them or half of one. Following the beats through the track can: twice the period
+        // only gets half the onsets, and half of it puts every other beat where there's
+        // nothing.
+        let period = estimate_period(&onsets)?;
+        let (score, period, beats) = [period / 2.0, period, period * 2.0]
+            .into_iter()
+            .filter(|period| (MIN_BPM..=MAX_BPM).contains(&(FRAME_RATE * 60.0 / period)))
+            .map(|period| {
+                let beats = track_beats(&onsets, period);
+                let score: f64 = beats.iter().map(|beat| onsets[beat.frame] - mean).sum();
+                (score * prior(period), period, beats)
+            })
+            .max_by(|(a, ..), (b, ..)| a.total_cmp(b))?;
+        if score <= 0.0 {
+            return None;
+        }
+        let (period, first) = fit(&beats).unwrap_or((period, beats[0].frame as f64));
+
+        // downbeats tend to hit harder than the other beats of the bar
+        let bar_position = (0..BEATS_PER_BAR)
+            .map(|position| {
+                let strength: f64 = beats
+                    .iter()
+                    .skip(position)
+                    .step_by(BEATS_PER_BAR)
+                    .map(|beat| onsets[beat.frame])
+                    .sum();
+                (position, strength)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(position, _)| position);
+        let mut downbeat = first + bar_position as f64 * period;
+        // the fit can put the first beat a hair before the start
+        if downbeat < 0.0 {
+            downbeat += period * BEATS_PER_BAR as f64;
+        }
+
+        Some(Tempo {
+            bpm: (FRAME_RATE * 60.0 / period * 100.0).round() / 100.0,
+            // an onset lands in the frame it happens in, so on average halfway through it
+            downbeat: Duration::from_secs_f64((downbeat + 0.5) / FRAME_RATE),
+        })
+    }
+}
+
+/// a beat that `track_beats` found
+#[derive(Debug, Clone, Copy)]
+struct Beat {
+    frame: usize,
+    /// whether there was an onset there, rather than it being where one was expected
+    heard: bool,
+}
+
+/// The beat length in frames the onsets repeat at most strongly, by autocorrelation, weighted
+/// towards `PREFERRED_BPM`.
+fn estimate_period(onsets: &[f64]) -> Option<f64> {
+    // Beats rarely last a whole number of frames, so each onset is spread over its neighbours
+    // to still line up with the next beat's.
+    let spread: Vec<f64> = (0..onsets.len())
+        .map(|i| {
+            onsets[i.saturating_sub(1)..(i + 2).min(onsets.len())]
+                .iter()
+                .copied()
+                .fold(0.0, f64::max)
+        })
+        .collect();
+    let mean = spread.iter().sum::<f64>() / spread.len() as f64;
+    let centered: Vec<f64> = spread.iter().map(|onset| onset - mean).collect();
+    let shortest = (FRAME_RATE * 60.0 / MAX_BPM).floor() as usize;
+    let longest = (FRAME_RATE * 60.0 / MIN_BPM).ceil() as usize;
+
+    let correlation: Vec<f64> = (0..=longest + 1)
+        .map(|lag| {
+            centered
+                .iter()
+                .zip(&centered[lag.min(centered.len())..])
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect();
+    let (lag, &best) = correlation
+        .iter()
+        .enumerate()
+        .take(longest + 1)
+        .skip(shortest)
+        .max_by(|(a, x), (b, y)| (*x * prior(*a as f64)).total_cmp(&(*y * prior(*b as f64))))?;
+    if best <= 0.0 {
+        return None;
+    }
+
+    // between frames, going by the neighbours
+    let (before, after) = (correlation[lag - 1], correlation[lag + 1]);
+    let curve = before - 2.0 * best + after;
+    let offset = if curve < 0.0 {
+        (0.5 * (before - after) / curve).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    Some(lag as f64 + offset)
+}
+
+/// a log normal around `PREFERRED_BPM`, an octave wide
+fn prior(period: f64) -> f64 {
+    let bpm = FRAME_RATE * 60.0 / period;
+    let octaves = (bpm / PREFERRED_BPM).log2();
+    (-0.5 * octaves * octaves).exp()
+}
+
+/// Follows the beat through the track, from the strongest onset out to both ends. Each beat is
+/// looked for near where the last one says it should be, and taken as heard if there's an
+/// onset there, so the grid keeps up with a period that's a little off.
+fn track_beats(onsets: &[f64], period: f64) -> Vec<Beat> {
+    let window = (period / 8.0).round().max(1.0) as usize;
+    let Some(strongest) = (0..onsets.len()).max_by(|&a, &b| onsets[a].total_cmp(&onsets[b])) else {
+        return Vec::new();
+    };
+    let follow = |step: f64| {
+        let mut beats = Vec::new();
+        let mut expected = strongest as f64 + step;
+        while expected >= 0.0 && expected < onsets.len() as f64 {
+            let center = expected.round() as usize;
+            let range = center.saturating_sub(window)..(center + window + 1).min(onsets.len());
+            let heard = range
+                .max_by(|&a, &b| onsets[a].total_cmp(&onsets[b]))
+                .filter(|&frame| onsets[frame] > 0.0);
+            let frame = heard.unwrap_or(center.min(onsets.len() - 1));
+            beats.push(Beat {
+                frame,
+                heard: heard.is_some(),
+            });
+            expected = if heard.is_some() {
+                frame as f64
+            } else {
+                expected
+            } + step;
+        }
+        beats
+    };
+    let mut beats = follow(-period);
+    beats.reverse();
+    beats.push(Beat {
+        frame: strongest,
+        heard: true,
+    });
+    beats.extend(follow(period));
+    beats
+}
+
+/// The period and first beat of the straight line that fits the heard beats best. Over a
+/// whole track that's much finer than a frame.
+fn fit(beats: &[Beat]) -> Option<(f64, f64)> {
+    let heard: Vec<(f64, f64)> = beats
+        .iter()
+        .enumerate()
+        .filter(|(_, beat)| beat.heard)
+        .map(|(index, beat)| (index as f64, beat.frame as f64))
+        .collect();
+    let count = heard.len() as f64;
+    let mean_index = heard.iter().map(|(index, _)| index).sum::<f64>() / count;
+    let mean_frame = heard.iter().map(|(_, frame)| frame).sum::<f64>() / count;
+    let spread: f64 = heard
+        .iter()
+        .map(|(index, _)| (index - mean_index).powi(2))
+        .sum();
+    if spread == 0.0 {
+        return None;
+    }
+    let period = heard
+        .iter()
+        .map(|(index, frame)| (index - mean_index) * (frame - mean_frame))
+        .sum::<f64>()
+        / spread;
+    Some((period, mean_frame - period * mean_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    /// 30 seconds of short clicks on every beat, from `first` on, with the first of each bar
+    /// twice as loud. A beat before `first` is there too, so the downbeat isn't the first one.
+    fn clicks(bpm: f64, first: f64) -> Vec<f32> {
+        let beat = 60.0 / bpm;
+        let mut samples = vec![0.0; SAMPLE_RATE as usize * 30];
+        let mut time = first - beat;
+        let mut index = BEATS_PER_BAR - 1;
+        while time < 30.0 {
+            let start = (time * SAMPLE_RATE as f64).round() as usize;
+            let level = if index.is_multiple_of(BEATS_PER_BAR) {
+                0.8
+            } else {
+                0.4
+            };
+            for (i, sample) in samples.iter_mut().skip(start).take(200).enumerate() {
+                *sample = level * if i % 2 == 0 { 1.0 } else { -1.0 };
+            }
+            time += beat;
+            index += 1;
+        }
+        samples
+    }
+
+    fn detect(samples: &[f32]) -> Option<Tempo> {
+        let mut detector = TempoDetector::new(SAMPLE_RATE);
+        // in the odd sized pieces a decoder hands over
+        for chunk in samples.chunks(1_000) {
+            detector.push(chunk, 1);
+        }
+        detector.finish()
+    }
+
+    #[test]
+    fn finds_the_tempo_and_downbeat_of_clicks() {
+        for (bpm, first) in [(90.0, 0.9), (120.0, 0.75), (128.0, 1.0), (174.0, 0.6)] {
+            let tempo = detect(&clicks(bpm, first)).unwrap();
+            assert!(
+                (tempo.bpm - bpm).abs() < 0.5,
+                "{bpm} bpm found as {tempo:?}"
+            );
+            // an onset is only placed to within a frame
+            let off = (tempo.downbeat.as_secs_f64() - first).abs();
+            assert!(
+                off < 1.5 / FRAME_RATE,
+                "downbeat {first} found as {tempo:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn silence_has_no_tempo() {
+        assert_eq!(detect(&vec![0.0; SAMPLE_RATE as usize * 30]), None);
+        assert_eq!(detect(&clicks(120.0, 0.5)[..SAMPLE_RATE as usize]), None);
+    }
+}
@@ -0,0 +1,332 @@
+use std::{fs::read, path::Path, time::Duration};
+
+use color_eyre::{Result, eyre::bail};
+use tracing::warn;
+
+use crate::tags::EmbeddedTags;
+
+/// cue sheet timestamps count frames, as on a cd
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// A cue sheet, which splits one long audio file, or a few, into tracks.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CueSheet {
+    pub title: Option<Box<str>>,
+    pub performer: Option<Box<str>>,
+    /// from `REM GENRE`, which most rippers write
+    pub genre: Option<Box<str>>,
+    /// from `REM DATE`
+    pub year: Option<i32>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CueFile {
+    /// as written in the sheet, relative to the folder the sheet is in
+    pub path: Box<str>,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<Box<str>>,
+    pub performer: Option<Box<str>>,
+    /// where `INDEX 01` is, any pregap before it belongs to the track before. A track without
+    /// one starts at its `INDEX 00`.
+    pub start: Duration,
+}
+
+/// whether the file at `path` is a cue sheet
+pub fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+}
+
+impl CueSheet {
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = read(path)?;
+        // sheets from older rippers tend to be latin-1
+        let text = String::from_utf8(bytes)
+            .unwrap_or_else(|e| e.into_bytes().iter().map(|&byte| byte as char).collect());
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut sheet = CueSheet::default();
+        // each track's INDEX 00 and 01, in the order the tracks come in
+        let mut indexes: Vec<[Option<Duration>; 2]> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim().trim_start_matches('\u{feff}');
+            let Some((command, rest)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let args = arguments(rest);
+            let first = args.first().map(|arg| Box::<str>::from(arg.as_str()));
+            let track = sheet
+                .files
+                .last_mut()
+                .and_then(|file| file.tracks.last_mut());
+
+            match command.to_uppercase().as_str() {
+                "TITLE" => match track {
+                    Some(track) => track.title = first,
+                    None => sheet.title = first,
+                },
+                "PERFORMER" => match track {
+                    Some(track) => track.performer = first,
+                    None => sheet.performer = first,
+                },
+                "REM" => match (args.first().map(String::as_str), args.get(1)) {
+                    (Some("GENRE"), Some(genre)) => sheet.genre = Some(genre.as_str().into()),
+                    (Some("DATE"), Some(date)) => {
+                        sheet.year = date.get(..4).and_then(|year| year.parse().ok())
+                    }
+                    _ => (),
+                },
+                "FILE" => {
+                    let Some(path) = first else {
+                        bail!("line {}: FILE without a file name", number + 1);
+                    };
+                    sheet.files.push(CueFile {
+                        path,
+                        tracks: Vec::new(),
+                    });
+                }
+                "TRACK" => {
+                    let Some(file) = sheet.files.last_mut() else {
+                        bail!("line {}: TRACK before any FILE", number + 1);
+                    };
+                    let Some(track_number) = args.first().and_then(|arg| arg.parse().ok()) else {
+                        bail!("line {}: TRACK without a number", number + 1);
+                    };
+                    file.tracks.push(CueTrack {
+                        number: track_number,
+                        ..Default::default()
+                    });
+                    indexes.push([None; 2]);
+                }
+                "INDEX" => {
+                    let (Some(_), Some(index), Some(time)) =
+                        (track, args.first().map(String::as_str), args.get(1))
+                    else {
+                        continue;
+                    };
+                    let index = match index {
+                        "00" => 0,
+                        "01" => 1,
+                        _ => continue,
+                    };
+                    let Some(time) = timestamp(time) else {
+                        bail!("line {}: {time} isn't a time", number + 1);
+                    };
+                    if let Some(track) = indexes.last_mut() {
+                        track[index] = Some(time);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let mut indexes = indexes.into_iter();
+        for file in &mut sheet.files {
+            file.tracks.retain_mut(|track| match indexes.next() {
+                Some([_, Some(start)]) => {
+                    track.start = start;
+                    true
+                }
+                Some([Some(pregap), None]) => {
+                    warn!(
+                        "track {} has no INDEX 01, starting it at INDEX 00",
+                        track.number
+                    );
+                    track.start = pregap;
+                    true
+                }
+                _ => {
+                    warn!("track {} has no INDEX 01, leaving it out", track.number);
+                    false
+                }
+            });
+        }
+        Ok(sheet)
+    }
+
+    /// what the sheet says about one of its tracks, as if it were the track's own tags
+    pub fn tags(&self, file: &CueFile, track: &CueTrack) -> EmbeddedTags {
+        let file_name = Path::new(file.path.as_ref())
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        EmbeddedTags {
+            title: Some(
+                track
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("{file_name} {:02}", track.number).into()),
+            ),
+            artist: track.performer.clone().or_else(|| self.performer.clone()),
+            album: self.title.clone(),
+            album_artist: self.performer.clone(),
+            track_number: Some(track.number),
+            disc_number: None,
+            year: self.year,
+            genre: self.genre.clone(),
+            cover: None,
+            replay_gain: Default::default(),
+        }
+    }
+}
+
+/// splits a line into words, keeping "quoted strings" together
+fn arguments(line: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            arguments.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            arguments.push(word);
+        }
+    }
+    arguments
+}
+
+/// `mm:ss:ff`, minutes can go past 59
+fn timestamp(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+    Some(Duration::from_nanos(
+        frames * 1_000_000_000 / FRAMES_PER_SECOND,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn starts(file: &CueFile) -> Vec<(u32, Duration)> {
+        file.tracks
+            .iter()
+            .map(|track| (track.number, track.start))
+            .collect()
+    }
+
+    #[test]
+    fn timestamps_count_cd_frames() {
+        assert_eq!(timestamp("00:00:00"), Some(Duration::ZERO));
+        assert_eq!(timestamp("01:02:15"), Some(Duration::from_millis(62_200)));
+        assert_eq!(
+            timestamp("00:00:01"),
+            Some(Duration::from_nanos(13_333_333))
+        );
+        assert_eq!(timestamp("100:00:00"), Some(Duration::from_secs(6000)));
+        for time in ["", "1:02", "01:02:03:04", "01:xx:00", "-1:00:00"] {
+            assert_eq!(timestamp(time), None, "{time:?}");
+        }
+    }
+
+    #[test]
+    fn tracks_go_with_their_files() {
+        let sheet = CueSheet::parse(
+            "\u{feff}REM GENRE Ambient
+            REM DATE 1999-04-01
+            PERFORMER \"Someone\"
+            TITLE \"Both Sides\"
+            FILE \"side a.flac\" WAVE
+              TRACK 01 AUDIO
+                TITLE \"One\"
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                TITLE \"Two\"
+                PERFORMER \"Someone Else\"
+                INDEX 00 03:59:00
+                INDEX 01 04:00:00
+            FILE \"side b.flac\" WAVE
+              TRACK 03 AUDIO
+                INDEX 01 00:00:00
+              TRACK 04 AUDIO
+                INDEX 01 02:30:00",
+        )
+        .unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Both Sides"));
+        assert_eq!(sheet.performer.as_deref(), Some("Someone"));
+        assert_eq!(sheet.genre.as_deref(), Some("Ambient"));
+        assert_eq!(sheet.year, Some(1999));
+
+        let [a, b] = &sheet.files[..] else {
+            panic!("{:?}", sheet.files);
+        };
+        assert_eq!(&*a.path, "side a.flac");
+        assert_eq!(
+            starts(a),
+            [(1, Duration::ZERO), (2, Duration::from_secs(240))]
+        );
+        assert_eq!(a.tracks[1].title.as_deref(), Some("Two"));
+        assert_eq!(a.tracks[1].performer.as_deref(), Some("Someone Else"));
+        assert_eq!(&*b.path, "side b.flac");
+        assert_eq!(
+            starts(b),
+            [(3, Duration::ZERO), (4, Duration::from_secs(150))]
+        );
+
+        // a track without a title is named after its file
+        let tags = sheet.tags(b, &b.tracks[1]);
+        assert_eq!(tags.title.as_deref(), Some("side b 04"));
+        assert_eq!(tags.artist.as_deref(), Some("Someone"));
+        assert_eq!(tags.album.as_deref(), Some("Both Sides"));
+    }
+
+    #[test]
+    fn tracks_without_index_01_fall_back_or_go() {
+        let sheet = CueSheet::parse(
+            "FILE mix.wav WAVE
+              TRACK 01 AUDIO
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                INDEX 00 01:00:00
+              TRACK 03 AUDIO
+                TITLE \"Nowhere\"
+              TRACK 04 AUDIO
+                INDEX 01 03:00:00",
+        )
+        .unwrap();
+        assert_eq!(
+            starts(&sheet.files[0]),
+            [
+                (1, Duration::ZERO),
+                (2, Duration::from_secs(60)),
+                (4, Duration::from_secs(180))
+            ]
+        );
+        assert!(CueSheet::parse("FILE mix.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 1:00").is_err());
+        assert!(CueSheet::parse("TRACK 01 AUDIO").is_err());
+    }
+
+    #[test]
+    fn latin_1_sheets_are_read() {
+        let path = temp_dir("cue-latin-1").join("album.cue");
+        let mut bytes = b"TITLE \"Caf".to_vec();
+        bytes.extend([0xE9, b'"', b'\n']);
+        bytes.extend(b"FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n");
+        fs::write(&path, bytes).unwrap();
+        let sheet = CueSheet::read(&path).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Café"));
+        assert_eq!(starts(&sheet.files[0]), [(1, Duration::ZERO)]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    fs::{File, metadata, remove_file, rename},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use color_eyre::Result;
+
+/// counts temp files, so two threads writing the same file never share one
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Where a file is written before being renamed over `path`, the same folder so the rename
+/// can't cross file systems. Every call gets a different one, so writers racing to the same
+/// path don't trip over each other and the last rename wins.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let count = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{name}.{}-{count}.tmp", std::process::id()))
+}
+
+/// Writes `contents` to `path` so that it's either all there or not at all, even if we crash
+/// or the disk fills up halfway through. A file that's replaced keeps its permissions.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp = temp_path(path);
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            if let Ok(old) = metadata(path) {
+                file.set_permissions(old.permissions())?;
+            }
+            file.sync_all()
+        })
+        .and_then(|_| rename(&temp, path));
+    if written.is_err() {
+        let _ = remove_file(&temp);
+    }
+    Ok(written?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn racing_writers_all_succeed() {
+        let path = temp_dir("racing_writers").join("cover.jpg");
+        let written: Vec<Result<()>> = thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| write_atomically(&path, b"the same cover")))
+                .collect();
+            writers
+                .into_iter()
+                .map(|writer| writer.join().unwrap())
+                .collect()
+        });
+        assert!(written.iter().all(Result::is_ok));
+        assert_eq!(fs::read(&path).unwrap(), b"the same cover");
+        // and no temp files are left behind
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::{LazyLock, RwLock},
+};
+
+/// how much of a file `sniff` gets to look at
+const HEADER_LEN: usize = 64;
+
+/// Extensions of files that turn up next to music but never are any, so they aren't sniffed.
+/// Some of them can start with bytes that look like audio, like a UTF-16 cue sheet or log.
+const NOT_AUDIO: &[&str] = &[
+    "cue", "log", "txt", "nfo", "md", "pdf", "m3u", "m3u8", "pls", "xspf", "jpg", "jpeg", "png",
+    "gif", "bmp", "webp", "heic", "heif", "avif", "tif", "tiff", "json", "toml", "xml", "db",
+    "sqlite", "mov", "mkv", "avi", "webm", "3gp",
+];
+
+/// Every audio format the library knows, the built in ones to start with. Adding one here is
+/// all it takes for scans to pick its files up.
+static REGISTRY: LazyLock<RwLock<Vec<AudioFormat>>> = LazyLock::new(|| RwLock::new(builtin()));
+
+/// An audio format the library can scan.
+#[derive(Debug, Clone, Copy)]
+pub struct AudioFormat {
+    pub name: &'static str,
+    /// lowercase, without the dot
+    pub extensions: &'static [&'static str],
+    /// whether a file starting with these bytes is in this format. It gets the first 64 bytes,
+    /// or fewer if the file is shorter.
+    pub sniff: fn(&[u8]) -> bool,
+    /// a closer look at a file `sniff` matched, for containers whose first bytes don't say
+    /// whether there's only audio in them
+    pub confirm: Option<fn(&Path) -> bool>,
+    /// whether playback can decode it, files that can't are still scanned
+    pub playable: bool,
+}
+
+/// adds a format, which takes precedence over the ones already there
+pub fn register(format: AudioFormat) {
+    if let Ok(mut registry) = REGISTRY.write() {
+        registry.insert(0, format);
+    }
+}
+
+/// every format there is, in the order they're tried
+pub fn formats() -> Vec<AudioFormat> {
+    REGISTRY
+        .read()
+        .map(|registry| registry.clone())
+        .unwrap_or_default()
+}
+
+/// The format of the file at `path`, going by its extension, or by how it starts if it doesn't
+/// have one we know. Files with an extension that's known not to be audio are never opened.
+pub fn identify(path: &Path) -> Option<AudioFormat> {
+    if let Some(format) = by_extension(path) {
+        return Some(format);
+    }
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    if extension.is_some_and(|extension| NOT_AUDIO.contains(&extension.to_lowercase().as_str())) {
+        return None;
+    }
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)
+        .ok()?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .ok()?;
+    let format = by_header(&header)?;
+    format
+        .confirm
+        .is_none_or(|confirm| confirm(path))
+        .then_some(format)
+}
+
+pub fn by_extension(path: &Path) -> Option<AudioFormat> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    formats()
+        .into_iter()
+        .find(|format| format.extensions.contains(&extension.as_str()))
+}
+
+pub fn by_header(header: &[u8]) -> Option<AudioFormat> {
+    formats().into_iter().find(|format| (format.sniff)(header))
+}
+
+fn builtin() -> Vec<AudioFormat> {
+    vec![
+        AudioFormat {
+            name: "WAV",
+            extensions: &["wav", "wave"],
+            sniff: |header| {
+                header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE".as_slice())
+            },
+            confirm: None,
+            playable: true,
+        },
+        AudioFormat {
+            name: "AIFF",
+            extensions: &["aif", "aiff", "aifc"],
+            sniff: |header| {
+                header.starts_with(b"FORM")
+                    && matches!(header.get(8..12), Some(b"AIFF") | Some(b"AIFC"))
+            },
+            confirm: None,
+            playable: true,
+        },
+        AudioFormat {
+            name: "FLAC",
+            extensions: &["flac"],
+            sniff: |header| header.starts_with(b"fLaC"),
+            confirm: None,
+            playable: true,
+        },
+        AudioFormat {
+            name: "MP3",
+            extensions: &["mp3"],
+            // an id3 tag, or straight into a frame
+            sniff: |header| header.starts_with(b"ID3") || mpeg_frame(header),
+            confirm: None,
+            playable: true,
+        },
+        AudioFormat {
+            name: "Ogg Vorbis",
+            extensions: &["ogg", "oga"],
+            sniff: |header| {
+                ogg_packet(header).is_some_and(|packet| packet.starts_with(b"\x01vorbis"))
+            },
+            confirm: None,
+            playable: true,
+        },
+        // Scanned and tagged, but not played: symphonia has no Opus decoder, and the ones
+        // there are bind libopus, a C library this crate doesn't build against yet. Playback
+        // refuses them with an error rather than playing silence, and the inspector says so.
+        AudioFormat {
+            name: "Opus",
+            extensions: &["opus"],
+            sniff: |header| {
+                ogg_packet(header).is_some_and(|packet| packet.starts_with(b"OpusHead"))
+            },
+            confirm: None,
+            playable: false,
+        },
+        // AAC and ALAC both come in an MPEG-4 container. An .mp4 is as likely to be a video,
+        // so those are sniffed like files without an extension.
+        AudioFormat {
+            name: "MPEG-4 audio",
+            extensions: &["m4a", "m4b"],
+            sniff: |header| {
+                header.get(4..8) == Some(b"ftyp".as_slice())
+                    && matches!(
+                        header.get(8..12),
+                        Some(b"M4A " | b"M4B " | b"M4P " | b"mp41" | b"mp42" | b"isom")
+                    )
+            },
+            // the audio brands say so, the general ones need their tracks looked at
+            confirm: Some(|path| {
+                let mut brand = [0; 4];
+                let Ok(mut file) = File::open(path) else {
+                    return false;
+                };
+                file.seek(SeekFrom::Start(8))
+                    .and_then(|_| file.read_exact(&mut brand))
+                    .is_ok()
+                    && (matches!(&brand, b"M4A " | b"M4B " | b"M4P ") || mp4_audio_only(&mut file))
+            }),
+            playable: true,
+        },
+        AudioFormat {
+            name: "AAC",
+            extensions: &["aac"],
+            // an ADTS frame: layer 0 and a sample rate index that's in use
+            sniff: |header| {
+                matches!(header, [0xff, second, third, ..]
+                    if second & 0xf6 == 0xf0 && (third >> 2) & 0x0f < 13)
+            },
+            confirm: None,
+            playable: true,
+        },
+    ]
+}
+
+/// the start of the first packet in an ogg file, which says what codec it is
+fn ogg_packet(header: &[u8]) -> Option<&[u8]> {
+    if !header.starts_with(b"OggS") {
+        return None;
+    }
+    // a page header is 27 bytes and then the segment table, one byte per segment
+    let segments = *header.get(26)? as usize;
+    header.get(27 + segments..)
+}
+
+/// Whether `header` starts with an MPEG audio frame header that holds up: layer II or III, a
+/// bitrate and a sample rate. Layer I is left out since a UTF-16 byte order mark looks just
+/// like one.
+fn mpeg_frame(header: &[u8]) -> bool {
+    let [0xff, second, third, ..] = *header else {
+        return false;
+    };
+    let version = (second >> 3) & 0x03;
+    let layer = (second >> 1) & 0x03;
+    let bitrate = third >> 4;
+    let sample_rate = (third >> 2) & 0x03;
+    second & 0xe0 == 0xe0
+        && version != 0b01
+        && matches!(layer, 0b01 | 0b10)
+        && !matches!(bitrate, 0 | 15)
+        && sample_rate != 3
+}
+
+/// Whether an MPEG-4 file's tracks are all sound, going through the boxes to each track's
+/// handler. Anything that can't be read counts as no.
+fn mp4_audio_only(file: &mut File) -> bool {
+    // the moov box can come before or after the media, so the top level boxes are skipped
+    // through until it turns up
+    let mut at = 0;
+    let moov = loop {
+        let Some((kind, start, end)) = next_box(file, at) else {
+            return false;
+        };
+        if &kind == b"moov" {
+            let mut moov = vec![0; (end - start) as usize];
+            if file.seek(SeekFrom::Start(start)).is_err() || file.read_exact(&mut moov).is_err() {
+                return false;
+            }
+            break moov;
+        }
+        at = end;
+    };
+    let handlers: Vec<&[u8]> = boxes(&moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| boxes(trak).find(|(kind, _)| kind == b"mdia"))
+        .filter_map(|(_, mdia)| boxes(mdia).find(|(kind, _)| kind == b"hdlr"))
+        // a version and flags, then a field that's always zero, then the handler type
+        .filter_map(|(_, hdlr)| hdlr.get(8..12))
+        .collect();
+    !handlers.is_empty() && handlers.iter().all(|handler| *handler == b"soun")
+}
+
+/// the type of the box at `at` in the file, and where its contents start and end
+fn next_box(file: &mut File, at: u64) -> Option<([u8; 4], u64, u64)> {
+    // moov is the only box that's read whole, and it's never anywhere near this big
+    const LARGEST_MOOV: u64 = 64 << 20;
+    let mut head = [0; 16];
+    file.seek(SeekFrom::Start(at)).ok()?;
+    file.read_exact(&mut head[..8]).ok()?;
+    let kind = head[4..8].try_into().ok()?;
+    let (size, header_len) = match u32::from_be_bytes(head[..4].try_into().ok()?) {
+        // a 64 bit size follows
+        1 => {
+            file.read_exact(&mut head[8..]).ok()?;
+            (u64::from_be_bytes(head[8..].try_into().ok()?), 16)
+        }
+        // to the end of the file
+        0 => (file.metadata().ok()?.len().checked_sub(at)?, 8),
+        size => (u64::from(size), 8),
+    };
+    if size < header_len || (&kind == b"moov" && size > LARGEST_MOOV) {
+        return None;
+    }
+    Some((kind, at + header_len, at.checked_add(size)?))
+}
+
+/// the boxes directly inside `contents`, by type
+fn boxes(contents: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = contents;
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let kind = rest.get(4..8)?.try_into().ok()?;
+        let inner = rest.get(8..size)?;
+        rest = &rest[size..];
+        Some((kind, inner))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn name(header: &[u8]) -> Option<&'static str> {
+        by_header(header).map(|format| format.name)
+    }
+
+    fn mp4_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut bytes = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend(kind);
+        bytes.extend(contents);
+        bytes
+    }
+
+    /// an MPEG-4 file with the major brand `brand`, and tracks with these handlers after the
+    /// media, the way most encoders lay it out
+    fn mp4(brand: &[u8; 4], handlers: &[&[u8; 4]]) -> Vec<u8> {
+        let mut bytes = mp4_box(b"ftyp", &[brand.as_slice(), &[0; 4], b"isom"].concat());
+        bytes.extend(mp4_box(b"mdat", &[0; 32]));
+        let traks: Vec<u8> = handlers
+            .iter()
+            .flat_map(|handler| {
+                let hdlr = mp4_box(b"hdlr", &[&[0; 8], handler.as_slice(), &[0; 12]].concat());
+                mp4_box(b"trak", &mp4_box(b"mdia", &hdlr))
+            })
+            .collect();
+        bytes.extend(mp4_box(
+            b"moov",
+            &[mp4_box(b"mvhd", &[0; 100]), traks].concat(),
+        ));
+        bytes
+    }
+
+    #[test]
+    fn sniffs_mpeg_frames() {
+        // MPEG-1 layer III at 128kbps and 44.1kHz, and layer II
+        assert_eq!(name(&[0xff, 0xfb, 0x90, 0x64]), Some("MP3"));
+        assert_eq!(name(&[0xff, 0xfd, 0x90, 0x64]), Some("MP3"));
+        assert_eq!(name(b"ID3\x04\x00"), Some("MP3"));
+        // no bitrate, a bad bitrate, a sample rate that doesn't exist
+        assert_eq!(name(&[0xff, 0xfb, 0x00, 0x64]), None);
+        assert_eq!(name(&[0xff, 0xfb, 0xf0, 0x64]), None);
+        assert_eq!(name(&[0xff, 0xfb, 0x9c, 0x64]), None);
+        // a UTF-16 cue sheet, "REM"
+        assert_eq!(name(b"\xff\xfeR\x00E\x00M\x00"), None);
+        // an ADTS frame at 44.1kHz
+        assert_eq!(name(&[0xff, 0xf1, 0x50, 0x80]), Some("AAC"));
+    }
+
+    #[test]
+    fn sniffs_containers() {
+        assert_eq!(name(b"RIFF\x24\x00\x00\x00WAVEfmt "), Some("WAV"));
+        assert_eq!(name(b"RIFF\x24\x00\x00\x00AVI LIST"), None);
+        assert_eq!(name(b"FORM\x00\x00\x00\x00AIFF"), Some("AIFF"));
+        assert_eq!(name(b"fLaC\x00\x00\x00\x22"), Some("FLAC"));
+        let mut ogg = b"OggS".to_vec();
+        ogg.extend([0; 22]);
+        ogg.push(1);
+        ogg.push(30);
+        assert_eq!(
+            name(&[&ogg, b"\x01vorbis".as_slice()].concat()),
+            Some("Ogg Vorbis")
+        );
+        assert_eq!(name(&[&ogg, b"OpusHead".as_slice()].concat()), Some("Opus"));
+        assert_eq!(name(&[&ogg, b"\x80theora".as_slice()].concat()), None);
+        assert_eq!(name(&mp4(b"M4A ", &[b"soun"])), Some("MPEG-4 audio"));
+        assert_eq!(name(&mp4(b"heic", &[])), None);
+        assert_eq!(name(&mp4(b"qt  ", &[b"soun"])), None);
+    }
+
+    #[test]
+    fn identifies_files() {
+        let dir = temp_dir("formats");
+        let write = |file: &str, bytes: &[u8]| {
+            let path = dir.join(file);
+            fs::write(&path, bytes).unwrap();
+            identify(&path).map(|format| format.name)
+        };
+        let frame = [0xff, 0xfb, 0x90, 0x64];
+
+        // the extension wins, whatever's in the file
+        assert_eq!(write("song.FLAC", b"nothing"), Some("FLAC"));
+        assert_eq!(write("song", &frame), Some("MP3"));
+        assert_eq!(write("song.bin", &frame), Some("MP3"));
+        // files that are never audio aren't looked at
+        assert_eq!(write("rip.log", &frame), None);
+        assert_eq!(write("sheet.cue", &frame), None);
+        assert_eq!(write("empty", b""), None);
+
+        // MPEG-4 files with a general brand only count with nothing but sound in them
+        assert_eq!(
+            write("m4a.mp4", &mp4(b"M4A ", &[b"soun"])),
+            Some("MPEG-4 audio")
+        );
+        assert_eq!(
+            write("audio.mp4", &mp4(b"isom", &[b"soun"])),
+            Some("MPEG-4 audio")
+        );
+        let video = mp4(b"isom", &[b"vide", b"soun"]);
+        assert_eq!(write("video.mp4", &video), None);
+        assert_eq!(write("nothing.mp4", &mp4(b"mp42", &[])), None);
+        assert_eq!(write("picture.img", &mp4(b"avif", &[b"pict"])), None);
+    }
+}
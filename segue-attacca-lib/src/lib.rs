@@ -1,8 +1,23 @@
 use music_library::MusicLibrary;
 use playback::PlaybackEngine;
 
+pub mod album_art;
+pub mod analysis;
+pub mod cue;
+mod files;
+pub mod formats;
+pub mod migrations;
 pub mod music_library;
-mod playback;
+pub mod playback;
+pub mod playlist_files;
+pub mod queue;
+pub mod search;
+pub mod storage;
+pub mod tags;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "watch")]
+pub mod watch;
 
 pub struct AppState {
     _library: MusicLibrary,
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use color_eyre::{Result, eyre::bail};
+use serde_json::{Map, Value, json};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// The version of the library file this build writes. Bump it along with adding a migration
+/// whenever the layout of what's saved changes in a way old files can't just be read as.
+pub const FORMAT_VERSION: u64 = 2;
+
+/// `MIGRATIONS[n]` upgrades a file from version `n` to `n + 1`.
+const MIGRATIONS: [fn(&mut Value) -> Result<()>; FORMAT_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Upgrades a library file of any older version to the current one, in place.
+pub fn migrate(library: &mut Value) -> Result<()> {
+    let Some(fields) = library.as_object() else {
+        bail!("library file isn't a json object");
+    };
+    let version = match fields.get("version") {
+        // files from before there was a version are version 0
+        None => 0,
+        Some(version) => match version.as_u64() {
+            Some(version) => version,
+            None => bail!("library file has a bad version: {version}"),
+        },
+    };
+    if version > FORMAT_VERSION {
+        bail!(
+            "library file is version {version}, newer than the {FORMAT_VERSION} this build knows"
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("migrating library file from version {from} to {}", from + 1);
+        migration(library)?;
+        set_version(library, from as u64 + 1);
+    }
+    Ok(())
+}
+
+fn set_version(library: &mut Value, version: u64) {
+    if let Some(fields) = library.as_object_mut() {
+        fields.insert("version".into(), version.into());
+    }
+}
+
+/// Version 0 is every file from before the version field. Nothing else about the layout
+/// changed, everything added up to then has a default.
+fn v0_to_v1(_library: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+/// Tracks get a uuid, and playlists go from holding whole copies of their tracks and
+/// playlists to pointing at them by uuid. The `artists` list isn't saved anymore, it's worked
+/// out from the tracks.
+fn v1_to_v2(library: &mut Value) -> Result<()> {
+    let Some(fields) = library.as_object_mut() else {
+        bail!("library file isn't a json object");
+    };
+    fields.remove("artists");
+
+    let mut tracks = match fields.remove("tracks") {
+        Some(Value::Array(tracks)) => tracks,
+        None => Vec::new(),
+        Some(_) => bail!("library file's tracks aren't a list"),
+    };
+    let mut uuids: HashMap<String, Value> = HashMap::new();
+    for track in tracks.iter_mut() {
+        let Some(track) = track.as_object_mut() else {
+            bail!("library file has a track that isn't an object");
+        };
+        let uuid = give_uuid(track);
+        if let Some(path) = track.get("path").and_then(Value::as_str) {
+            uuids.insert(path.to_string(), uuid);
+        }
+    }
+
+    if let Some(Value::Array(playlists)) = fields.get_mut("playlists") {
+        for playlist in playlists.iter_mut() {
+            let Some(items) = playlist.get_mut("items").and_then(Value::as_array_mut) else {
+                continue;
+            };
+            *items = items
+                .iter()
+                .filter_map(|item| item_to_reference(item, &mut uuids, &mut tracks))
+                .collect();
+        }
+    }
+
+    fields.insert("tracks".into(), Value::Array(tracks));
+    Ok(())
+}
+
+fn give_uuid(track: &mut Map<String, Value>) -> Value {
+    track
+        .entry("uuid")
+        .or_insert_with(|| Uuid::new_v4().to_string().into())
+        .clone()
+}
+
+/// Turns an inline item into a reference. Tracks are matched up with the library's by path,
+/// and ones the library doesn't have are added to it so nothing in a playlist gets lost.
+fn item_to_reference(
+    item: &Value,
+    uuids: &mut HashMap<String, Value>,
+    tracks: &mut Vec<Value>,
+) -> Option<Value> {
+    let (kind, inner) = item.as_object()?.iter().next()?;
+    match kind.as_str() {
+        "Track" => {
+            let path = inner.get("path")?.as_str()?;
+            if let Some(uuid) = uuids.get(path) {
+                return Some(json!({ "Track": uuid }));
+            }
+            let mut track = inner.as_object()?.clone();
+            let uuid = give_uuid(&mut track);
+            uuids.insert(path.to_string(), uuid.clone());
+            tracks.push(Value::Object(track));
+            Some(json!({ "Track": uuid }))
+        }
+        // these were saved as a copy of the playlist, or null when it was already gone
+        "Playlist" => {
+            let uuid = inner.get("uuid")?;
+            Some(json!({ "Playlist": uuid }))
+        }
+        "Block" => {
+            let items: Vec<Value> = inner
+                .as_array()?
+                .iter()
+                .filter_map(|item| item_to_reference(item, uuids, tracks))
+                .collect();
+            Some(json!({ "Block": items }))
+        }
+        _ => {
+            warn!("dropping unknown playlist item {kind}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        music_library::{MusicLibrary, Playlist, PlaylistItem, Track},
+        test_util::track_at,
+    };
+
+    /// migrates a saved library and loads it, the way `JsonStorage` does
+    fn load(json: &str) -> MusicLibrary {
+        let mut library: Value = serde_json::from_str(json).unwrap();
+        migrate(&mut library).unwrap();
+        assert_eq!(library["version"], FORMAT_VERSION);
+        let library: MusicLibrary = serde_json::from_value(library).unwrap();
+        // and it saves as the current version
+        assert_eq!(
+            serde_json::to_value(&library).unwrap()["version"],
+            FORMAT_VERSION
+        );
+        library
+    }
+
+    fn playlist(library: &MusicLibrary, name: &str) -> Arc<RwLock<Playlist>> {
+        let playlist = library
+            .playlists()
+            .iter()
+            .find(|playlist| playlist.read().unwrap().name() == name);
+        Arc::clone(playlist.unwrap())
+    }
+
+    fn is_track(item: &PlaylistItem, track: &Arc<RwLock<Track>>) -> bool {
+        matches!(item, PlaylistItem::Track(item) if Arc::ptr_eq(item, track))
+    }
+
+    #[test]
+    fn v0_loads() {
+        let library = load(include_str!("../fixtures/library_v0.json"));
+        let a = track_at(&library, "a.wav");
+        let b = track_at(&library, "b.wav");
+        // only the playlist had it, so it's added to the library
+        let c = track_at(&library, "c.wav");
+        assert_eq!(library.get_tracks().len(), 3);
+        assert_eq!(&*a.read().unwrap().name, "Hand Named");
+        assert_eq!(&*a.read().unwrap().tags[0], "chill");
+        assert_eq!(&*c.read().unwrap().name, "Only In A Playlist");
+
+        // the playlist that was already gone is dropped
+        let warm_up = playlist(&library, "Warm Up");
+        let warm_up = warm_up.read().unwrap();
+        let [first, PlaylistItem::Block(block)] = warm_up.items() else {
+            panic!("{:?}", warm_up.items());
+        };
+        assert!(is_track(first, &a));
+        assert!(is_track(&block[0], &b) && is_track(&block[1], &c));
+    }
+
+    #[test]
+    fn v1_loads() {
+        let library = load(include_str!("../fixtures/library_v1.json"));
+        let a = track_at(&library, "a.wav");
+        let b = track_at(&library, "b.wav");
+        assert_eq!(library.get_tracks().len(), 2);
+        assert_eq!(a.read().unwrap().album.as_deref(), Some("Night"));
+        assert_ne!(a.read().unwrap().uuid, b.read().unwrap().uuid);
+
+        let set = playlist(&library, "Set");
+        let closing = playlist(&library, "Closing");
+        let set = set.read().unwrap();
+        let [first, PlaylistItem::Playlist(nested)] = set.items() else {
+            panic!("{:?}", set.items());
+        };
+        assert!(is_track(first, &a));
+        assert!(Arc::ptr_eq(&nested.upgrade().unwrap(), &closing));
+
+        // the copies of a track in different playlists are all the one track
+        let closing = closing.read().unwrap();
+        let [PlaylistItem::Block(block)] = closing.items() else {
+            panic!("{:?}", closing.items());
+        };
+        assert!(is_track(&block[0], &b) && is_track(&block[1], &a));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut library = json!({ "version": FORMAT_VERSION + 1, "tracks": [] });
+        assert!(migrate(&mut library).is_err());
+    }
+}
@@ -1,247 +1,223 @@
 use std::{
-    collections::HashSet,
-    fs::{DirEntry, File, read_dir},
-    hash::{Hash, RandomState},
-    io::{BufReader, Write},
-    path::Path,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    fs::{File, metadata, read_dir, symlink_metadata},
+    hash::Hash,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
 };
 
-use color_eyre::Result;
+use color_eyre::{Result, eyre::bail};
 use rayon::prelude::*;
-use scc::HashMap;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tracing::{info, warn};
 use uuid::Uuid;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+use crate::{
+    album_art,
+    analysis::{self, Analysis, AnalysisCache, AudioProperties, Key, Loudness, ReplayGain},
+    cue::{self, CueSheet},
+    formats,
+    migrations::FORMAT_VERSION,
+    playback::{PlaybackSettings, Transition},
+    storage::{JsonStorage, SqliteStorage, Storage},
+    tags::{self, EmbeddedTags, TagChange},
+};
+
+pub(crate) mod stored;
+
+use stored::StoredLibrary;
+
+pub const LIBRARY_FILE: &str = "music_library.json";
+/// how many old copies of the library file are kept, as `music_library.json.1` and up
+pub const BACKUPS: usize = 3;
+/// the library as an SQLite database, which is used over `LIBRARY_FILE` when it's there
+pub const LIBRARY_DB: &str = "music_library.db";
+
+/// Saved as a `StoredLibrary`, see there for the layout.
+#[derive(Debug, Default)]
 pub struct MusicLibrary {
+    /// the version of the file this was loaded from, see `migrations`
+    version: u64,
     pub path: Box<str>,
     tracks: Vec<Arc<RwLock<Track>>>,
     playlists: Vec<Arc<RwLock<Playlist>>>,
     artists: Vec<Arc<str>>,
     pub tags: Vec<Arc<str>>,
+
+    pub playback: PlaybackSettings,
+    /// shared with the analysis jobs, see `analyze`
+    analysis: Arc<RwLock<AnalysisCache>>,
+
+    /// where the library is saved to, if anywhere
+    storage: Option<Box<dyn Storage>>,
+    /// why the library couldn't be loaded, in which case it's never saved over
+    load_error: Option<String>,
+}
+
+impl Serialize for MusicLibrary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        StoredLibrary::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MusicLibrary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        StoredLibrary::deserialize(deserializer).map(MusicLibrary::from)
+    }
 }
 
 impl MusicLibrary {
+    /// Loads the library in `path`, from `music_library.db` if there is one and otherwise
+    /// from `music_library.json`, then scans the folder.
     pub fn new_from_path(path: &str) -> Result<MusicLibrary> {
+        let folder = Path::new(path);
+        let db = folder.join(LIBRARY_DB);
+        let storage: Box<dyn Storage> = if db.exists() {
+            info!("using {LIBRARY_DB}");
+            Box::new(SqliteStorage::open(&db)?)
+        } else {
+            Box::new(JsonStorage::new(folder))
+        };
+        Self::new_with_storage(path, storage)
+    }
+
+    /// loads the library in `path` from `storage`, then scans the folder
+    pub fn new_with_storage(path: &str, mut storage: Box<dyn Storage>) -> Result<MusicLibrary> {
         let mut lib = MusicLibrary {
+            version: FORMAT_VERSION,
             path: path.into(),
             tracks: Vec::new(),
             playlists: Vec::new(),
             artists: Vec::new(),
             tags: Vec::new(),
+            playback: PlaybackSettings::default(),
+            analysis: Default::default(),
+            storage: None,
+            load_error: None,
         };
 
-        let dir = read_dir(path)?;
-        let prefix = Path::new(path);
-
-        let mut read_queue: Vec<DirEntry> = dir.flatten().collect();
-
-        match File::open(format!("{path}/music_library.json")) {
-            Ok(file) => {
-                info!("opened music_library.json");
-                let reader = BufReader::new(file);
-
-                match serde_json::from_reader(reader) {
-                    Ok(library) => {
-                        info!("successfully parsed json file");
-                        lib = library;
-                        if lib.path != path.into() {
-                            lib.path = path.into();
-                        }
-                    }
-                    Err(e) => warn!("couldn't parse json file: {e}"),
+        match storage.load() {
+            Ok(Some(library)) => {
+                info!("loaded library");
+                lib = library;
+                if lib.path != path.into() {
+                    lib.path = path.into();
                 }
             }
+            Ok(None) => info!("no saved library yet"),
             Err(e) => {
-                warn!("couldn't open json file {e}");
+                warn!("couldn't load library, it won't be saved over: {e}");
+                lib.load_error = Some(format!("couldn't load it: {e}"));
             }
         }
+        lib.storage = Some(storage);
 
-        let mut visited_track_paths: HashSet<Box<str>> =
-            HashSet::from_par_iter(lib.tracks.clone().into_par_iter().map(|track| {
-                if let Ok(read) = track.read() {
-                    read.path.clone()
-                } else {
-                    "".into()
-                }
-            }));
+        let summary = lib.rescan()?;
+        info!("scanned library: {summary}");
 
-        while let Some(item) = read_queue.pop() {
-            let file_type;
-            if let Ok(filetype) = item.file_type() {
-                file_type = filetype;
-            } else {
-                continue;
-            }
-            if file_type.is_file() {
-                let file_name = &item.file_name();
+        lib.intern_tags();
 
-                let extension;
-                if let Some(temp) = Path::new(file_name).extension() {
-                    extension = temp;
-                } else {
-                    continue;
-                }
+        Ok(lib)
+    }
 
-                let name;
-                if let Some(temp) = file_name.to_str() {
-                    name = temp.into()
-                } else {
-                    continue;
-                }
-                if extension == "wav" || extension == "mp3" || extension == "flac" {
-                    let item_full_path = item.path();
-                    let item_path;
-                    if let Ok(no_prefix) = item_full_path.strip_prefix(prefix) {
-                        item_path = no_prefix;
-                    } else {
-                        unreachable!();
-                    }
-                    let path: Box<str>;
-                    if let Some(temp) = item_path.to_str() {
-                        path = temp.into();
-                    } else {
-                        continue;
-                    }
+    /// Brings the tracks up to date with the whole folder, see `Scanner::run`.
+    pub fn rescan(&mut self) -> Result<RescanSummary> {
+        let scan = self.scanner(None).run()?;
+        Ok(self.apply_scan(scan))
+    }
 
-                    if !visited_track_paths.contains(&path) {
-                        let track = Arc::new(RwLock::new(Track {
-                            path: path.clone(),
-                            name,
-                            ..Default::default()
-                        }));
+    /// Something to bring the tracks up to date with the files at `changed`, or with the whole
+    /// folder with `None`. It doesn't need the library, so the files can be read on another
+    /// thread while the library's in use.
+    pub fn scanner(&self, changed: Option<Vec<PathBuf>>) -> Scanner {
+        let root = PathBuf::from(self.path.as_ref());
+        let scope = changed.map(|changed| {
+            changed
+                .iter()
+                .filter_map(|path| path.strip_prefix(&root).ok())
+                .map(Path::to_path_buf)
+                .collect()
+        });
+        Scanner {
+            root,
+            scope,
+            tracks: self.tracks.clone(),
+        }
+    }
 
-                        visited_track_paths.insert(path);
+    /// Adds the tracks a `Scanner` found. Scans have to be run and applied one at a time, or
+    /// two of them could both make a track of the same new file.
+    pub fn apply_scan(&mut self, scan: Scan) -> RescanSummary {
+        self.tracks.extend(scan.added);
+        self.intern_artists();
+        scan.summary
+    }
 
-                        lib.tracks.push(Arc::clone(&track));
+    /// makes tracks share the library's `Arc` for each of their tags, which `gc_tags` counts on
+    fn intern_tags(&mut self) {
+        let mut tags: HashSet<Arc<str>> = self.tags.drain(..).collect();
+        for lock in &self.tracks {
+            let Ok(mut track) = lock.write() else {
+                continue;
+            };
+            for tag in track.tags.iter_mut() {
+                match tags.get(tag) {
+                    Some(known) => *tag = Arc::clone(known),
+                    None => {
+                        tags.insert(Arc::clone(tag));
                     }
                 }
-            } else if file_type.is_dir() {
-                let dir;
-                if let Ok(temp) = read_dir(item.path()) {
-                    dir = temp;
-                } else {
-                    continue;
-                }
-                dir.flatten().for_each(|item| {
-                    read_queue.push(item);
-                });
             }
         }
+        self.tags = tags.into_iter().collect();
+        self.tags.sort_by_key(|tag| tag.to_lowercase());
+    }
 
-        let artists = scc::HashMap::with_hasher(RandomState::new());
-        let tags = scc::HashMap::with_hasher(RandomState::new());
-        let tracks = scc::HashMap::with_hasher(RandomState::new());
-        let playlists = scc::HashMap::with_hasher(RandomState::new());
-
-        lib.tracks.clone().par_iter().for_each(|track_lock| {
-            if let Ok(mut track) = track_lock.write() {
-                let _ = tracks.insert(track.path.to_string(), Arc::clone(track_lock));
-
-                let artist = track.artist.clone();
-                if let Some(artist) = artist {
-                    let artist_key = artist.to_string();
-                    if artists
-                        .insert(artist_key.clone(), Arc::clone(&artist))
-                        .is_err()
-                    {
-                        track.artist = artists.read(&artist_key, |_, v| v.clone());
-                    }
-                }
-                let tags_dedup = Vec::from_par_iter(track.tags.clone().par_iter().map(|tag| {
-                    if tags.insert(tag.to_string(), Arc::clone(tag)).is_err() {
-                        let tag_key = tag.to_string();
-                        if let Some(tag_dedup) = tags.read(&tag_key, |_k, v| Arc::clone(v)) {
-                            tag_dedup
-                        } else {
-                            unreachable!()
-                        }
-                    } else {
-                        Arc::clone(tag)
-                    }
-                }));
-                track.tags = tags_dedup;
-            }
-        });
-        lib.playlists.par_iter().for_each(|playlist_lock| {
-            if let Ok(playlist) = playlist_lock.read() {
-                if playlists
-                    .insert(playlist.uuid, Arc::clone(playlist_lock))
-                    .is_err()
-                {
-                    unreachable!()
-                }
-            }
-        });
-        lib.playlists.par_iter().for_each(|playlist_lock| {
-            if let Ok(mut playlist) = playlist_lock.write() {
-                fn dedup_item(
-                    item: &PlaylistItem,
-                    tracks: &HashMap<String, Arc<RwLock<Track>>>,
-                    playlists: &HashMap<Uuid, Arc<RwLock<Playlist>>>,
-                ) -> Option<PlaylistItem> {
-                    match item {
-                        PlaylistItem::Track(rw_lock) => {
-                            if let Ok(track) = rw_lock.read() {
-                                tracks
-                                    .read(&track.path.to_string(), |_, track| Arc::clone(track))
-                                    .map(PlaylistItem::Track)
-                            } else {
-                                unreachable!()
-                            }
-                        }
-                        PlaylistItem::Playlist(weak) => {
-                            if let Some(playlist_lock) = weak.upgrade() {
-                                if let Ok(playlist_read) = playlist_lock.read() {
-                                    playlists
-                                        .read(&playlist_read.uuid, |_, v| Arc::downgrade(v))
-                                        .map(PlaylistItem::Playlist)
-                                } else {
-                                    unreachable!()
-                                }
-                            } else {
-                                None
-                            }
-                        }
-                        PlaylistItem::Block(playlist_items) => {
-                            let items_dedup = playlist_items
-                                .par_iter()
-                                .filter_map(|item| dedup_item(item, tracks, playlists))
-                                .collect();
-                            Some(PlaylistItem::Block(items_dedup))
-                        }
-                    }
+    /// makes tracks with the same artist share one `Arc`, and keeps `artists` to the ones in use
+    fn intern_artists(&mut self) {
+        let mut artists: HashSet<Arc<str>> = HashSet::new();
+        for lock in &self.tracks {
+            let Ok(mut track) = lock.write() else {
+                continue;
+            };
+            let Some(artist) = track.artist.take() else {
+                continue;
+            };
+            let artist = match artists.get(&artist) {
+                Some(known) => Arc::clone(known),
+                None => {
+                    artists.insert(Arc::clone(&artist));
+                    artist
                 }
-
-                playlist.items = playlist
-                    .items
-                    .par_iter()
-                    .filter_map(|item| dedup_item(item, &tracks, &playlists))
-                    .collect();
-            }
-        });
-
-        let mut temp = Vec::new();
-        artists.scan(|_k, v| {
-            temp.push(Arc::clone(v));
-        });
-        lib.artists = temp;
-
-        let mut temp = Vec::new();
-        tags.scan(|_k, v| {
-            temp.push(Arc::clone(v));
-        });
-        lib.tags = temp;
-
-        Ok(lib)
+            };
+            track.artist = Some(artist);
+        }
+        self.artists = artists.into_iter().collect();
     }
 
     pub fn get_tracks(&self) -> &[Arc<RwLock<Track>>] {
         &self.tracks
     }
 
+    /// Writes a track's fields into the tags of its file, or with `dry_run` only says what that
+    /// would change.
+    pub fn write_tags(&self, track: &Arc<RwLock<Track>>, dry_run: bool) -> Result<Vec<TagChange>> {
+        let Ok(track) = track.read() else {
+            bail!("couldn't read track");
+        };
+        if let Some(cue) = &track.cue {
+            bail!(
+                "{} comes from the cue sheet {}, its tags live there",
+                track.name,
+                cue.sheet
+            );
+        }
+        let path = Path::new(self.path.as_ref()).join(track.path.as_ref());
+        tags::write_tags(&path, &track, dry_run)
+    }
+
     pub fn add_tag(&mut self, track: &Arc<RwLock<Track>>, tag: &str) {
         let known_tag = self
             .tags
@@ -290,68 +266,1978 @@ impl MusicLibrary {
     }
 }
 
-impl Drop for MusicLibrary {
-    fn drop(&mut self) {
-        if let Ok(json) = serde_json::to_vec_pretty(self) {
-            let path = &self.path;
-            if let Ok(mut file) = File::create(format!("{path}/music_library.json")) {
-                let _ = file.write_all(json.as_ref());
+impl MusicLibrary {
+    pub fn playlists(&self) -> &[Arc<RwLock<Playlist>>] {
+        &self.playlists
+    }
+
+    pub fn playlist(&self, uuid: Uuid) -> Option<Arc<RwLock<Playlist>>> {
+        self.playlists
+            .iter()
+            .find(|lock| lock.read().is_ok_and(|playlist| playlist.uuid == uuid))
+            .cloned()
+    }
+
+    /// makes a new empty playlist at the end of the list
+    pub fn create_playlist(&mut self, name: &str) -> Arc<RwLock<Playlist>> {
+        let playlist = Arc::new(RwLock::new(Playlist {
+            name: name.into(),
+            ..Default::default()
+        }));
+        self.playlists.push(Arc::clone(&playlist));
+        playlist
+    }
+
+    pub fn rename_playlist(&self, uuid: Uuid, name: &str) -> Result<()> {
+        let playlist = self.playlist_lock(uuid)?;
+        let Ok(mut playlist) = playlist.write() else {
+            bail!("couldn't write playlist");
+        };
+        playlist.name = name.into();
+        Ok(())
+    }
+
+    /// Deletes a playlist, and takes it out of every playlist it's in.
+    pub fn delete_playlist(&mut self, uuid: Uuid) -> Result<()> {
+        let playlist = self.playlist_lock(uuid)?;
+        self.playlists.retain(|lock| !Arc::ptr_eq(lock, &playlist));
+        for lock in &self.playlists {
+            let Ok(mut other) = lock.write() else {
+                bail!("couldn't write playlist");
+            };
+            other.remove_references(&playlist);
+        }
+        Ok(())
+    }
+
+    /// Copies a playlist under a new uuid, right after the original. The copy has the same
+    /// tracks and nested playlists as the original, not copies of them.
+    pub fn duplicate_playlist(&mut self, uuid: Uuid) -> Result<Arc<RwLock<Playlist>>> {
+        let Some(index) = self.playlist_index(uuid) else {
+            bail!("no playlist {uuid}");
+        };
+        let Ok(original) = self.playlists[index].read() else {
+            bail!("couldn't read playlist");
+        };
+        let copy = Arc::new(RwLock::new(Playlist {
+            name: format!("{} copy", original.name).into(),
+            uuid: Uuid::new_v4(),
+            ..original.clone()
+        }));
+        drop(original);
+        self.playlists.insert(index + 1, Arc::clone(&copy));
+        Ok(copy)
+    }
+
+    /// Puts `item` into a playlist at `at`, a path of indices: the first into the playlist's
+    /// items, the next into the block at that index, and so on. The tracks and playlists in
+    /// `item` have to be this library's, and a playlist can't end up inside itself.
+    pub fn insert_item(&self, playlist: Uuid, at: &[usize], item: PlaylistItem) -> Result<()> {
+        let playlist = self.playlist_lock(playlist)?;
+        self.check_item(&item, &playlist)?;
+        let Ok(mut playlist) = playlist.write() else {
+            bail!("couldn't write playlist");
+        };
+        playlist.insert(at, item, None)
+    }
+
+    /// takes the item at `at` out of a playlist, see `insert_item` for what `at` means
+    pub fn remove_item(&self, playlist: Uuid, at: &[usize]) -> Result<PlaylistItem> {
+        let playlist = self.playlist_lock(playlist)?;
+        let Ok(mut playlist) = playlist.write() else {
+            bail!("couldn't write playlist");
+        };
+        Ok(playlist.remove(at)?.0)
+    }
+
+    /// Moves an item within a playlist, into or out of blocks too. `to` is where it ends up,
+    /// counted as if it had already been taken out of `from`. A transition after the item
+    /// moves along with it.
+    pub fn move_item(&self, playlist: Uuid, from: &[usize], to: &[usize]) -> Result<()> {
+        let playlist = self.playlist_lock(playlist)?;
+        let Ok(mut playlist) = playlist.write() else {
+            bail!("couldn't write playlist");
+        };
+        let (item, transition) = playlist.remove(from)?;
+        if let Err(e) = playlist.insert(to, item.clone(), transition) {
+            playlist.insert(from, item, transition)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Groups the item at `at` with the one after it into a block. If either of them is a block
+    /// already, the other joins it. The transition after the second one ends up after the
+    /// block, and the one between them goes since blocks play gaplessly.
+    pub fn group(&self, playlist: Uuid, at: &[usize]) -> Result<()> {
+        let playlist = self.playlist_lock(playlist)?;
+        let Ok(mut playlist) = playlist.write() else {
+            bail!("couldn't write playlist");
+        };
+        playlist.group(at)
+    }
+
+    /// Puts the items of the block at `at` back where the block was, with the transition after
+    /// the block after its last item.
+    pub fn ungroup(&self, playlist: Uuid, at: &[usize]) -> Result<()> {
+        let playlist = self.playlist_lock(playlist)?;
+        let Ok(mut playlist) = playlist.write() else {
+            bail!("couldn't write playlist");
+        };
+        playlist.ungroup(at)
+    }
+
+    fn playlist_index(&self, uuid: Uuid) -> Option<usize> {
+        self.playlists
+            .iter()
+            .position(|lock| lock.read().is_ok_and(|playlist| playlist.uuid == uuid))
+    }
+
+    fn playlist_lock(&self, uuid: Uuid) -> Result<Arc<RwLock<Playlist>>> {
+        let Some(playlist) = self.playlist(uuid) else {
+            bail!("no playlist {uuid}");
+        };
+        Ok(playlist)
+    }
+
+    /// makes sure `item` only has this library's tracks and playlists, and that putting it in
+    /// `into` wouldn't make a playlist contain itself
+    fn check_item(&self, item: &PlaylistItem, into: &Arc<RwLock<Playlist>>) -> Result<()> {
+        match item {
+            PlaylistItem::Track(track) => {
+                if !self.tracks.iter().any(|known| Arc::ptr_eq(known, track)) {
+                    bail!("track isn't in the library");
+                }
+            }
+            PlaylistItem::Playlist(weak) => {
+                let Some(playlist) = weak.upgrade() else {
+                    bail!("playlist has been deleted");
+                };
+                if !self
+                    .playlists
+                    .iter()
+                    .any(|known| Arc::ptr_eq(known, &playlist))
+                {
+                    bail!("playlist isn't in the library");
+                }
+                if item.contains_playlist(into, &mut HashSet::new()) {
+                    bail!("a playlist can't contain itself");
+                }
+            }
+            PlaylistItem::Block(items) => {
+                for item in items {
+                    self.check_item(item, into)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MusicLibrary {
+    /// Analyses every track that hasn't been yet, or was by an older `ANALYSIS_VERSION`, in
+    /// the background on rayon's pool. Each file is only done once, however many tracks share
+    /// it. What's found is filled into the tracks, except for fields that have been edited.
+    /// `on_progress` is called from the pool whenever a file is done.
+    pub fn analyze(&self, on_progress: impl Fn() + Send + Sync + 'static) {
+        let mut files = HashMap::new();
+        let mut albums: HashMap<_, Vec<_>> = HashMap::new();
+        for lock in &self.tracks {
+            let Ok(track) = lock.read() else {
+                continue;
+            };
+            if let Some(album) = track.album_key() {
+                albums.entry(album).or_default().push(Arc::clone(lock));
+            }
+            let Some(stamp) = track.file.as_ref().filter(|_| !track.missing) else {
+                continue;
+            };
+            let full_path = Path::new(self.path.as_ref()).join(track.path.as_ref());
+            let (_, tracks) = files
+                .entry(stamp.hash.clone())
+                .or_insert_with(|| (full_path, Vec::new()));
+            tracks.push(Arc::clone(lock));
+        }
+        let Ok(mut cache) = self.analysis.write() else {
+            warn!("couldn't queue files for analysis");
+            return;
+        };
+        let mut queue = Vec::new();
+        let mut done = Vec::new();
+        for (hash, (full_path, tracks)) in files {
+            match cache.results.get(&hash) {
+                // tracks that are new since, like ones from a cue sheet, still need it
+                Some(analysis) if analysis.version == analysis::ANALYSIS_VERSION => {
+                    done.push((tracks, analysis.clone()));
+                }
+                _ => {
+                    if cache.queued.insert(hash.clone()) {
+                        queue.push((hash, full_path, tracks));
+                    }
+                }
+            }
+        }
+        drop(cache);
+        // not while the cache is locked, whoever's holding a track might be waiting on it
+        for (tracks, analysis) in done {
+            for lock in tracks {
+                if let Ok(mut track) = lock.write() {
+                    track.apply_analysis(&analysis);
+                }
+            }
+        }
+
+        // albums with a track waiting on analysis are worked out once it's all done, the rest
+        // now, in case their tracks changed. The tracks are read before the cache is locked,
+        // since whoever's holding a track might be waiting on the cache.
+        let waiting: HashSet<_> = queue
+            .iter()
+            .flat_map(|(.., tracks)| tracks)
+            .filter_map(|lock| lock.read().ok()?.album_key())
+            .collect();
+        let (waiting, ready): (HashMap<_, _>, HashMap<_, _>) = albums
+            .into_iter()
+            .partition(|(album, _)| waiting.contains(album));
+        let ready: Vec<_> = ready
+            .into_iter()
+            .map(|(album, tracks)| (album, album_parts(&tracks)))
+            .collect();
+        if let Ok(mut cache) = self.analysis.write() {
+            cache.albums.retain(|album, _| waiting.contains_key(album));
+            for (album, parts) in ready {
+                if let Some(loudness) = album_loudness(&cache, &parts) {
+                    cache.albums.insert(album, loudness);
+                }
+            }
+        }
+        if queue.is_empty() {
+            return;
+        }
+
+        info!("analysing {} files", queue.len());
+        let cache = Arc::clone(&self.analysis);
+        rayon::spawn(move || {
+            queue.into_par_iter().for_each(|(hash, full_path, tracks)| {
+                match analysis::analyze(&full_path) {
+                    Ok(analysis) => {
+                        for lock in tracks {
+                            if let Ok(mut track) = lock.write() {
+                                track.apply_analysis(&analysis);
+                            }
+                        }
+                        if let Ok(mut cache) = cache.write() {
+                            cache.results.insert(hash.clone(), analysis);
+                            cache.queued.remove(&hash);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("couldn't analyse {}: {e}", full_path.display());
+                        // it's tried again next time, in case it was only unreadable for now
+                        if let Ok(mut cache) = cache.write() {
+                            cache.queued.remove(&hash);
+                        }
+                    }
+                }
+                on_progress();
+            });
+            let waiting: Vec<_> = waiting
+                .into_iter()
+                .map(|(album, tracks)| (album, album_parts(&tracks)))
+                .collect();
+            if let Ok(mut cache) = cache.write() {
+                for (album, parts) in waiting {
+                    match album_loudness(&cache, &parts) {
+                        Some(loudness) => cache.albums.insert(album, loudness),
+                        None => cache.albums.remove(&album),
+                    };
+                }
             }
+        });
+    }
+
+    /// what analysis found out about a track's file, if it's been analysed
+    pub fn analysis(&self, track: &Track) -> Option<Analysis> {
+        let stamp = track.file.as_ref()?;
+        self.analysis.read().ok()?.results.get(&stamp.hash).cloned()
+    }
+
+    /// The track's duration, sample rate and so on. The duration is of what actually plays,
+    /// between its cue points and inside its part of the file for a track from a cue sheet.
+    pub fn properties(&self, track: &Track) -> Option<AudioProperties> {
+        let properties = self.analysis(track)?.properties;
+        let (start, end) = track.playing_range();
+        Some(properties.range(start, end))
+    }
+
+    /// How loud the track is. Tracks from a cue sheet get the measurements of the whole file.
+    pub fn loudness(&self, track: &Track) -> Option<Loudness> {
+        self.analysis(track)?.loudness
+    }
+
+    /// How loud the album the track is on is, when it says what album that is, as of the last
+    /// time its tracks were analysed. See `album_loudness` for how.
+    pub fn album_loudness(&self, track: &Track) -> Option<Loudness> {
+        let album = track.album_key()?;
+        self.analysis.read().ok()?.albums.get(&album).copied()
+    }
+
+    /// The ReplayGain to play the track with. What its tags say wins, anything they don't say
+    /// comes from analysis.
+    pub fn replay_gain(&self, track: &Track) -> ReplayGain {
+        let loudness = self.loudness(track);
+        let album = self.album_loudness(track);
+        track.replay_gain.or(ReplayGain {
+            track_gain: loudness.map(|loudness| loudness.gain()),
+            track_peak: loudness.map(|loudness| loudness.peak()),
+            album_gain: album.map(|album| album.gain()),
+            album_peak: album.map(|album| album.peak()),
+        })
+    }
+
+    /// How long a playlist plays for, with nested playlists. Tracks that haven't been analysed
+    /// yet don't count.
+    pub fn playlist_duration(&self, playlist: &Playlist) -> Duration {
+        self.items_duration(&playlist.items, &mut HashSet::from([playlist.uuid]))
+    }
+
+    fn items_duration(&self, items: &[PlaylistItem], visited: &mut HashSet<Uuid>) -> Duration {
+        items
+            .iter()
+            .map(|item| match item {
+                PlaylistItem::Track(lock) => lock
+                    .read()
+                    .ok()
+                    .and_then(|track| self.properties(&track))
+                    .map_or(Duration::ZERO, |properties| properties.duration),
+                PlaylistItem::Playlist(weak) => {
+                    let Some(lock) = weak.upgrade() else {
+                        return Duration::ZERO;
+                    };
+                    let Ok(playlist) = lock.read() else {
+                        return Duration::ZERO;
+                    };
+                    if !visited.insert(playlist.uuid) {
+                        return Duration::ZERO;
+                    }
+                    let duration = self.items_duration(&playlist.items, visited);
+                    visited.remove(&playlist.uuid);
+                    duration
+                }
+                PlaylistItem::Block(items) => self.items_duration(items, visited),
+            })
+            .sum()
+    }
+}
+
+impl MusicLibrary {
+    /// Saves the library to wherever it was loaded from. Refuses to if it was there but
+    /// couldn't be loaded, since that would throw away whatever was in it.
+    pub fn save(&mut self) -> Result<()> {
+        if let Some(e) = &self.load_error {
+            bail!("not saving over the library, {e}");
+        }
+        self.version = FORMAT_VERSION;
+        let Some(mut storage) = self.storage.take() else {
+            bail!("library has nowhere to be saved");
+        };
+        let saved = storage.save(self);
+        self.storage = Some(storage);
+        saved
+    }
+
+    /// Moves the library into `music_library.db`, which it's loaded from and saved to from
+    /// then on. The json file is left where it is.
+    pub fn move_to_sqlite(&mut self) -> Result<()> {
+        if let Some(e) = &self.load_error {
+            bail!("not moving the library, {e}");
+        }
+        let mut storage = SqliteStorage::open(&Path::new(self.path.as_ref()).join(LIBRARY_DB))?;
+        if storage.load()?.is_some() {
+            bail!("{LIBRARY_DB} already has a library in it");
         }
+        storage.save(self)?;
+        self.storage = Some(Box::new(storage));
+        Ok(())
+    }
+}
+
+/// where a file named in a cue sheet is, relative to the library folder like the sheet
+fn sheet_file_path(sheet: &str, file: &str) -> Option<Box<str>> {
+    let path = Path::new(sheet).parent()?.join(file);
+    path.to_str().map(Box::from)
+}
+
+/// whether a scan picks up the file at `path` as a track, see `formats`
+pub fn is_audio_file(path: &Path) -> bool {
+    formats::identify(path).is_some()
+}
+
+/// whether `path` is hidden or inside a hidden folder under `root`
+pub(crate) fn is_hidden(root: &Path, path: &Path) -> bool {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .any(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .is_none_or(|name| name.starts_with('.'))
+        })
+}
+
+/// the hash of the file an album's track is in, and which part of it plays
+type AlbumPart = (Box<str>, (Duration, Option<Duration>));
+
+/// The files an album's tracks are in, and which part of each plays.
+fn album_parts(tracks: &[Arc<RwLock<Track>>]) -> Vec<AlbumPart> {
+    tracks
+        .iter()
+        .filter_map(|lock| {
+            let track = lock.read().ok()?;
+            Some((track.file.as_ref()?.hash.clone(), track.playing_range()))
+        })
+        .collect()
+}
+
+/// How loud an album is, worked out from the measurements of its tracks rather than measured
+/// again: the loudness is their average power going by how long each is, the peak the highest
+/// of theirs, and the range covers the widest of theirs and how far apart they are. Tracks
+/// from a cue sheet count the measurements of their whole file for as long as they play.
+fn album_loudness(cache: &AnalysisCache, parts: &[AlbumPart]) -> Option<Loudness> {
+    let measured: Vec<(Loudness, f64)> = parts
+        .iter()
+        .filter_map(|(hash, (start, end))| {
+            let analysis = cache.results.get(hash)?;
+            let length = analysis.properties.range(*start, *end).duration;
+            Some((analysis.loudness?, length.as_secs_f64()))
+        })
+        .collect();
+    let length: f64 = measured.iter().map(|(_, length)| length).sum();
+    if measured.is_empty() || length <= 0.0 {
+        return None;
     }
+
+    let power = measured
+        .iter()
+        .map(|(loudness, length)| 10f64.powf(loudness.integrated / 10.0) * length)
+        .sum::<f64>()
+        / length;
+    let (quietest, loudest) = measured.iter().fold(
+        (f64::INFINITY, f64::NEG_INFINITY),
+        |(low, high), (loudness, _)| (low.min(loudness.integrated), high.max(loudness.integrated)),
+    );
+    Some(Loudness {
+        integrated: 10.0 * power.log10(),
+        range: measured
+            .iter()
+            .map(|(loudness, _)| loudness.range)
+            .fold(loudest - quietest, f64::max),
+        true_peak: measured
+            .iter()
+            .map(|(loudness, _)| loudness.true_peak)
+            .fold(f64::NEG_INFINITY, f64::max),
+    })
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Track {
+    /// stays the same through moves and renames, playlists point at tracks by it
+    pub uuid: Uuid,
     pub path: Box<str>,
     pub name: Box<str>,
     pub artist: Option<Arc<str>>,
+    #[serde(default)]
+    pub album: Option<Arc<str>>,
+    #[serde(default)]
+    pub album_artist: Option<Arc<str>>,
+    #[serde(default)]
+    pub track_number: Option<u32>,
+    #[serde(default)]
+    pub disc_number: Option<u32>,
+    #[serde(default)]
+    pub year: Option<i32>,
+    #[serde(default)]
+    pub genre: Option<Arc<str>>,
     pub album_art: Option<String>,
     pub tags: Vec<Arc<str>>,
+
+    /// fields the user has set by hand, which the tags in the file never overwrite
+    #[serde(default)]
+    pub edited: BTreeSet<TrackField>,
+    /// what the file looked like when it was last scanned
+    #[serde(default)]
+    pub file: Option<FileStamp>,
+    /// the file wasn't there on the last scan
+    #[serde(default)]
+    pub missing: bool,
+    /// for a track that's part of a longer file, which part
+    #[serde(default)]
+    pub cue: Option<CueRange>,
+    /// from analysis unless it's been edited
+    #[serde(default)]
+    pub bpm: Option<f64>,
+    /// where the first bar starts, from the start of the track
+    #[serde(default)]
+    pub downbeat: Option<Duration>,
+    /// from analysis unless it's been edited
+    #[serde(default)]
+    pub key: Option<Key>,
+    /// what the file's tags say, which wins over what analysis measured
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+    /// where playback starts and stops, from the start of the track, so the silence either end
+    /// is skipped. From analysis unless they've been edited, `None` plays from the start or to
+    /// the end.
+    #[serde(default)]
+    pub cue_in: Option<Duration>,
+    #[serde(default)]
+    pub cue_out: Option<Duration>,
 }
 
-impl Track {
-    pub fn add_tag(&mut self, tag: &str) {
-        self.tags.push(tag.into());
-    }
+/// Enough about a file to tell whether it changed since the last scan, and to find it again by
+/// its contents if it moves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// blake3 of the whole file
+    pub hash: Box<str>,
 }
 
-impl Hash for Track {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.path.hash(state);
-        self.name.hash(state);
-        self.artist
-            .as_ref()
-            .map(|string| string.as_ref())
-            .hash(state);
-        self.album_art.hash(state);
-        let mut tags: Vec<&str> = self.tags.iter().map(|tag| tag.as_ref()).collect();
-        tags.sort_by_key(|t| t.to_lowercase());
-        tags.hash(state);
+/// Where a track from a cue sheet is in the file it shares with the sheet's other tracks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CueRange {
+    /// the sheet it comes from, relative to the library folder
+    pub sheet: Box<str>,
+    /// its number in the sheet
+    pub number: u32,
+    pub start: Duration,
+    /// where the next track starts, or `None` to play to the end of the file
+    pub end: Option<Duration>,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> Result<Self> {
+        let metadata = metadata(path)?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(File::open(path)?)?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash: hasher.finalize().to_hex().as_str().into(),
+        })
+    }
+
+    /// whether the file still has the size and modification time it had when this was made
+    pub fn is_current(&self, path: &Path) -> bool {
+        metadata(path).is_ok_and(|metadata| {
+            self.size == metadata.len() && self.modified == metadata.modified().ok()
+        })
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Playlist {
-    name: Box<str>,
-    items: Vec<PlaylistItem>,
+/// Brings tracks up to date with what's on disk, made with `MusicLibrary::scanner`.
+pub struct Scanner {
+    root: PathBuf,
+    /// what changed, relative to `root`, or `None` to look at everything
+    scope: Option<Vec<PathBuf>>,
+    /// the library's tracks when this was made
+    tracks: Vec<Arc<RwLock<Track>>>,
+}
 
-    uuid: Uuid,
+/// What a `Scanner` found, for `MusicLibrary::apply_scan`.
+pub struct Scan {
+    /// tracks for files that weren't in the library yet
+    added: Vec<Arc<RwLock<Track>>>,
+    pub summary: RescanSummary,
 }
 
-impl Default for Playlist {
-    fn default() -> Self {
-        Self {
-            name: Default::default(),
-            items: Default::default(),
-            uuid: Uuid::new_v4(),
+impl Scanner {
+    /// Goes through the library's files, matching them to tracks by path, and failing that by
+    /// content, so moved and renamed files keep their track along with its tags and playlists.
+    /// Tracks whose file is gone are marked missing rather than dropped.
+    pub fn run(mut self) -> Result<Scan> {
+        let prefix = &self.root;
+        let mut summary = RescanSummary::default();
+        let mut added = Vec::new();
+
+        // a sheet is read again whenever a file it splits up changes, and the files it split
+        // up are looked at again whenever it changes, since they're whole again if it's gone
+        if let Some(scope) = &mut self.scope {
+            let related: Vec<PathBuf> = self
+                .tracks
+                .iter()
+                .filter_map(|lock| {
+                    let track = lock.read().ok()?;
+                    let cue = track.cue.as_ref()?;
+                    let changed = |path: &str| {
+                        let path = Path::new(path);
+                        scope.iter().any(|changed| path.starts_with(changed))
+                    };
+                    if changed(&track.path) {
+                        Some(PathBuf::from(cue.sheet.as_ref()))
+                    } else if changed(&cue.sheet) {
+                        Some(PathBuf::from(track.path.as_ref()))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            scope.extend(related);
+            scope.sort();
+            scope.dedup();
         }
-    }
-}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum PlaylistItem {
-    Track(Arc<RwLock<Track>>),
-    Playlist(Weak<RwLock<Playlist>>),
-    Block(Vec<PlaylistItem>),
+        let mut read_queue: Vec<PathBuf> = match &self.scope {
+            None => read_dir(prefix)?
+                .flatten()
+                .map(|item| item.path())
+                .collect(),
+            Some(scope) => scope.iter().map(|path| prefix.join(path)).collect(),
+        };
+        let art_cache = prefix.join(album_art::ART_CACHE);
+        let mut found = Vec::new();
+        let mut sheets = Vec::new();
+        while let Some(full_path) = read_queue.pop() {
+            // our own, and it only has pictures in it
+            if full_path.starts_with(&art_cache) {
+                continue;
+            }
+            // hidden files and folders aren't part of the library, and files we're in the
+            // middle of writing are hidden until they're renamed into place
+            if is_hidden(prefix, &full_path) {
+                continue;
+            }
+            // anything that's gone is dealt with through the tracks that were made of it
+            let Ok(metadata) = symlink_metadata(&full_path) else {
+                continue;
+            };
+            if metadata.is_dir() {
+                if let Ok(dir) = read_dir(&full_path) {
+                    read_queue.extend(dir.flatten().map(|item| item.path()));
+                }
+                continue;
+            }
+            let path = full_path
+                .strip_prefix(prefix)
+                .ok()
+                .and_then(|path| path.to_str())
+                .map(Box::<str>::from);
+            let Some(path) = path else {
+                continue;
+            };
+            if is_audio_file(&full_path) {
+                found.push((path, full_path));
+            } else if cue::is_cue_sheet(&full_path) {
+                sheets.push((path, full_path));
+            }
+        }
+        // changes can overlap, like a folder and a file in it
+        found.sort();
+        found.dedup();
+        sheets.sort();
+        sheets.dedup();
+
+        let sheets: Vec<(Box<str>, CueSheet)> = sheets
+            .into_iter()
+            .filter_map(|(path, full_path)| match CueSheet::read(&full_path) {
+                Ok(sheet) => Some((path, sheet)),
+                Err(e) => {
+                    warn!("couldn't read cue sheet {path}: {e}");
+                    None
+                }
+            })
+            .collect();
+        // files that cue sheets split up aren't tracks of their own
+        let split: HashSet<Box<str>> = sheets
+            .iter()
+            .flat_map(|(path, sheet)| {
+                sheet
+                    .files
+                    .iter()
+                    .filter_map(|file| sheet_file_path(path, &file.path))
+            })
+            .collect();
+        found.retain(|(path, _)| !split.contains(path));
+        // so tracks made of them before their sheet showed up are retired, they're found again
+        // if it goes
+        for lock in &self.tracks {
+            let whole = lock.read().is_ok_and(|track| {
+                track.cue.is_none() && !track.missing && split.contains(&track.path)
+            });
+            if !whole {
+                continue;
+            }
+            if let Ok(mut track) = lock.write() {
+                info!("{} is split up by a cue sheet now", track.path);
+                track.missing = true;
+                summary.removed += 1;
+            }
+        }
+
+        let known: HashMap<Box<str>, Arc<RwLock<Track>>> = self
+            .tracks
+            .iter()
+            .filter_map(|lock| {
+                let track = lock.read().ok()?;
+                (track.cue.is_none() && !split.contains(&track.path) && self.in_scope(&track.path))
+                    .then(|| (track.path.clone(), Arc::clone(lock)))
+            })
+            .collect();
+        let found_paths: HashSet<&str> = found.iter().map(|(path, _)| path.as_ref()).collect();
+
+        // files that are where we left them, and tracks that aren't anymore
+        let (present, new): (Vec<_>, Vec<_>) =
+            found.iter().partition(|(path, _)| known.contains_key(path));
+        let mut gone: Vec<Arc<RwLock<Track>>> = known
+            .iter()
+            .filter(|(path, _)| !found_paths.contains(path.as_ref()))
+            .map(|(_, track)| Arc::clone(track))
+            .collect();
+
+        let mut touched = Vec::new();
+        let present: Vec<_> = present
+            .par_iter()
+            .filter_map(|(path, full_path)| {
+                let lock = known.get(path)?;
+                let mut track = lock.write().ok()?;
+                let change = track.restamp(full_path);
+                if track.missing {
+                    track.missing = false;
+                    return Some((Arc::clone(lock), Some(Change::Restored)));
+                }
+                Some((Arc::clone(lock), change))
+            })
+            .collect();
+        for (track, change) in present {
+            match change {
+                Some(Change::Restored) => summary.restored += 1,
+                Some(Change::Changed) => summary.changed += 1,
+                Some(Change::Stamped) | None => (),
+            }
+            if change.is_some() {
+                touched.push(track);
+            }
+        }
+
+        let new: Vec<(Box<str>, &PathBuf, Option<FileStamp>)> = new
+            .into_par_iter()
+            .map(|(path, full_path)| (path.clone(), full_path, FileStamp::read(full_path).ok()))
+            .collect();
+        for (path, full_path, stamp) in new {
+            let moved = stamp.as_ref().and_then(|stamp| {
+                let index = gone.iter().position(|track| {
+                    track.read().is_ok_and(|track| {
+                        track
+                            .file
+                            .as_ref()
+                            .is_some_and(|old| old.hash == stamp.hash)
+                    })
+                })?;
+                Some(gone.swap_remove(index))
+            });
+
+            if let Some(lock) = moved {
+                if let Ok(mut track) = lock.write() {
+                    info!("{} moved to {path}", track.path);
+                    track.path = path;
+                    track.file = stamp;
+                    track.missing = false;
+                }
+                summary.moved += 1;
+                touched.push(lock);
+                continue;
+            }
+
+            let name = full_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into())
+                .unwrap_or_default();
+            let track = Arc::new(RwLock::new(Track {
+                path,
+                name,
+                file: stamp,
+                ..Default::default()
+            }));
+            added.push(Arc::clone(&track));
+            summary.added += 1;
+            touched.push(track);
+        }
+
+        for lock in gone {
+            let Ok(mut track) = lock.write() else {
+                continue;
+            };
+            if !track.missing {
+                warn!("{} is missing", track.path);
+                track.missing = true;
+                summary.removed += 1;
+            }
+        }
+
+        let cue_tags = self.rescan_cue_sheets(&sheets, &mut summary, &mut touched, &mut added);
+
+        touched.par_iter().for_each(|lock| {
+            if let Ok(mut track) = lock.write() {
+                track.read_file(prefix);
+            }
+        });
+        // a cue sheet knows better than the tags of the whole file, except for the ReplayGain
+        // it doesn't have
+        for (lock, mut tags) in cue_tags {
+            if let Ok(mut track) = lock.write() {
+                tags.replay_gain = tags.replay_gain.or(track.replay_gain);
+                track.apply_tags(tags);
+            }
+        }
+
+        Ok(Scan { added, summary })
+    }
+
+    /// Brings the tracks that come from cue sheets up to date with the sheets. They're matched
+    /// by sheet and track number, and what each sheet says about its tracks is handed back to
+    /// be applied once their files have been read.
+    fn rescan_cue_sheets(
+        &self,
+        sheets: &[(Box<str>, CueSheet)],
+        summary: &mut RescanSummary,
+        touched: &mut Vec<Arc<RwLock<Track>>>,
+        added: &mut Vec<Arc<RwLock<Track>>>,
+    ) -> Vec<(Arc<RwLock<Track>>, EmbeddedTags)> {
+        let mut known: HashMap<(Box<str>, u32), Arc<RwLock<Track>>> = self
+            .tracks
+            .iter()
+            .filter_map(|lock| {
+                let track = lock.read().ok()?;
+                let cue = track.cue.as_ref().filter(|cue| self.in_scope(&cue.sheet))?;
+                Some(((cue.sheet.clone(), cue.number), Arc::clone(lock)))
+            })
+            .collect();
+
+        let mut cue_tags = Vec::new();
+        for (sheet_path, sheet) in sheets {
+            for file in &sheet.files {
+                let Some(path) = sheet_file_path(sheet_path, &file.path) else {
+                    continue;
+                };
+                // the file's tracks share its stamp, so it's only hashed once for all of them
+                let full_path = self.root.join(path.as_ref());
+                let stamp = file
+                    .tracks
+                    .iter()
+                    .find_map(|cue_track| {
+                        let lock = known.get(&(sheet_path.clone(), cue_track.number))?;
+                        lock.read().ok()?.file.clone()
+                    })
+                    .filter(|stamp| stamp.is_current(&full_path))
+                    .or_else(|| FileStamp::read(&full_path).ok());
+                for (i, cue_track) in file.tracks.iter().enumerate() {
+                    let range = CueRange {
+                        sheet: sheet_path.clone(),
+                        number: cue_track.number,
+                        start: cue_track.start,
+                        end: file.tracks.get(i + 1).map(|next| next.start),
+                    };
+                    let tags = sheet.tags(file, cue_track);
+
+                    let Some(lock) = known.remove(&(sheet_path.clone(), cue_track.number)) else {
+                        let track = Arc::new(RwLock::new(Track {
+                            path: path.clone(),
+                            name: tags.title.clone().unwrap_or_default(),
+                            cue: Some(range),
+                            file: stamp.clone(),
+                            ..Default::default()
+                        }));
+                        added.push(Arc::clone(&track));
+                        summary.added += 1;
+                        touched.push(Arc::clone(&track));
+                        cue_tags.push((track, tags));
+                        continue;
+                    };
+                    if let Ok(mut track) = lock.write() {
+                        let moved = track.cue.as_ref() != Some(&range) || track.path != path;
+                        if track.missing {
+                            track.missing = false;
+                            summary.restored += 1;
+                            touched.push(Arc::clone(&lock));
+                        } else if moved {
+                            summary.changed += 1;
+                            touched.push(Arc::clone(&lock));
+                        }
+                        track.path = path.clone();
+                        track.cue = Some(range);
+                        track.file = stamp.clone();
+                    }
+                    cue_tags.push((lock, tags));
+                }
+            }
+        }
+
+        // whatever's left isn't in its sheet anymore
+        for lock in known.into_values() {
+            let Ok(mut track) = lock.write() else {
+                continue;
+            };
+            if !track.missing {
+                warn!("{} is missing from its cue sheet", track.name);
+                track.missing = true;
+                summary.removed += 1;
+            }
+        }
+        cue_tags
+    }
+
+    fn in_scope(&self, path: &str) -> bool {
+        self.scope.as_ref().is_none_or(|scope| {
+            let path = Path::new(path);
+            scope.iter().any(|changed| path.starts_with(changed))
+        })
+    }
+}
+
+/// What a rescan found.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RescanSummary {
+    pub added: usize,
+    /// tracks whose file is gone, they're kept and marked missing
+    pub removed: usize,
+    pub moved: usize,
+    pub changed: usize,
+    /// missing tracks whose file came back
+    pub restored: usize,
+}
+
+impl Display for RescanSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} moved, {} changed, {} restored",
+            self.added, self.removed, self.moved, self.changed, self.restored
+        )
+    }
+}
+
+/// what a rescan did to a track whose file is where it was
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    /// it had no stamp yet
+    Stamped,
+    Changed,
+    Restored,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TrackField {
+    Name,
+    Artist,
+    Album,
+    AlbumArtist,
+    TrackNumber,
+    DiscNumber,
+    Year,
+    Genre,
+    AlbumArt,
+    Bpm,
+    Downbeat,
+    Key,
+    CueIn,
+    CueOut,
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Self {
+            uuid: Uuid::new_v4(),
+            path: Default::default(),
+            name: Default::default(),
+            artist: Default::default(),
+            album: Default::default(),
+            album_artist: Default::default(),
+            track_number: Default::default(),
+            disc_number: Default::default(),
+            year: Default::default(),
+            genre: Default::default(),
+            album_art: Default::default(),
+            tags: Default::default(),
+            edited: Default::default(),
+            file: Default::default(),
+            missing: Default::default(),
+            cue: Default::default(),
+            bpm: Default::default(),
+            downbeat: Default::default(),
+            key: Default::default(),
+            replay_gain: Default::default(),
+            cue_in: Default::default(),
+            cue_out: Default::default(),
+        }
+    }
+}
+
+impl Track {
+    pub fn add_tag(&mut self, tag: &str) {
+        self.tags.push(tag.into());
+    }
+
+    /// Checks the file against the stamp we have for it. It's only hashed again when its size or
+    /// modification time moved.
+    fn restamp(&mut self, full_path: &Path) -> Option<Change> {
+        match &self.file {
+            Some(stamp) if stamp.is_current(full_path) => None,
+            Some(stamp) => {
+                let new = FileStamp::read(full_path).ok()?;
+                let changed = new.hash != stamp.hash;
+                self.file = Some(new);
+                changed.then_some(Change::Changed)
+            }
+            None => {
+                self.file = FileStamp::read(full_path).ok();
+                Some(Change::Stamped)
+            }
+        }
+    }
+
+    /// refreshes everything that comes from the file itself: its tags and album art
+    fn read_file(&mut self, library: &Path) {
+        let full_path = library.join(self.path.as_ref());
+        let mut cover = None;
+        match EmbeddedTags::read(&full_path) {
+            Ok(mut tags) => {
+                cover = tags.cover.take();
+                self.apply_tags(tags);
+            }
+            Err(e) => warn!("couldn't read tags of {}: {e}", self.path),
+        }
+
+        if !self.edited.contains(&TrackField::AlbumArt) {
+            self.album_art = album_art::find(library, &full_path, cover.as_ref())
+                .map(|path| path.to_string_lossy().into());
+        }
+    }
+
+    /// Fills in what the file's tags say, leaving alone anything in `edited` and anything the
+    /// file doesn't have a tag for. ReplayGain can't be edited, so it's always what the file
+    /// says now.
+    pub fn apply_tags(&mut self, tags: EmbeddedTags) {
+        let edited = self.edited.clone();
+        let keep = |field| !edited.contains(&field);
+
+        if let Some(title) = tags.title.filter(|_| keep(TrackField::Name)) {
+            self.name = title;
+        }
+        if let Some(artist) = tags.artist.filter(|_| keep(TrackField::Artist)) {
+            self.artist = Some(artist.into());
+        }
+        if let Some(album) = tags.album.filter(|_| keep(TrackField::Album)) {
+            self.album = Some(album.into());
+        }
+        if let Some(album_artist) = tags.album_artist.filter(|_| keep(TrackField::AlbumArtist)) {
+            self.album_artist = Some(album_artist.into());
+        }
+        if let Some(track_number) = tags.track_number.filter(|_| keep(TrackField::TrackNumber)) {
+            self.track_number = Some(track_number);
+        }
+        if let Some(disc_number) = tags.disc_number.filter(|_| keep(TrackField::DiscNumber)) {
+            self.disc_number = Some(disc_number);
+        }
+        if let Some(year) = tags.year.filter(|_| keep(TrackField::Year)) {
+            self.year = Some(year);
+        }
+        if let Some(genre) = tags.genre.filter(|_| keep(TrackField::Genre)) {
+            self.genre = Some(genre.into());
+        }
+        // only ever comes from the file, so tags that are gone from it are gone here too
+        self.replay_gain = tags.replay_gain;
+    }
+
+    /// Fills in what analysis found, leaving alone anything in `edited`. Tracks from a cue
+    /// sheet get their own part of the file's beat grid, and only get cue points where the
+    /// file's sound starts or stops inside them, so the gaps between them are left alone.
+    pub fn apply_analysis(&mut self, analysis: &Analysis) {
+        if let Some(key) = analysis
+            .key
+            .filter(|_| !self.edited.contains(&TrackField::Key))
+        {
+            self.key = Some(key);
+        }
+
+        let start = self.cue.as_ref().map_or(Duration::ZERO, |cue| cue.start);
+        let end = self
+            .cue
+            .as_ref()
+            .and_then(|cue| cue.end)
+            .unwrap_or(analysis.properties.duration);
+        let inside = |time: &Duration| start < *time && *time < end;
+        if !self.edited.contains(&TrackField::CueIn) {
+            self.cue_in = analysis
+                .sound
+                .map(|sound| sound.start)
+                .filter(inside)
+                .map(|time| time - start);
+        }
+        if !self.edited.contains(&TrackField::CueOut) {
+            self.cue_out = analysis
+                .sound
+                .map(|sound| sound.end)
+                .filter(inside)
+                .map(|time| time - start);
+        }
+
+        let Some(tempo) = analysis.tempo else {
+            return;
+        };
+        if !self.edited.contains(&TrackField::Bpm) {
+            self.bpm = Some(tempo.bpm);
+        }
+        if !self.edited.contains(&TrackField::Downbeat) {
+            self.downbeat = Some(tempo.downbeat_after(start));
+        }
+    }
+
+    /// the album it's on and who by, for telling albums of the same name apart
+    fn album_key(&self) -> Option<(Arc<str>, Option<Arc<str>>)> {
+        let album_artist = self.album_artist.as_ref().or(self.artist.as_ref());
+        Some((Arc::clone(self.album.as_ref()?), album_artist.cloned()))
+    }
+
+    /// Where in its file the track plays from and to, going by its cue sheet and cue points.
+    /// `None` plays to the end of the file.
+    pub fn playing_range(&self) -> (Duration, Option<Duration>) {
+        let start = self.cue.as_ref().map_or(Duration::ZERO, |cue| cue.start);
+        let end = match self.cue_out {
+            Some(cue_out) => Some(start.saturating_add(cue_out)),
+            None => self.cue.as_ref().and_then(|cue| cue.end),
+        };
+        (start.saturating_add(self.cue_in.unwrap_or_default()), end)
+    }
+}
+
+impl Hash for Track {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+        self.name.hash(state);
+        self.artist
+            .as_ref()
+            .map(|string| string.as_ref())
+            .hash(state);
+        self.album
+            .as_ref()
+            .map(|string| string.as_ref())
+            .hash(state);
+        self.album_artist
+            .as_ref()
+            .map(|string| string.as_ref())
+            .hash(state);
+        self.track_number.hash(state);
+        self.disc_number.hash(state);
+        self.year.hash(state);
+        self.genre
+            .as_ref()
+            .map(|string| string.as_ref())
+            .hash(state);
+        self.album_art.hash(state);
+        self.cue.hash(state);
+        self.bpm.map(f64::to_bits).hash(state);
+        self.downbeat.hash(state);
+        self.key.hash(state);
+        self.cue_in.hash(state);
+        self.cue_out.hash(state);
+        let replay_gain = self.replay_gain;
+        [
+            replay_gain.track_gain,
+            replay_gain.track_peak,
+            replay_gain.album_gain,
+            replay_gain.album_peak,
+        ]
+        .map(|value| value.map(f64::to_bits))
+        .hash(state);
+        let mut tags: Vec<&str> = self.tags.iter().map(|tag| tag.as_ref()).collect();
+        tags.sort_by_key(|t| t.to_lowercase());
+        tags.hash(state);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    name: Box<str>,
+    items: Vec<PlaylistItem>,
+    /// transitions that override the library's default, keyed by the index of the item they
+    /// come after
+    transitions: BTreeMap<usize, Transition>,
+
+    uuid: Uuid,
+}
+
+impl Playlist {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn items(&self) -> &[PlaylistItem] {
+        &self.items
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// the transition from `items[index]` into `items[index + 1]`, if it overrides the default
+    pub fn transition_after(&self, index: usize) -> Option<Transition> {
+        self.transitions.get(&index).copied()
+    }
+
+    pub fn set_transition_after(&mut self, index: usize, transition: Option<Transition>) {
+        if let Some(transition) = transition {
+            self.transitions.insert(index, transition);
+        } else {
+            self.transitions.remove(&index);
+        }
+    }
+}
+
+impl Playlist {
+    /// the list of items `at` points into, and the index into it
+    fn items_at(&mut self, at: &[usize]) -> Result<(&mut Vec<PlaylistItem>, usize)> {
+        let Some((&index, blocks)) = at.split_last() else {
+            bail!("no item given");
+        };
+        let mut items = &mut self.items;
+        for &block in blocks {
+            let Some(PlaylistItem::Block(inner)) = items.get_mut(block) else {
+                bail!("there's no block at {at:?}");
+            };
+            items = inner;
+        }
+        Ok((items, index))
+    }
+
+    /// Puts an item in at `at`, with `transition` after it if it's going in at the top.
+    fn insert(
+        &mut self,
+        at: &[usize],
+        item: PlaylistItem,
+        transition: Option<Transition>,
+    ) -> Result<()> {
+        let (items, index) = self.items_at(at)?;
+        if index > items.len() {
+            bail!("{at:?} is past the end");
+        }
+        items.insert(index, item);
+
+        if self.shift_transitions(at, |after| if after >= index { after + 1 } else { after }) {
+            self.set_transition_after(index, transition);
+        }
+        Ok(())
+    }
+
+    /// takes out the item at `at`, along with the transition after it
+    fn remove(&mut self, at: &[usize]) -> Result<(PlaylistItem, Option<Transition>)> {
+        let (items, index) = self.items_at(at)?;
+        if index >= items.len() {
+            bail!("there's no item at {at:?}");
+        }
+        let item = items.remove(index);
+
+        let mut transition = None;
+        if at.len() == 1 {
+            transition = self.transitions.remove(&index);
+        }
+        self.shift_transitions(at, |after| if after > index { after - 1 } else { after });
+        Ok((item, transition))
+    }
+
+    fn group(&mut self, at: &[usize]) -> Result<()> {
+        let (items, index) = self.items_at(at)?;
+        if index + 1 >= items.len() {
+            bail!("there's nothing after {at:?} to group it with");
+        }
+        let next = items.remove(index + 1);
+        let item = std::mem::replace(&mut items[index], PlaylistItem::Block(Vec::new()));
+        let block = match (item, next) {
+            (item, PlaylistItem::Block(mut block)) => {
+                block.insert(0, item);
+                block
+            }
+            (PlaylistItem::Block(mut block), next) => {
+                block.push(next);
+                block
+            }
+            (item, next) => vec![item, next],
+        };
+        items[index] = PlaylistItem::Block(block);
+
+        if at.len() == 1 {
+            self.transitions.remove(&index);
+        }
+        self.shift_transitions(at, |after| if after > index { after - 1 } else { after });
+        Ok(())
+    }
+
+    fn ungroup(&mut self, at: &[usize]) -> Result<()> {
+        let (items, index) = self.items_at(at)?;
+        let Some(PlaylistItem::Block(block)) = items.get_mut(index) else {
+            bail!("there's no block at {at:?}");
+        };
+        let block = std::mem::take(block);
+        let count = block.len();
+        items.splice(index..=index, block);
+
+        if count == 0 && at.len() == 1 {
+            self.transitions.remove(&index);
+        }
+        self.shift_transitions(at, |after| match after {
+            after if after < index => after,
+            after => (after + count).saturating_sub(1),
+        });
+        Ok(())
+    }
+
+    /// Re-keys the transitions after an item went in or came out at `at`, returning whether
+    /// that's a level that has transitions. Only the playlist's own items do: everything inside
+    /// a block plays gaplessly, so a path into one doesn't move any, and a transition that goes
+    /// into a block with its item is dropped.
+    fn shift_transitions(&mut self, at: &[usize], shift: impl Fn(usize) -> usize) -> bool {
+        if at.len() != 1 {
+            return false;
+        }
+        self.transitions = std::mem::take(&mut self.transitions)
+            .into_iter()
+            .map(|(after, transition)| (shift(after), transition))
+            .collect();
+        true
+    }
+
+    /// takes out every reference to `playlist`, in blocks too
+    fn remove_references(&mut self, playlist: &Arc<RwLock<Playlist>>) {
+        let refers = |item: &PlaylistItem| matches!(item, PlaylistItem::Playlist(weak) if std::ptr::eq(weak.as_ptr(), Arc::as_ptr(playlist)));
+        for index in (0..self.items.len()).rev() {
+            if refers(&self.items[index]) {
+                let _ = self.remove(&[index]);
+            }
+        }
+        fn retain(items: &mut Vec<PlaylistItem>, refers: &impl Fn(&PlaylistItem) -> bool) {
+            items.retain(|item| !refers(item));
+            for item in items {
+                if let PlaylistItem::Block(inner) = item {
+                    retain(inner, refers);
+                }
+            }
+        }
+        retain(&mut self.items, &refers);
+    }
+}
+
+impl Default for Playlist {
+    fn default() -> Self {
+        Self {
+            name: Default::default(),
+            items: Default::default(),
+            transitions: Default::default(),
+            uuid: Uuid::new_v4(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaylistItem {
+    Track(Arc<RwLock<Track>>),
+    Playlist(Weak<RwLock<Playlist>>),
+    /// An unbreakable segue group, like a medley or the movements of a piece. A block is one
+    /// unit: shuffling moves it as a whole and never reorders what's inside, its tracks always
+    /// play gaplessly into each other whatever the transition settings say, and skipping only
+    /// lands halfway into one if `BlockEntry::Anywhere` is set.
+    Block(Vec<PlaylistItem>),
+}
+
+/// Goes into a nested playlist with `walk`. `visited` holds the playlists being walked further
+/// up, a playlist that shows up inside itself is skipped instead of recursing forever.
+pub(crate) fn walk_playlist(
+    playlist: &Weak<RwLock<Playlist>>,
+    visited: &mut HashSet<Uuid>,
+    walk: impl FnOnce(&Playlist, &mut HashSet<Uuid>),
+) {
+    let Some(lock) = playlist.upgrade() else {
+        return;
+    };
+    let Ok(playlist) = lock.read() else {
+        return;
+    };
+    if !visited.insert(playlist.uuid) {
+        warn!("playlist {} contains itself, skipping it", playlist.name);
+        return;
+    }
+    walk(&playlist, visited);
+    visited.remove(&playlist.uuid);
+}
+
+impl PlaylistItem {
+    pub fn is_block(&self) -> bool {
+        matches!(self, PlaylistItem::Block(_))
+    }
+
+    /// every track this item plays, in order, with nested playlists expanded
+    pub fn tracks(&self) -> Vec<Arc<RwLock<Track>>> {
+        let mut tracks = Vec::new();
+        self.collect_tracks(&mut HashSet::new(), &mut tracks);
+        tracks
+    }
+
+    /// whether `playlist` is this item, or is somewhere inside it. `visited` is there for the
+    /// same reason as in `collect_tracks`
+    fn contains_playlist(
+        &self,
+        playlist: &Arc<RwLock<Playlist>>,
+        visited: &mut HashSet<Uuid>,
+    ) -> bool {
+        match self {
+            PlaylistItem::Track(_) => false,
+            PlaylistItem::Playlist(weak) => {
+                let Some(lock) = weak.upgrade() else {
+                    return false;
+                };
+                if Arc::ptr_eq(&lock, playlist) {
+                    return true;
+                }
+                let Ok(inner) = lock.read() else {
+                    return false;
+                };
+                if !visited.insert(inner.uuid) {
+                    return false;
+                }
+                inner
+                    .items
+                    .iter()
+                    .any(|item| item.contains_playlist(playlist, visited))
+            }
+            PlaylistItem::Block(items) => items
+                .iter()
+                .any(|item| item.contains_playlist(playlist, visited)),
+        }
+    }
+
+    /// `visited` is there for the same reason as in `walk_playlist`
+    pub(crate) fn collect_tracks(
+        &self,
+        visited: &mut HashSet<Uuid>,
+        tracks: &mut Vec<Arc<RwLock<Track>>>,
+    ) {
+        match self {
+            PlaylistItem::Track(track) => tracks.push(Arc::clone(track)),
+            PlaylistItem::Playlist(weak) => walk_playlist(weak, visited, |playlist, visited| {
+                for item in playlist.items.iter() {
+                    item.collect_tracks(visited, tracks);
+                }
+            }),
+            PlaylistItem::Block(items) => {
+                for item in items {
+                    item.collect_tracks(visited, tracks);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::mpsc, thread};
+
+    use super::*;
+    use crate::{
+        playback::FadeCurve,
+        test_util::{playlist_with, sine, temp_dir, track_at, wav_library, write_flac, write_wav},
+    };
+
+    fn paths(library: &MusicLibrary) -> Vec<(String, bool)> {
+        let mut paths: Vec<_> = library
+            .get_tracks()
+            .iter()
+            .map(|track| {
+                let track = track.read().unwrap();
+                (track.path.to_string(), track.missing)
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn only_saves_when_asked() {
+        let dir = temp_dir("explicit-save");
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        library.create_playlist("saved");
+        library.save().unwrap();
+        library.create_playlist("unsaved");
+        drop(library);
+
+        let library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        let playlists = library.playlists();
+        assert_eq!(playlists.len(), 1);
+        assert_eq!(playlists[0].read().unwrap().name(), "saved");
+    }
+
+    #[test]
+    fn scans_only_what_changed() {
+        let dir = temp_dir("scan-changed");
+        write_wav(&dir.join("a.wav"), 44_100, 1, &[0; 64]);
+        write_wav(&dir.join("b.wav"), 44_100, 1, &[1; 64]);
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+
+        // b is renamed and c added, but only the rename is passed on
+        fs::rename(dir.join("b.wav"), dir.join("moved.wav")).unwrap();
+        write_wav(&dir.join("c.wav"), 44_100, 1, &[2; 64]);
+        let changed = vec![dir.join("b.wav"), dir.join("moved.wav")];
+        let scan = library.scanner(Some(changed)).run().unwrap();
+        let summary = library.apply_scan(scan);
+        assert_eq!(summary.moved, 1);
+        assert_eq!(summary.added, 0);
+        assert_eq!(
+            paths(&library),
+            [("a.wav".into(), false), ("moved.wav".into(), false)]
+        );
+
+        // a folder that's moved in is gone through
+        fs::create_dir(dir.join("album")).unwrap();
+        write_wav(&dir.join("album/d.wav"), 44_100, 1, &[3; 64]);
+        fs::remove_file(dir.join("a.wav")).unwrap();
+        let changed = vec![dir.join("album"), dir.join("a.wav")];
+        let scan = library.scanner(Some(changed)).run().unwrap();
+        let summary = library.apply_scan(scan);
+        assert_eq!((summary.added, summary.removed), (1, 1));
+        assert_eq!(
+            paths(&library),
+            [
+                ("a.wav".into(), true),
+                ("album/d.wav".into(), false),
+                ("moved.wav".into(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn hidden_files_are_skipped() {
+        let dir = temp_dir("scan-hidden");
+        write_wav(&dir.join("a.wav"), 44_100, 1, &[0; 64]);
+        // what's left of a tag write that didn't finish, and a hidden folder
+        write_wav(&dir.join(".a.wav.tmp"), 44_100, 1, &[1; 64]);
+        fs::create_dir(dir.join(".trash")).unwrap();
+        write_wav(&dir.join(".trash/b.wav"), 44_100, 1, &[2; 64]);
+        // nothing in the album art cache is looked at either
+        fs::create_dir(dir.join(album_art::ART_CACHE)).unwrap();
+        write_wav(&dir.join("album_art/c.wav"), 44_100, 1, &[3; 64]);
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        assert_eq!(paths(&library), [("a.wav".into(), false)]);
+
+        let changed = vec![
+            dir.join(".a.wav.tmp"),
+            dir.join(".trash"),
+            dir.join(".trash/b.wav"),
+            PathBuf::from(".trash/b.wav"),
+            dir.join("album_art/c.wav"),
+        ];
+        let scan = library.scanner(Some(changed)).run().unwrap();
+        assert_eq!(library.apply_scan(scan).added, 0);
+        assert_eq!(paths(&library), [("a.wav".into(), false)]);
+    }
+
+    /// a library of tracks named a to e
+    fn five_tracks(test: &str) -> MusicLibrary {
+        wav_library(test, &["a.wav", "b.wav", "c.wav", "d.wav", "e.wav"])
+    }
+
+    fn track(library: &MusicLibrary, name: &str) -> PlaylistItem {
+        PlaylistItem::Track(track_at(library, &format!("{name}.wav")))
+    }
+
+    /// a crossfade `seconds` long
+    fn fade(seconds: u64) -> Option<Transition> {
+        Some(Transition::Crossfade {
+            duration: Duration::from_secs(seconds),
+            curve: FadeCurve::Linear,
+        })
+    }
+
+    /// the transition after each item
+    fn transitions(playlist: &Arc<RwLock<Playlist>>) -> Vec<Option<Transition>> {
+        let playlist = playlist.read().unwrap();
+        (0..playlist.items.len())
+            .map(|i| playlist.transition_after(i))
+            .collect()
+    }
+
+    /// What every playlist operation has to leave true: uuids are unique, every item is one of
+    /// the library's tracks or a live reference to one of its playlists, and transitions only
+    /// come after items that are there.
+    fn check(library: &MusicLibrary) {
+        fn check_items(library: &MusicLibrary, items: &[PlaylistItem]) {
+            for item in items {
+                match item {
+                    PlaylistItem::Track(track) => {
+                        assert!(library.tracks.iter().any(|known| Arc::ptr_eq(known, track)));
+                    }
+                    PlaylistItem::Playlist(weak) => {
+                        let playlist = weak.upgrade().expect("reference to a deleted playlist");
+                        let known = library.playlist(playlist.read().unwrap().uuid);
+                        assert!(known.is_some_and(|known| Arc::ptr_eq(&known, &playlist)));
+                    }
+                    PlaylistItem::Block(items) => check_items(library, items),
+                }
+            }
+        }
+
+        let mut uuids = HashSet::new();
+        for lock in library.playlists() {
+            let playlist = lock.read().unwrap();
+            assert!(
+                uuids.insert(playlist.uuid),
+                "{} isn't unique",
+                playlist.uuid
+            );
+            let last = playlist.items.len();
+            assert!(playlist.transitions.keys().all(|after| *after < last));
+            check_items(library, &playlist.items);
+        }
+    }
+
+    /// tracks by name, playlists in brackets and blocks in parentheses
+    fn describe(playlist: &Arc<RwLock<Playlist>>) -> String {
+        fn names(items: &[PlaylistItem]) -> String {
+            let names: Vec<String> = items
+                .iter()
+                .map(|item| match item {
+                    PlaylistItem::Track(track) => {
+                        track.read().unwrap().name.trim_end_matches(".wav").into()
+                    }
+                    PlaylistItem::Playlist(weak) => {
+                        format!("[{}]", weak.upgrade().unwrap().read().unwrap().name)
+                    }
+                    PlaylistItem::Block(inner) => format!("({})", names(inner)),
+                })
+                .collect();
+            names.join(" ")
+        }
+        names(&playlist.read().unwrap().items)
+    }
+
+    #[test]
+    fn playlist_operations_keep_references_whole() {
+        let mut library = five_tracks("playlist-operations");
+        let items = [
+            track(&library, "a"),
+            track(&library, "b"),
+            PlaylistItem::Block(vec![track(&library, "c")]),
+        ];
+        let set = playlist_with(&mut library, "set", items);
+        let items = [track(&library, "e")];
+        let other = playlist_with(&mut library, "other", items);
+        let set_uuid = set.read().unwrap().uuid;
+        let other_uuid = other.read().unwrap().uuid;
+        check(&library);
+
+        let nested = PlaylistItem::Playlist(Arc::downgrade(&other));
+        library.insert_item(set_uuid, &[3], nested).unwrap();
+        library
+            .insert_item(set_uuid, &[2, 1], track(&library, "d"))
+            .unwrap();
+        assert_eq!(describe(&set), "a b (c d) [other]");
+        check(&library);
+
+        // nothing that would break the references goes in
+        let itself = PlaylistItem::Playlist(Arc::downgrade(&set));
+        assert!(library.insert_item(other_uuid, &[1], itself).is_err());
+        let stranger = PlaylistItem::Track(Arc::new(RwLock::new(Track::default())));
+        assert!(library.insert_item(set_uuid, &[0], stranger).is_err());
+        assert!(
+            library
+                .insert_item(set_uuid, &[9, 0], track(&library, "a"))
+                .is_err()
+        );
+        assert_eq!(describe(&set), "a b (c d) [other]");
+        check(&library);
+
+        library.rename_playlist(other_uuid, "closing").unwrap();
+        assert_eq!(other.read().unwrap().uuid, other_uuid);
+        assert_eq!(describe(&set), "a b (c d) [closing]");
+        check(&library);
+
+        // the copy shares its tracks and nested playlists with the original
+        let copy = library.duplicate_playlist(set_uuid).unwrap();
+        let copy_uuid = copy.read().unwrap().uuid;
+        assert_ne!(copy_uuid, set_uuid);
+        assert!(Arc::ptr_eq(&library.playlists()[1], &copy));
+        assert_eq!(describe(&copy), "a b (c d) [closing]");
+        let nested = match &copy.read().unwrap().items[3] {
+            PlaylistItem::Playlist(weak) => weak.upgrade().unwrap(),
+            item => panic!("{item:?}"),
+        };
+        assert!(Arc::ptr_eq(&nested, &other));
+        check(&library);
+
+        let PlaylistItem::Track(removed) = library.remove_item(set_uuid, &[2, 0]).unwrap() else {
+            panic!("removed something other than c");
+        };
+        assert_eq!(&*removed.read().unwrap().name, "c.wav");
+        assert_eq!(describe(&set), "a b (d) [closing]");
+        assert_eq!(describe(&copy), "a b (c d) [closing]");
+        check(&library);
+
+        library.move_item(set_uuid, &[0], &[1, 1]).unwrap();
+        assert_eq!(describe(&set), "b (d a) [closing]");
+        // a move that fails leaves things as they were
+        assert!(library.move_item(set_uuid, &[0], &[5]).is_err());
+        assert_eq!(describe(&set), "b (d a) [closing]");
+        check(&library);
+
+        // deleting takes it out of every playlist it was in, the copy too
+        library.delete_playlist(other_uuid).unwrap();
+        assert!(library.playlist(other_uuid).is_none());
+        assert_eq!(describe(&set), "b (d a)");
+        assert_eq!(describe(&copy), "a b (c d)");
+        check(&library);
+    }
+
+    #[test]
+    fn transitions_follow_their_items() {
+        let mut library = five_tracks("playlist-transitions");
+        let items = ["a", "b", "c", "d"].map(|name| track(&library, name));
+        let set = playlist_with(&mut library, "set", items);
+        let uuid = set.read().unwrap().uuid;
+        for (after, seconds) in [(0, 1), (1, 2), (2, 3)] {
+            set.write()
+                .unwrap()
+                .set_transition_after(after, fade(seconds));
+        }
+
+        library.move_item(uuid, &[0], &[3]).unwrap();
+        assert_eq!(describe(&set), "b c d a");
+        assert_eq!(transitions(&set), [fade(2), fade(3), None, fade(1)]);
+        check(&library);
+
+        let block = PlaylistItem::Block(vec![track(&library, "e")]);
+        library.insert_item(uuid, &[1], block).unwrap();
+        assert_eq!(describe(&set), "b (e) c d a");
+        assert_eq!(transitions(&set), [fade(2), None, fade(3), None, fade(1)]);
+        check(&library);
+
+        // moving around inside the block leaves the ones outside it alone
+        library.move_item(uuid, &[4], &[1, 1]).unwrap();
+        library.move_item(uuid, &[1, 1], &[1, 0]).unwrap();
+        assert_eq!(describe(&set), "b (a e) c d");
+        assert_eq!(transitions(&set), [fade(2), None, fade(3), None]);
+        check(&library);
+
+        // what's in a block plays gaplessly, so the fade after a didn't go in with it
+        library.move_item(uuid, &[1, 0], &[4]).unwrap();
+        assert_eq!(describe(&set), "b (e) c d a");
+        assert_eq!(transitions(&set), [fade(2), None, fade(3), None, None]);
+        check(&library);
+
+        library.remove_item(uuid, &[1]).unwrap();
+        library.remove_item(uuid, &[2]).unwrap();
+        assert_eq!(describe(&set), "b c a");
+        assert_eq!(transitions(&set), [fade(2), fade(3), None]);
+        check(&library);
+    }
+
+    #[test]
+    fn grouping_carries_transitions() {
+        let mut library = five_tracks("playlist-grouping");
+        let items = ["a", "b", "c", "d"].map(|name| track(&library, name));
+        let set = playlist_with(&mut library, "set", items);
+        let uuid = set.read().unwrap().uuid;
+        for (after, seconds) in [(0, 1), (1, 2), (2, 3)] {
+            set.write()
+                .unwrap()
+                .set_transition_after(after, fade(seconds));
+        }
+
+        library.group(uuid, &[1]).unwrap();
+        assert_eq!(describe(&set), "a (b c) d");
+        assert_eq!(transitions(&set), [fade(1), fade(3), None]);
+        library.group(uuid, &[1]).unwrap();
+        assert_eq!(describe(&set), "a (b c d)");
+        assert_eq!(transitions(&set), [fade(1), None]);
+        check(&library);
+
+        // nothing changes when it can't be done
+        assert!(library.group(uuid, &[1]).is_err());
+        assert!(library.ungroup(uuid, &[0]).is_err());
+        assert_eq!(describe(&set), "a (b c d)");
+        assert_eq!(transitions(&set), [fade(1), None]);
+
+        set.write().unwrap().set_transition_after(1, fade(4));
+        library.ungroup(uuid, &[1]).unwrap();
+        assert_eq!(describe(&set), "a b c d");
+        assert_eq!(transitions(&set), [fade(1), None, None, fade(4)]);
+        check(&library);
+
+        library.group(uuid, &[1]).unwrap();
+        library.group(uuid, &[0]).unwrap();
+        assert_eq!(describe(&set), "(a b c) d");
+        assert_eq!(transitions(&set), [None, fade(4)]);
+        library.ungroup(uuid, &[0]).unwrap();
+        assert_eq!(describe(&set), "a b c d");
+        assert_eq!(transitions(&set), [None, None, None, fade(4)]);
+        check(&library);
+    }
+
+    #[test]
+    fn cue_sheets_retire_whole_file_tracks() {
+        let names = |library: &MusicLibrary| {
+            let mut names: Vec<(String, bool)> = library
+                .get_tracks()
+                .iter()
+                .map(|track| {
+                    let track = track.read().unwrap();
+                    (track.name.to_string(), track.missing)
+                })
+                .collect();
+            names.sort();
+            names
+        };
+
+        let dir = temp_dir("cue-retire");
+        write_wav(&dir.join("album.wav"), 44_100, 1, &vec![0; 88_200]);
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        assert_eq!(names(&library), [("album.wav".into(), false)]);
+
+        // the watcher only sees the sheet show up
+        let sheet = [
+            "FILE \"album.wav\" WAVE",
+            "  TRACK 01 AUDIO",
+            "    TITLE \"One\"",
+            "    INDEX 01 00:00:00",
+            "  TRACK 02 AUDIO",
+            "    TITLE \"Two\"",
+            "    INDEX 01 00:01:00",
+        ];
+        fs::write(dir.join("album.cue"), sheet.join("\n")).unwrap();
+        let scan = library.scanner(Some(vec![dir.join("album.cue")])).run();
+        let summary = library.apply_scan(scan.unwrap());
+        assert_eq!((summary.added, summary.removed), (2, 1));
+        assert_eq!(
+            names(&library),
+            [
+                ("One".into(), false),
+                ("Two".into(), false),
+                ("album.wav".into(), true)
+            ]
+        );
+
+        // and the file's a track of its own again once the sheet's gone
+        fs::remove_file(dir.join("album.cue")).unwrap();
+        let scan = library.scanner(Some(vec![dir.join("album.cue")])).run();
+        let summary = library.apply_scan(scan.unwrap());
+        assert_eq!((summary.restored, summary.removed), (1, 2));
+        assert_eq!(
+            names(&library),
+            [
+                ("One".into(), true),
+                ("Two".into(), true),
+                ("album.wav".into(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_gain_follows_the_tags() {
+        let dir = temp_dir("replay-gain-tags");
+        write_flac(
+            &dir.join("a.flac"),
+            &[
+                b"REPLAYGAIN_TRACK_GAIN=-3.50 dB",
+                b"REPLAYGAIN_ALBUM_GAIN=-4.00 dB",
+            ],
+        );
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        let track = track_at(&library, "a.flac");
+        let replay_gain = track.read().unwrap().replay_gain;
+        assert_eq!(replay_gain.track_gain, Some(-3.5));
+        assert_eq!(replay_gain.album_gain, Some(-4.0));
+
+        // the album gain is taken out of the file
+        write_flac(&dir.join("a.flac"), &[b"REPLAYGAIN_TRACK_GAIN=-2.00 dB"]);
+        library.rescan().unwrap();
+        let replay_gain = track.read().unwrap().replay_gain;
+        assert_eq!(replay_gain.track_gain, Some(-2.0));
+        assert_eq!(replay_gain.album_gain, None);
+    }
+
+    #[test]
+    fn album_loudness_comes_from_its_tracks() {
+        let dir = temp_dir("album-loudness");
+        let loud = sine(44_100, 3.0, 440.0);
+        let quiet: Vec<i16> = loud.iter().map(|sample| sample / 4).collect();
+        write_wav(&dir.join("a.wav"), 44_100, 2, &loud);
+        write_wav(&dir.join("b.wav"), 44_100, 2, &quiet);
+        let library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        let (a, b) = (track_at(&library, "a.wav"), track_at(&library, "b.wav"));
+        for (lock, album) in [(&a, "album"), (&b, "album")] {
+            let mut track = lock.write().unwrap();
+            track.album = Some(album.into());
+            let analysis = analysis::analyze(&dir.join(track.path.as_ref())).unwrap();
+            let hash = track.file.as_ref().unwrap().hash.clone();
+            library
+                .analysis
+                .write()
+                .unwrap()
+                .results
+                .insert(hash, analysis);
+        }
+        // everything's analysed already, so the albums are worked out straight away
+        library.analyze(|| ());
+
+        let loud = library.loudness(&a.read().unwrap()).unwrap();
+        let quiet = library.loudness(&b.read().unwrap()).unwrap();
+        let album = library.album_loudness(&a.read().unwrap()).unwrap();
+        assert_eq!(library.album_loudness(&b.read().unwrap()), Some(album));
+        assert!(quiet.integrated < album.integrated && album.integrated < loud.integrated);
+        assert_eq!(album.true_peak, loud.true_peak);
+        assert!(album.range >= loud.integrated - quiet.integrated);
+
+        // b moves to another album
+        b.write().unwrap().album = Some("other".into());
+        library.analyze(|| ());
+        assert_eq!(library.album_loudness(&a.read().unwrap()), Some(loud));
+    }
+
+    /// analyses the library and waits until `files` files are done
+    fn analyze_all(library: &MusicLibrary, files: usize) {
+        let (done, finished) = mpsc::channel();
+        library.analyze(move || done.send(()).unwrap());
+        for _ in 0..files {
+            finished.recv_timeout(Duration::from_secs(60)).unwrap();
+        }
+        // the last file is reported before it's out of the queue
+        while !library.analysis.read().unwrap().queued.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn analysis_fills_in_the_properties() {
+        let dir = temp_dir("analysis-properties");
+        write_wav(&dir.join("a.wav"), 48_000, 2, &sine(48_000, 1.5, 440.0));
+        let library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        let a = track_at(&library, "a.wav");
+        assert_eq!(library.properties(&a.read().unwrap()), None);
+
+        analyze_all(&library, 1);
+        let properties = library.properties(&a.read().unwrap()).unwrap();
+        assert_eq!(properties.duration, Duration::from_millis(1500));
+        assert_eq!(properties.sample_rate, 48_000);
+        assert_eq!(properties.channels, 2);
+        assert_eq!(properties.bit_depth, Some(16));
+    }
+
+    #[test]
+    fn failed_analysis_is_tried_again() {
+        let dir = temp_dir("analysis-failure");
+        fs::write(dir.join("broken.wav"), b"RIFF\0\0\0\0WAVE").unwrap();
+        let library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        let broken = track_at(&library, "broken.wav");
+
+        analyze_all(&library, 1);
+        assert_eq!(library.analysis(&broken.read().unwrap()), None);
+        // it's not left queued, so it's picked up again
+        analyze_all(&library, 1);
+    }
 }
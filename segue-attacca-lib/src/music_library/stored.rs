@@ -0,0 +1,267 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{MusicLibrary, Playlist, PlaylistItem, Track};
+use crate::{
+    analysis::{Analysis, AnalysisCache},
+    migrations::FORMAT_VERSION,
+    playback::PlaybackSettings,
+    playback::Transition,
+};
+
+/// A library the way it's saved. Playlists point at their tracks and at other playlists by
+/// uuid, and get turned back into shared pointers when it's loaded.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredLibrary {
+    pub version: u64,
+    pub path: Box<str>,
+    pub tracks: Vec<Arc<RwLock<Track>>>,
+    pub playlists: Vec<StoredPlaylist>,
+    pub tags: Vec<Arc<str>>,
+    #[serde(default)]
+    pub playback: PlaybackSettings,
+    /// by file hash
+    #[serde(default)]
+    pub analysis: BTreeMap<Box<str>, Analysis>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredPlaylist {
+    pub name: Box<str>,
+    pub items: Vec<StoredItem>,
+    #[serde(default)]
+    pub transitions: BTreeMap<usize, Transition>,
+    pub uuid: Uuid,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum StoredItem {
+    Track(Uuid),
+    Playlist(Uuid),
+    Block(Vec<StoredItem>),
+}
+
+impl From<&MusicLibrary> for StoredLibrary {
+    fn from(library: &MusicLibrary) -> Self {
+        let playlists = library
+            .playlists
+            .iter()
+            .filter_map(|lock| {
+                let playlist = lock.read().ok()?;
+                let (items, transitions) =
+                    keep_items(&playlist.items, &playlist.transitions, store_item);
+                Some(StoredPlaylist {
+                    name: playlist.name.clone(),
+                    items,
+                    transitions,
+                    uuid: playlist.uuid,
+                })
+            })
+            .collect();
+
+        // only what the tracks still need, files that changed get new hashes
+        let hashes: HashSet<Box<str>> = library
+            .tracks
+            .iter()
+            .filter_map(|lock| Some(lock.read().ok()?.file.as_ref()?.hash.clone()))
+            .collect();
+        let analysis = library
+            .analysis
+            .read()
+            .map(|cache| {
+                cache
+                    .results
+                    .iter()
+                    .filter(|(hash, _)| hashes.contains(*hash))
+                    .map(|(hash, analysis)| (hash.clone(), analysis.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            version: FORMAT_VERSION,
+            path: library.path.clone(),
+            tracks: library.tracks.clone(),
+            playlists,
+            tags: library.tags.clone(),
+            playback: library.playback,
+            analysis,
+        }
+    }
+}
+
+/// Converts a playlist's items, leaving out the ones that don't convert. Transitions are keyed
+/// by index, so they're moved down along with the items after a dropped one, and the one after
+/// a dropped item goes with it.
+fn keep_items<T, U>(
+    items: &[T],
+    transitions: &BTreeMap<usize, Transition>,
+    mut convert: impl FnMut(&T) -> Option<U>,
+) -> (Vec<U>, BTreeMap<usize, Transition>) {
+    let mut kept = Vec::with_capacity(items.len());
+    let mut kept_transitions = BTreeMap::new();
+    for (i, item) in items.iter().enumerate() {
+        let Some(item) = convert(item) else {
+            continue;
+        };
+        if let Some(transition) = transitions.get(&i) {
+            kept_transitions.insert(kept.len(), *transition);
+        }
+        kept.push(item);
+    }
+    (kept, kept_transitions)
+}
+
+fn store_item(item: &PlaylistItem) -> Option<StoredItem> {
+    Some(match item {
+        PlaylistItem::Track(track) => StoredItem::Track(track.read().ok()?.uuid),
+        PlaylistItem::Playlist(weak) => StoredItem::Playlist(weak.upgrade()?.read().ok()?.uuid),
+        PlaylistItem::Block(items) => {
+            StoredItem::Block(items.iter().filter_map(store_item).collect())
+        }
+    })
+}
+
+impl From<StoredLibrary> for MusicLibrary {
+    fn from(stored: StoredLibrary) -> Self {
+        let tracks: HashMap<Uuid, Arc<RwLock<Track>>> = stored
+            .tracks
+            .iter()
+            .filter_map(|lock| Some((lock.read().ok()?.uuid, Arc::clone(lock))))
+            .collect();
+
+        // every playlist has to exist before any of them can point at another
+        let playlists: Vec<(Arc<RwLock<Playlist>>, StoredPlaylist)> = stored
+            .playlists
+            .into_iter()
+            .map(|playlist| {
+                let lock = Arc::new(RwLock::new(Playlist {
+                    name: playlist.name.clone(),
+                    items: Vec::new(),
+                    transitions: BTreeMap::new(),
+                    uuid: playlist.uuid,
+                }));
+                (lock, playlist)
+            })
+            .collect();
+        let by_uuid: HashMap<Uuid, Arc<RwLock<Playlist>>> = playlists
+            .iter()
+            .filter_map(|(lock, _)| Some((lock.read().ok()?.uuid, Arc::clone(lock))))
+            .collect();
+        for (lock, stored) in &playlists {
+            if let Ok(mut playlist) = lock.write() {
+                (playlist.items, playlist.transitions) =
+                    keep_items(&stored.items, &stored.transitions, |item| {
+                        resolve_item(item, &tracks, &by_uuid)
+                    });
+            }
+        }
+
+        Self {
+            version: stored.version,
+            path: stored.path,
+            tracks: stored.tracks,
+            playlists: playlists.into_iter().map(|(lock, _)| lock).collect(),
+            artists: Vec::new(),
+            tags: stored.tags,
+            playback: stored.playback,
+            analysis: Arc::new(RwLock::new(AnalysisCache {
+                results: stored.analysis.into_iter().collect(),
+                ..Default::default()
+            })),
+            storage: None,
+            load_error: None,
+        }
+    }
+}
+
+fn resolve_item(
+    item: &StoredItem,
+    tracks: &HashMap<Uuid, Arc<RwLock<Track>>>,
+    playlists: &HashMap<Uuid, Arc<RwLock<Playlist>>>,
+) -> Option<PlaylistItem> {
+    match item {
+        StoredItem::Track(uuid) => {
+            let track = tracks.get(uuid).map(Arc::clone);
+            if track.is_none() {
+                warn!("playlist has a track {uuid} that isn't in the library, dropping it");
+            }
+            track.map(PlaylistItem::Track)
+        }
+        StoredItem::Playlist(uuid) => {
+            let playlist = playlists.get(uuid).map(Arc::downgrade);
+            if playlist.is_none() {
+                warn!("playlist has a playlist {uuid} that isn't in the library, dropping it");
+            }
+            playlist.map(PlaylistItem::Playlist)
+        }
+        StoredItem::Block(items) => Some(PlaylistItem::Block(
+            items
+                .iter()
+                .filter_map(|item| resolve_item(item, tracks, playlists))
+                .collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::playback::FadeCurve;
+
+    fn fade(seconds: u64) -> Transition {
+        Transition::Crossfade {
+            duration: Duration::from_secs(seconds),
+            curve: FadeCurve::Linear,
+        }
+    }
+
+    #[test]
+    fn transitions_stay_with_their_items() {
+        let track = |name: &str| {
+            Arc::new(RwLock::new(Track {
+                path: name.into(),
+                name: name.into(),
+                ..Default::default()
+            }))
+        };
+        let (a, b) = (track("a.wav"), track("b.wav"));
+        let uuid = |track: &Arc<RwLock<Track>>| track.read().unwrap().uuid;
+        let stored = StoredLibrary {
+            version: FORMAT_VERSION,
+            path: "".into(),
+            tracks: vec![Arc::clone(&a), Arc::clone(&b)],
+            playlists: vec![StoredPlaylist {
+                name: "set".into(),
+                items: vec![
+                    StoredItem::Track(uuid(&a)),
+                    StoredItem::Track(Uuid::new_v4()),
+                    StoredItem::Track(uuid(&b)),
+                    StoredItem::Playlist(Uuid::new_v4()),
+                ],
+                transitions: BTreeMap::from([(0, fade(1)), (1, fade(2)), (2, fade(3))]),
+                uuid: Uuid::new_v4(),
+            }],
+            tags: Vec::new(),
+            playback: PlaybackSettings::default(),
+            analysis: BTreeMap::new(),
+        };
+
+        // the missing track and playlist are dropped, and the fade after b moves down with it
+        let library = MusicLibrary::from(stored);
+        let playlist = library.playlists()[0].read().unwrap();
+        assert_eq!(playlist.items().len(), 2);
+        assert_eq!(
+            playlist.transitions,
+            BTreeMap::from([(0, fade(1)), (1, fade(3))])
+        );
+    }
+}
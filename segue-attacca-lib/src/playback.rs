@@ -1 +1,1258 @@
-pub struct PlaybackEngine {}
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{Result, eyre::bail};
+use rodio::{Decoder, OutputStream, Source, source::UniformSourceIterator};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    analysis::{REFERENCE_LUFS, ReplayGain},
+    formats,
+    music_library::{MusicLibrary, Track},
+};
+
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const CHANNELS: u16 = 2;
+
+/// number of samples the deck plays between looking at its mailbox
+const CONTROL_PERIOD: usize = 512;
+const TICK: Duration = Duration::from_millis(20);
+/// where the logarithmic curve bottoms out
+const FADE_FLOOR_DB: f32 = -60.0;
+/// what the limiter keeps normalized items under, in dBFS, leaving room for the peaks between
+/// samples
+const LIMITER_CEILING_DB: f32 = -1.0;
+/// how long the limiter takes to let go after a peak
+const LIMITER_RELEASE: Duration = Duration::from_millis(100);
+
+type Decoded = Box<dyn Source<Item = f32> + Send>;
+
+pub enum PlaybackMessage {
+    Play(PlaybackItem),
+    /// replaces the queue, playing the first item right away. Items with the same `block` are
+    /// kept together as a block.
+    PlayAll(Vec<PlaybackItem>),
+    /// adds an item after the last queued one, it starts on the first sample after the previous
+    /// item ends
+    Enqueue(PlaybackItem),
+    /// enqueues items that always play through, one straight into the next
+    EnqueueBlock(Vec<PlaybackItem>),
+    ClearQueue,
+    Next,
+    SetSettings(PlaybackSettings),
+    Pause,
+    Resume,
+    Stop,
+    Seek(Duration),
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackItem {
+    pub path: PathBuf,
+    pub name: Box<str>,
+    /// how to go into this item from the one before it, the engine's default if `None`
+    pub transition: Option<Transition>,
+    /// items sharing a block are played gaplessly and, depending on `BlockEntry`, can't be
+    /// skipped into halfway through. The engine hands these out in `EnqueueBlock`.
+    pub block: Option<u64>,
+    /// the id of the `QueueEntry` it was built from
+    pub queue_entry: Option<u64>,
+    /// where in the file the item starts and ends, for tracks that share a file or skip
+    /// silence. Positions and seeking are relative to `start`.
+    pub start: Duration,
+    pub end: Option<Duration>,
+    /// for normalizing it, see `Normalization`
+    pub replay_gain: ReplayGain,
+}
+
+impl PlaybackItem {
+    pub fn new(library: &MusicLibrary, track: &Track) -> Self {
+        let (start, end) = track.playing_range();
+        Self {
+            path: Path::new(library.path.as_ref()).join(track.path.as_ref()),
+            name: track.name.clone(),
+            transition: None,
+            block: None,
+            queue_entry: None,
+            start,
+            end,
+            replay_gain: library.replay_gain(track),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transition {
+    /// the next track starts on the sample after the last one ends
+    #[default]
+    Gapless,
+    Crossfade {
+        duration: Duration,
+        curve: FadeCurve,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    /// keeps the summed power constant, so uncorrelated material doesn't dip in the middle
+    EqualPower,
+    /// linear in decibels, down to `FADE_FLOOR_DB`
+    Logarithmic,
+}
+
+impl FadeCurve {
+    /// gains for the outgoing and incoming tracks, `progress` goes from 0 to 1 over the fade
+    pub fn gains(self, progress: f32) -> (f32, f32) {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => (1.0 - progress, progress),
+            FadeCurve::EqualPower => {
+                let angle = progress * std::f32::consts::FRAC_PI_2;
+                (angle.cos().max(0.0), angle.sin())
+            }
+            FadeCurve::Logarithmic => {
+                fn gain(amount: f32) -> f32 {
+                    if amount <= 0.0 {
+                        0.0
+                    } else {
+                        10f32.powf(FADE_FLOOR_DB * (1.0 - amount) / 20.0)
+                    }
+                }
+                (gain(1.0 - progress), gain(progress))
+            }
+        }
+    }
+}
+
+/// what skipping does when it would land in the middle of a block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockEntry {
+    /// blocks are only ever entered from their first track, skipping inside one skips past it
+    #[default]
+    Start,
+    /// skipping moves track by track, even inside a block
+    Anywhere,
+}
+
+/// which ReplayGain value normalizing goes by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GainMode {
+    Off,
+    #[default]
+    Track,
+    /// keeps the differences between tracks on the same album, tracks without an album gain
+    /// fall back to their track gain
+    Album,
+}
+
+/// How items are brought to the same loudness. Items that haven't been analysed and have no
+/// ReplayGain tags are played as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Normalization {
+    pub mode: GainMode,
+    /// in LUFS
+    pub target: f64,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            mode: GainMode::default(),
+            target: REFERENCE_LUFS,
+        }
+    }
+}
+
+impl Normalization {
+    /// the linear gain to play an item with and the peak that goes with it, `None` when it's
+    /// left alone
+    fn gain(&self, replay_gain: &ReplayGain) -> Option<(f32, Option<f32>)> {
+        let (gain, peak) = match self.mode {
+            GainMode::Off => return None,
+            GainMode::Track => (replay_gain.track_gain, replay_gain.track_peak),
+            GainMode::Album if replay_gain.album_gain.is_some() => {
+                (replay_gain.album_gain, replay_gain.album_peak)
+            }
+            GainMode::Album => (replay_gain.track_gain, replay_gain.track_peak),
+        };
+        let decibels = gain? + self.target - REFERENCE_LUFS;
+        Some((
+            10f32.powf(decibels as f32 / 20.0),
+            peak.map(|peak| peak as f32),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackSettings {
+    /// used for items that don't bring their own
+    pub transition: Transition,
+    #[serde(default)]
+    pub block_entry: BlockEntry,
+    #[serde(default)]
+    pub normalization: Normalization,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlaybackState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlaybackStatus {
+    pub state: PlaybackState,
+    pub item: Option<PlaybackItem>,
+    pub position: Duration,
+    pub queue: Vec<PlaybackItem>,
+    /// goes up whenever a source starts playing, whether it's the next item or a seek
+    pub generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlaybackOutput {
+    #[default]
+    Device,
+    /// samples are pulled at real time speed and thrown away, for machines without a sound card
+    Null,
+}
+
+pub struct PlaybackEngine {
+    tx: Sender<PlaybackMessage>,
+    status: Arc<RwLock<PlaybackStatus>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PlaybackEngine {
+    pub fn new(output: PlaybackOutput) -> Self {
+        let (tx, rx) = channel();
+        let status = Arc::new(RwLock::new(PlaybackStatus::default()));
+
+        let thread_status = Arc::clone(&status);
+        let thread = thread::spawn(move || run_engine(output, rx, thread_status));
+
+        Self {
+            tx,
+            status,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&self, message: PlaybackMessage) {
+        if self.tx.send(message).is_err() {
+            warn!("playback thread is gone");
+        }
+    }
+
+    pub fn play(&self, item: PlaybackItem) {
+        self.send(PlaybackMessage::Play(item));
+    }
+
+    pub fn play_all(&self, items: Vec<PlaybackItem>) {
+        self.send(PlaybackMessage::PlayAll(items));
+    }
+
+    pub fn enqueue(&self, item: PlaybackItem) {
+        self.send(PlaybackMessage::Enqueue(item));
+    }
+
+    pub fn clear_queue(&self) {
+        self.send(PlaybackMessage::ClearQueue);
+    }
+
+    pub fn next(&self) {
+        self.send(PlaybackMessage::Next);
+    }
+
+    pub fn enqueue_block(&self, items: Vec<PlaybackItem>) {
+        self.send(PlaybackMessage::EnqueueBlock(items));
+    }
+
+    pub fn set_settings(&self, settings: PlaybackSettings) {
+        self.send(PlaybackMessage::SetSettings(settings));
+    }
+
+    pub fn pause(&self) {
+        self.send(PlaybackMessage::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(PlaybackMessage::Resume);
+    }
+
+    pub fn toggle_pause(&self) {
+        match self.status().state {
+            PlaybackState::Playing => self.pause(),
+            PlaybackState::Paused => self.resume(),
+            PlaybackState::Stopped => (),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.send(PlaybackMessage::Stop);
+    }
+
+    pub fn seek(&self, position: Duration) {
+        self.send(PlaybackMessage::Seek(position));
+    }
+
+    pub fn status(&self) -> PlaybackStatus {
+        if let Ok(status) = self.status.read() {
+            status.clone()
+        } else {
+            PlaybackStatus::default()
+        }
+    }
+
+    pub fn position(&self) -> Duration {
+        self.status().position
+    }
+}
+
+impl Drop for PlaybackEngine {
+    fn drop(&mut self) {
+        let _ = self.tx.send(PlaybackMessage::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+enum Order {
+    /// replaces whatever is playing
+    Load(Decoded, u64),
+    /// starts when the playing source runs out, or as it fades out
+    Cue(Cued),
+    Uncue,
+    /// drops the playing source, moving on to the cued one if there is one
+    Skip,
+    Clear,
+}
+
+struct Cued {
+    source: Decoded,
+    generation: u64,
+    transition: Transition,
+}
+
+impl Cued {
+    fn fade_samples(&self) -> usize {
+        match self.transition {
+            Transition::Gapless => 0,
+            Transition::Crossfade { duration, .. } => duration_to_samples(duration) as usize,
+        }
+    }
+
+    fn curve(&self) -> FadeCurve {
+        match self.transition {
+            Transition::Gapless => FadeCurve::default(),
+            Transition::Crossfade { curve, .. } => curve,
+        }
+    }
+}
+
+/// the tail of a source that's being faded out under the one that replaced it
+struct Fade {
+    outgoing: VecDeque<f32>,
+    length: usize,
+    curve: FadeCurve,
+}
+
+impl Fade {
+    /// the next faded out sample, and the gain for the incoming one
+    fn next(&mut self) -> Option<(f32, f32)> {
+        let frame = (self.length - self.outgoing.len()) / CHANNELS as usize;
+        let frames = self.length.div_ceil(CHANNELS as usize);
+        let sample = self.outgoing.pop_front()?;
+
+        let (outgoing_gain, incoming_gain) = self.curve.gains(frame as f32 / frames as f32);
+        Some((sample * outgoing_gain, incoming_gain))
+    }
+}
+
+/// state shared between the engine thread and the deck, which lives on the audio thread
+#[derive(Default)]
+struct Shared {
+    paused: AtomicBool,
+    orders: Mutex<Vec<Order>>,
+    has_orders: AtomicBool,
+
+    /// generation of the source the deck is playing
+    loaded: AtomicU64,
+    /// generation of the last source that ran out of samples
+    finished: AtomicU64,
+    /// samples played from the loaded source
+    played: AtomicU64,
+}
+
+impl Shared {
+    fn order(&self, order: Order) {
+        if let Ok(mut orders) = self.orders.lock() {
+            orders.push(order);
+            self.has_orders.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// The source handed to the output. It never runs out: with nothing loaded it plays silence so
+/// the stream stays open between tracks.
+struct Deck {
+    shared: Arc<Shared>,
+    current: Option<Decoded>,
+    /// samples already pulled out of `current`, played before anything still in it. When the
+    /// cued source fades in, this holds the tail of the current one so we know when it starts.
+    lookahead: VecDeque<f32>,
+    cued: Option<Cued>,
+    fade: Option<Fade>,
+    generation: u64,
+    played: u64,
+    countdown: usize,
+}
+
+impl Deck {
+    fn new(shared: Arc<Shared>) -> Self {
+        Self {
+            shared,
+            current: None,
+            lookahead: VecDeque::new(),
+            cued: None,
+            fade: None,
+            generation: 0,
+            played: 0,
+            countdown: 0,
+        }
+    }
+
+    fn start(&mut self, source: Decoded, generation: u64) {
+        self.current = Some(source);
+        self.lookahead.clear();
+        self.generation = generation;
+        self.played = 0;
+        self.shared.loaded.store(generation, Ordering::Release);
+    }
+
+    fn finish(&mut self) {
+        self.shared.played.store(self.played, Ordering::Release);
+        self.shared
+            .finished
+            .store(self.generation, Ordering::Release);
+    }
+
+    /// hands over to the cued source, fading out whatever is left in the lookahead
+    fn transition(&mut self) {
+        let Some(cued) = self.cued.take() else {
+            return;
+        };
+        self.finish();
+
+        let outgoing = std::mem::take(&mut self.lookahead);
+        self.fade = (!outgoing.is_empty()).then(|| Fade {
+            length: outgoing.len(),
+            outgoing,
+            curve: cued.curve(),
+        });
+        self.start(cued.source, cued.generation);
+    }
+
+    fn read_ahead(&mut self, samples: usize) {
+        let Some(current) = self.current.as_mut() else {
+            return;
+        };
+        while self.lookahead.len() <= samples {
+            if let Some(sample) = current.next() {
+                self.lookahead.push_back(sample);
+            } else {
+                self.current = None;
+                return;
+            }
+        }
+    }
+
+    fn check_orders(&mut self) {
+        if !self.shared.has_orders.load(Ordering::Acquire) {
+            return;
+        }
+        // never wait on the engine thread from the audio thread, try again next period instead
+        let orders = if let Ok(mut orders) = self.shared.orders.try_lock() {
+            self.shared.has_orders.store(false, Ordering::Release);
+            std::mem::take(&mut *orders)
+        } else {
+            return;
+        };
+        for order in orders {
+            match order {
+                Order::Load(source, generation) => {
+                    self.fade = None;
+                    self.start(source, generation);
+                }
+                Order::Cue(cued) => self.cued = Some(cued),
+                Order::Uncue => self.cued = None,
+                Order::Skip => {
+                    self.current = None;
+                    self.lookahead.clear();
+                    self.finish();
+                }
+                Order::Clear => {
+                    self.current = None;
+                    self.lookahead.clear();
+                    self.cued = None;
+                    self.fade = None;
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Deck {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.countdown == 0 {
+            self.countdown = CONTROL_PERIOD;
+            self.check_orders();
+            self.shared.played.store(self.played, Ordering::Release);
+        }
+        self.countdown -= 1;
+
+        if self.shared.paused.load(Ordering::Relaxed) {
+            return Some(0.0);
+        }
+
+        let fade_samples = self.cued.as_ref().map_or(0, Cued::fade_samples);
+        self.read_ahead(fade_samples);
+        // with a gapless cue this happens once the lookahead is empty, so the cued source takes
+        // over within this same sample and nothing is inserted between the two
+        if self.current.is_none() && self.lookahead.len() <= fade_samples {
+            self.transition();
+            self.read_ahead(0);
+        }
+
+        let (faded, gain) = match self.fade.as_mut().and_then(Fade::next) {
+            Some(faded) => faded,
+            None => {
+                self.fade = None;
+                (0.0, 1.0)
+            }
+        };
+        let sample = if let Some(sample) = self.lookahead.pop_front() {
+            self.played += 1;
+            sample
+        } else {
+            if self.current.is_none() {
+                self.finish();
+            }
+            0.0
+        };
+        Some(sample * gain + faded)
+    }
+}
+
+impl Source for Deck {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+fn samples_to_duration(samples: u64) -> Duration {
+    Duration::from_secs_f64(samples as f64 / (SAMPLE_RATE as f64 * CHANNELS as f64))
+}
+
+fn duration_to_samples(duration: Duration) -> u64 {
+    (duration.as_secs_f64() * SAMPLE_RATE as f64) as u64 * CHANNELS as u64
+}
+
+/// Opens and decodes an item, converted to the engine's sample rate and channel count and
+/// normalized. This is only ever called on the engine thread so the audio thread never touches
+/// the disk.
+fn decode(item: &PlaybackItem, start: Duration, normalization: &Normalization) -> Result<Decoded> {
+    if let Some(format) = formats::identify(&item.path).filter(|format| !format.playable) {
+        bail!("there's no decoder for {} files yet", format.name);
+    }
+    let file = File::open(&item.path)?;
+    let decoder = Decoder::new(BufReader::new(file))?;
+    let mut source = UniformSourceIterator::<_, f32>::new(decoder, CHANNELS, SAMPLE_RATE);
+
+    let start = item.start + start;
+    if start > Duration::ZERO && source.try_seek(start).is_err() {
+        // not every decoder can seek, so decode our way there instead
+        for _ in 0..duration_to_samples(start) {
+            if source.next().is_none() {
+                break;
+            }
+        }
+    }
+
+    // counted in samples rather than with `take_duration` so the next track in the same file
+    // picks up exactly where this one stops
+    let source: Decoded = match item.end {
+        Some(end) => Box::new(TakeSamples {
+            source,
+            remaining: duration_to_samples(end.saturating_sub(start)) as usize,
+        }),
+        None => Box::new(source),
+    };
+    match normalization.gain(&item.replay_gain) {
+        Some((gain, peak)) => Ok(Box::new(Normalize::new(source, gain, peak))),
+        None => Ok(source),
+    }
+}
+
+/// A source turned up or down by a gain, with a limiter that keeps it under
+/// `LIMITER_CEILING_DB`. The limiter works on whole frames so every channel is turned down
+/// together, it comes down at once on a peak and lets go over `LIMITER_RELEASE`.
+struct Normalize {
+    source: Decoded,
+    gain: f32,
+    /// off when the item's peak says it'll stay under the ceiling anyway
+    limit: bool,
+    ceiling: f32,
+    /// how much of the way back to no reduction the limiter goes each frame
+    release: f32,
+    /// the limiter's gain right now, 1 when it isn't doing anything
+    reduction: f32,
+    frame: [f32; CHANNELS as usize],
+    /// how many samples of `frame` there are, and how many have been handed out
+    len: usize,
+    position: usize,
+}
+
+impl Normalize {
+    fn new(source: Decoded, gain: f32, peak: Option<f32>) -> Self {
+        let release_frames = LIMITER_RELEASE.as_secs_f32() * SAMPLE_RATE as f32;
+        let ceiling = 10f32.powf(LIMITER_CEILING_DB / 20.0);
+        Self {
+            source,
+            gain,
+            limit: peak.is_none_or(|peak| peak * gain > ceiling),
+            ceiling,
+            release: 1.0 - (-1.0 / release_frames).exp(),
+            reduction: 1.0,
+            frame: [0.0; CHANNELS as usize],
+            len: 0,
+            position: 0,
+        }
+    }
+
+    /// the next sample of the frame that's been worked out already
+    fn next_in_frame(&mut self) -> Option<f32> {
+        let sample = *self.frame[..self.len].get(self.position)?;
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Iterator for Normalize {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position == self.len {
+            self.len = 0;
+            self.position = 0;
+            for sample in self.frame.iter_mut() {
+                let Some(next) = self.source.next() else {
+                    break;
+                };
+                *sample = next * self.gain;
+                self.len += 1;
+            }
+            if !self.limit {
+                return self.next_in_frame();
+            }
+            let peak = self.frame[..self.len]
+                .iter()
+                .fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+            self.reduction += (1.0 - self.reduction) * self.release;
+            if peak * self.reduction > self.ceiling {
+                self.reduction = self.ceiling / peak;
+            }
+            for sample in &mut self.frame[..self.len] {
+                *sample *= self.reduction;
+            }
+        }
+        self.next_in_frame()
+    }
+}
+
+impl Source for Normalize {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+/// a source cut off after a number of samples
+struct TakeSamples<S> {
+    source: S,
+    remaining: usize,
+}
+
+impl<S: Source<Item = f32>> Iterator for TakeSamples<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        self.source.next()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for TakeSamples<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source
+            .current_frame_len()
+            .map(|len| len.min(self.remaining))
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(samples_to_duration(self.remaining as u64))
+    }
+}
+
+struct Engine {
+    shared: Arc<Shared>,
+    status: Arc<RwLock<PlaybackStatus>>,
+    state: PlaybackState,
+    current: Option<PlaybackItem>,
+    /// where in the current item the loaded source started
+    offset: Duration,
+    /// the item decoded ahead of time and waiting in the deck
+    cued: Option<(PlaybackItem, u64)>,
+    upcoming: VecDeque<PlaybackItem>,
+    settings: PlaybackSettings,
+    generation: u64,
+    last_generation: u64,
+    last_block: u64,
+    /// the block of the last item that was handed to us, before renumbering
+    incoming_block: Option<u64>,
+}
+
+impl Engine {
+    fn new(shared: Arc<Shared>, status: Arc<RwLock<PlaybackStatus>>) -> Self {
+        Self {
+            shared,
+            status,
+            state: PlaybackState::Stopped,
+            current: None,
+            offset: Duration::ZERO,
+            cued: None,
+            upcoming: VecDeque::new(),
+            settings: PlaybackSettings::default(),
+            generation: 0,
+            last_generation: 0,
+            last_block: 0,
+            incoming_block: None,
+        }
+    }
+
+    /// The blocks items come with are renumbered so they can't collide with the ones we hand
+    /// out, consecutive items with the same block stay together.
+    fn renumber(&mut self, mut item: PlaybackItem) -> PlaybackItem {
+        if item.block.is_some() {
+            if item.block != self.incoming_block {
+                self.last_block += 1;
+            }
+            self.incoming_block = item.block;
+            item.block = Some(self.last_block);
+        } else {
+            self.incoming_block = None;
+        }
+        item
+    }
+
+    fn next_generation(&mut self) -> u64 {
+        self.last_generation += 1;
+        self.last_generation
+    }
+
+    fn load(&mut self, item: PlaybackItem, start: Duration) {
+        match decode(&item, start, &self.settings.normalization) {
+            Ok(source) => {
+                self.generation = self.next_generation();
+                self.shared.order(Order::Load(source, self.generation));
+                self.current = Some(item);
+                self.offset = start;
+                self.cue();
+            }
+            Err(e) => {
+                warn!("couldn't decode {}: {e}", item.path.display());
+                self.stop();
+            }
+        }
+    }
+
+    /// decodes the next item ahead of time so the deck can go straight into it
+    fn cue(&mut self) {
+        if self.current.is_none() || self.cued.is_some() {
+            return;
+        }
+        while let Some(item) = self.upcoming.pop_front() {
+            let same_block = item.block.is_some()
+                && self
+                    .current
+                    .as_ref()
+                    .is_some_and(|current| current.block == item.block);
+            let transition = if same_block {
+                Transition::Gapless
+            } else {
+                item.transition.unwrap_or(self.settings.transition)
+            };
+            match decode(&item, Duration::ZERO, &self.settings.normalization) {
+                Ok(source) => {
+                    let generation = self.next_generation();
+                    self.shared.order(Order::Cue(Cued {
+                        source,
+                        generation,
+                        transition,
+                    }));
+                    self.cued = Some((item, generation));
+                    return;
+                }
+                Err(e) => warn!("couldn't decode {}, skipping it: {e}", item.path.display()),
+            }
+        }
+    }
+
+    fn uncue(&mut self) {
+        if let Some((item, _)) = self.cued.take() {
+            self.shared.order(Order::Uncue);
+            self.upcoming.push_front(item);
+        }
+    }
+
+    fn stop(&mut self) {
+        self.shared.order(Order::Clear);
+        if let Some((item, _)) = self.cued.take() {
+            self.upcoming.push_front(item);
+        }
+        self.current = None;
+        self.offset = Duration::ZERO;
+        self.state = PlaybackState::Stopped;
+    }
+
+    fn handle(&mut self, message: PlaybackMessage) {
+        match message {
+            PlaybackMessage::Play(item) => {
+                info!("playing {}", item.path.display());
+                self.shared.paused.store(false, Ordering::Release);
+                self.state = PlaybackState::Playing;
+                self.load(item, Duration::ZERO);
+            }
+            PlaybackMessage::PlayAll(items) => {
+                self.uncue();
+                self.upcoming.clear();
+
+                self.incoming_block = None;
+                let mut items = items.into_iter();
+                let first = items.next().map(|item| self.renumber(item));
+                for item in items {
+                    let item = self.renumber(item);
+                    self.upcoming.push_back(item);
+                }
+
+                if let Some(item) = first {
+                    self.handle(PlaybackMessage::Play(item));
+                } else {
+                    self.stop();
+                }
+            }
+            PlaybackMessage::Enqueue(item) => {
+                let item = self.renumber(item);
+                self.upcoming.push_back(item);
+                self.cue();
+            }
+            PlaybackMessage::EnqueueBlock(items) => {
+                self.last_block += 1;
+                for mut item in items {
+                    item.block = Some(self.last_block);
+                    self.upcoming.push_back(item);
+                }
+                self.incoming_block = None;
+                self.cue();
+            }
+            PlaybackMessage::ClearQueue => {
+                self.uncue();
+                self.upcoming.clear();
+            }
+            PlaybackMessage::SetSettings(settings) => self.settings = settings,
+            PlaybackMessage::Next => {
+                let block = self.current.as_ref().and_then(|current| current.block);
+                if block.is_some() && self.settings.block_entry == BlockEntry::Start {
+                    self.uncue();
+                    while self
+                        .upcoming
+                        .front()
+                        .is_some_and(|item| item.block == block)
+                    {
+                        self.upcoming.pop_front();
+                    }
+                    self.cue();
+                }
+
+                if self.cued.is_some() {
+                    self.shared.order(Order::Skip);
+                } else if let Some(item) = self.upcoming.pop_front() {
+                    self.load(item, Duration::ZERO);
+                } else {
+                    self.stop();
+                }
+            }
+            PlaybackMessage::Pause => {
+                if self.state == PlaybackState::Playing {
+                    self.shared.paused.store(true, Ordering::Release);
+                    self.state = PlaybackState::Paused;
+                }
+            }
+            PlaybackMessage::Resume => {
+                if self.state == PlaybackState::Paused {
+                    self.shared.paused.store(false, Ordering::Release);
+                    self.state = PlaybackState::Playing;
+                }
+            }
+            PlaybackMessage::Stop => self.stop(),
+            PlaybackMessage::Seek(position) => {
+                if let Some(item) = self.current.clone() {
+                    self.load(item, position);
+                }
+            }
+            PlaybackMessage::Shutdown => (),
+        }
+    }
+
+    fn update(&mut self) {
+        let loaded = self.shared.loaded.load(Ordering::Acquire);
+        if let Some((item, generation)) = self.cued.take() {
+            if loaded == generation {
+                self.current = Some(item);
+                self.generation = generation;
+                self.offset = Duration::ZERO;
+                self.cue();
+            } else {
+                self.cued = Some((item, generation));
+            }
+        }
+        if self.current.is_some() && self.shared.finished.load(Ordering::Acquire) == self.generation
+        {
+            self.current = None;
+            self.state = PlaybackState::Stopped;
+        }
+        let loaded = loaded == self.generation;
+
+        if let Ok(mut status) = self.status.write() {
+            status.state = self.state;
+            status.item = self.current.clone();
+            status.generation = self.generation;
+            status.position = if self.current.is_none() {
+                Duration::ZERO
+            } else if loaded {
+                self.offset + samples_to_duration(self.shared.played.load(Ordering::Acquire))
+            } else {
+                self.offset
+            };
+            status.queue = self
+                .cued
+                .iter()
+                .map(|(item, _)| item)
+                .chain(self.upcoming.iter())
+                .cloned()
+                .collect();
+        }
+    }
+}
+
+fn run_engine(
+    output: PlaybackOutput,
+    rx: Receiver<PlaybackMessage>,
+    status: Arc<RwLock<PlaybackStatus>>,
+) {
+    let shared = Arc::new(Shared::default());
+    let deck = Deck::new(Arc::clone(&shared));
+
+    // the output stream isn't Send, so it has to be opened and kept on this thread
+    let (_stream, mut null_deck) = match output {
+        PlaybackOutput::Device => match OutputStream::try_default() {
+            Ok((stream, handle)) => match handle.play_raw(deck) {
+                Ok(()) => (Some(stream), None),
+                Err(e) => {
+                    warn!("couldn't play on output device, falling back to null output: {e}");
+                    (None, Some(Deck::new(Arc::clone(&shared))))
+                }
+            },
+            Err(e) => {
+                warn!("couldn't open output device, falling back to null output: {e}");
+                (None, Some(deck))
+            }
+        },
+        PlaybackOutput::Null => (None, Some(deck)),
+    };
+
+    let mut engine = Engine::new(shared, status);
+    let mut last_tick = Instant::now();
+
+    loop {
+        match rx.recv_timeout(TICK) {
+            Ok(PlaybackMessage::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(message) => engine.handle(message),
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+
+        if let Some(deck) = null_deck.as_mut() {
+            let samples = duration_to_samples(last_tick.elapsed());
+            for _ in 0..samples {
+                deck.next();
+            }
+            last_tick += samples_to_duration(samples);
+        }
+
+        engine.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::{sine, temp_dir, write_wav};
+
+    fn item(path: &Path) -> PlaybackItem {
+        PlaybackItem {
+            path: path.to_path_buf(),
+            name: path.to_string_lossy().into(),
+            transition: None,
+            block: None,
+            queue_entry: None,
+            start: Duration::ZERO,
+            end: None,
+            replay_gain: ReplayGain::default(),
+        }
+    }
+
+    /// an engine and the deck it plays into, pulled by hand like the null output does
+    fn null_engine() -> (Engine, Deck) {
+        let shared = Arc::new(Shared::default());
+        let deck = Deck::new(Arc::clone(&shared));
+        let status = Arc::new(RwLock::new(PlaybackStatus::default()));
+        (Engine::new(shared, status), deck)
+    }
+
+    /// plays `duration` worth of samples, then lets the engine catch up with the deck
+    fn play_for(engine: &mut Engine, deck: &mut Deck, duration: Duration) -> Vec<f32> {
+        let samples = (0..duration_to_samples(duration))
+            .map(|_| deck.next().unwrap_or_default())
+            .collect();
+        engine.update();
+        samples
+    }
+
+    fn status(engine: &Engine) -> PlaybackStatus {
+        engine.status.read().unwrap().clone()
+    }
+
+    fn assert_near(position: Duration, expected: Duration) {
+        // the deck only picks up orders and reports how far it got every `CONTROL_PERIOD`
+        // samples
+        let slack = samples_to_duration(2 * CONTROL_PERIOD as u64);
+        assert!(
+            position <= expected && expected - position <= slack,
+            "at {position:?}, expected {expected:?}"
+        );
+    }
+
+    #[test]
+    fn controls_change_status() {
+        let dir = temp_dir("controls");
+        let path = dir.join("sine.wav");
+        write_wav(&path, SAMPLE_RATE, CHANNELS, &sine(SAMPLE_RATE, 1.0, 440.0));
+        let (mut engine, mut deck) = null_engine();
+        let ms = Duration::from_millis;
+
+        engine.handle(PlaybackMessage::Play(item(&path)));
+        play_for(&mut engine, &mut deck, ms(200));
+        let playing = status(&engine);
+        assert_eq!(playing.state, PlaybackState::Playing);
+        assert_eq!(playing.item, Some(item(&path)));
+        assert_near(playing.position, ms(200));
+
+        engine.handle(PlaybackMessage::Pause);
+        let paused = play_for(&mut engine, &mut deck, ms(100));
+        assert_eq!(status(&engine).state, PlaybackState::Paused);
+        assert!(paused.iter().all(|sample| *sample == 0.0));
+        assert_near(status(&engine).position, ms(200));
+
+        engine.handle(PlaybackMessage::Resume);
+        engine.handle(PlaybackMessage::Seek(ms(600)));
+        play_for(&mut engine, &mut deck, ms(100));
+        assert_eq!(status(&engine).state, PlaybackState::Playing);
+        assert_near(status(&engine).position, ms(700));
+
+        engine.handle(PlaybackMessage::Stop);
+        engine.update();
+        let stopped = status(&engine);
+        assert_eq!(stopped.state, PlaybackState::Stopped);
+        assert_eq!(stopped.item, None);
+        assert_eq!(stopped.position, Duration::ZERO);
+
+        // and stops by itself at the end
+        engine.handle(PlaybackMessage::Play(item(&path)));
+        play_for(&mut engine, &mut deck, ms(1100));
+        assert_eq!(status(&engine).state, PlaybackState::Stopped);
+    }
+
+    #[test]
+    fn gapless_leaves_no_gap() {
+        let dir = temp_dir("gapless");
+        let (first, second) = (dir.join("first.wav"), dir.join("second.wav"));
+        // constant rather than a tone, so any zero is a gap
+        write_wav(&first, SAMPLE_RATE, CHANNELS, &vec![1000; 11_025 * 2]);
+        write_wav(&second, SAMPLE_RATE, CHANNELS, &vec![2000; 11_025 * 2]);
+        let (mut engine, mut deck) = null_engine();
+
+        engine.handle(PlaybackMessage::Play(item(&first)));
+        engine.handle(PlaybackMessage::Enqueue(item(&second)));
+        let played = play_for(&mut engine, &mut deck, Duration::from_millis(600));
+
+        let start = played.iter().position(|sample| *sample != 0.0).unwrap();
+        let end = played.iter().rposition(|sample| *sample != 0.0).unwrap() + 1;
+        assert_eq!(end - start, 2 * 11_025 * 2);
+        assert!(played[start..end].iter().all(|sample| *sample != 0.0));
+        assert_eq!(played[start + 11_025 * 2 - 1], 1000.0 / 32768.0);
+        assert_eq!(played[start + 11_025 * 2], 2000.0 / 32768.0);
+        assert_eq!(status(&engine).state, PlaybackState::Stopped);
+    }
+
+    #[test]
+    fn refuses_what_it_cant_decode() {
+        let dir = temp_dir("undecodable");
+        let path = dir.join("song.opus");
+        fs::write(&path, b"OggS").unwrap();
+        let Err(e) = decode(&item(&path), Duration::ZERO, &Normalization::default()) else {
+            panic!("decoded an opus file");
+        };
+        assert_eq!(e.to_string(), "there's no decoder for Opus files yet");
+    }
+
+    #[test]
+    fn fade_curves() {
+        let floor = 10f32.powf(FADE_FLOOR_DB / 2.0 / 20.0);
+        let half_power = std::f32::consts::FRAC_1_SQRT_2;
+        for (curve, middle) in [
+            (FadeCurve::Linear, 0.5),
+            (FadeCurve::EqualPower, half_power),
+            (FadeCurve::Logarithmic, floor),
+        ] {
+            for (progress, expected) in [
+                (0.0, (1.0, 0.0)),
+                (0.5, (middle, middle)),
+                (1.0, (0.0, 1.0)),
+            ] {
+                let (outgoing, incoming) = curve.gains(progress);
+                assert!(
+                    (outgoing - expected.0).abs() < 1e-6 && (incoming - expected.1).abs() < 1e-6,
+                    "{curve:?} at {progress} gave {:?}",
+                    (outgoing, incoming)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn equal_power_crossfade_keeps_level() {
+        let dir = temp_dir("crossfade");
+        let (first, second) = (dir.join("first.wav"), dir.join("second.wav"));
+        write_wav(
+            &first,
+            SAMPLE_RATE,
+            CHANNELS,
+            &sine(SAMPLE_RATE, 1.0, 440.0),
+        );
+        write_wav(
+            &second,
+            SAMPLE_RATE,
+            CHANNELS,
+            &sine(SAMPLE_RATE, 1.0, 660.0),
+        );
+        let (mut engine, mut deck) = null_engine();
+        engine.handle(PlaybackMessage::SetSettings(PlaybackSettings {
+            transition: Transition::Crossfade {
+                duration: Duration::from_millis(300),
+                curve: FadeCurve::EqualPower,
+            },
+            ..Default::default()
+        }));
+
+        engine.handle(PlaybackMessage::Play(item(&first)));
+        engine.handle(PlaybackMessage::Enqueue(item(&second)));
+        let played = play_for(&mut engine, &mut deck, Duration::from_millis(1800));
+
+        let start = played.iter().position(|sample| *sample != 0.0).unwrap();
+        let end = played.iter().rposition(|sample| *sample != 0.0).unwrap() + 1;
+        // the second file comes in under the last 300 ms of the first
+        let overlap = duration_to_samples(Duration::from_millis(300)) as usize;
+        assert!(
+            (end - start).abs_diff(2 * SAMPLE_RATE as usize * 2 - overlap) <= CHANNELS as usize
+        );
+
+        // the two tones don't correlate, so with equal power their sum is as loud as either
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32)
+                .sqrt()
+        };
+        let expected = 16000.0 / 32768.0 * std::f32::consts::FRAC_1_SQRT_2;
+        for window in
+            played[start..end].chunks_exact(duration_to_samples(Duration::from_millis(50)) as usize)
+        {
+            let level = rms(window);
+            assert!(
+                (level / expected - 1.0).abs() < 0.05,
+                "level {level}, expected {expected}"
+            );
+        }
+    }
+}
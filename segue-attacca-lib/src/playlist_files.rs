@@ -0,0 +1,740 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read,
+    path::{Component, Path, PathBuf, absolute},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use color_eyre::{Result, eyre::bail};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use quick_xml::{
+    Reader, Writer,
+    events::{BytesDecl, BytesText, Event},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    files::write_atomically,
+    music_library::{MusicLibrary, Playlist, PlaylistItem, Track},
+};
+
+/// non-standard m3u directives around the tracks of a block
+const M3U_BLOCK_START: &str = "#SEGUE-BLOCK-START";
+const M3U_BLOCK_END: &str = "#SEGUE-BLOCK-END";
+/// the `rel` of the xspf `<meta>` that numbers the block a track is in
+const XSPF_BLOCK_REL: &str = "https://github.com/amitmaish/segue-attacca/block";
+/// Which track of a cue sheet an entry is, as the sheet's location and the track's number with
+/// a `#` between them. Tracks of a sheet all share its file, so the file alone doesn't say.
+const M3U_CUE: &str = "#SEGUE-CUE:";
+const XSPF_CUE_REL: &str = "https://github.com/amitmaish/segue-attacca/cue";
+/// where VLC starts and stops playing an entry, in seconds, which other players read too
+const M3U_START: &str = "#EXTVLCOPT:start-time=";
+const M3U_STOP: &str = "#EXTVLCOPT:stop-time=";
+const XSPF_NAMESPACE: &str = "http://xspf.org/ns/0/";
+
+/// what gets escaped in an xspf location, which is a uri
+const URI_ESCAPES: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// extended m3u, utf-8
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// the format a playlist file is in, going by its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(Self::M3u8),
+            "xspf" => Some(Self::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// A playlist read from a file, and the entries in it that didn't match any track in the
+/// library.
+#[derive(Debug)]
+pub struct Import {
+    pub playlist: Arc<RwLock<Playlist>>,
+    pub unresolved: Vec<String>,
+}
+
+/// A track as it's written out. Nested playlists are flattened into their tracks, and `block`
+/// numbers the top level block a track is in, so other players get a plain list of tracks and
+/// we get our blocks back.
+struct Entry {
+    track: Arc<RwLock<Track>>,
+    /// where the track's file is from the playlist file
+    location: String,
+    /// for a track from a cue sheet, where the sheet is and the track's number in it
+    cue: Option<String>,
+    block: Option<usize>,
+}
+
+/// Writes a playlist to `path`, in the format its extension says. Tracks are written with
+/// their paths relative to the folder the playlist file is in, so other players find them
+/// wherever it's saved.
+pub fn export_playlist(library: &MusicLibrary, playlist: Uuid, path: &Path) -> Result<()> {
+    let Some(format) = PlaylistFormat::from_path(path) else {
+        bail!("don't know what playlist format {} is", path.display());
+    };
+    let Some(lock) = library.playlist(playlist) else {
+        bail!("no playlist {playlist}");
+    };
+    let Ok(playlist) = lock.read() else {
+        bail!("couldn't read playlist");
+    };
+
+    let library_folder = full_path(Path::new(library.path.as_ref()))?;
+    let folder = full_path(folder_of(path))?;
+    let locate = |path: &str| {
+        let file = library_folder.join(path);
+        let location = path_from(&folder, &file).unwrap_or(file);
+        location.to_string_lossy().into_owned()
+    };
+
+    let mut entries = Vec::new();
+    let mut blocks = 0;
+    let mut visited = HashSet::from([playlist.uuid()]);
+    flatten(
+        playlist.items(),
+        None,
+        &mut blocks,
+        &mut visited,
+        &locate,
+        &mut entries,
+    );
+
+    let contents = match format {
+        PlaylistFormat::M3u8 => write_m3u8(playlist.name(), &entries),
+        PlaylistFormat::Xspf => write_xspf(playlist.name(), &entries)?,
+    };
+    write_atomically(path, contents.as_bytes())
+}
+
+/// Reads the playlist file at `path` into a new playlist at the end of the library's. Relative
+/// paths in it are resolved against the folder it's in.
+pub fn import_playlist(library: &mut MusicLibrary, path: &Path) -> Result<Import> {
+    let Some(format) = PlaylistFormat::from_path(path) else {
+        bail!("don't know what playlist format {} is", path.display());
+    };
+    let contents = read(path)?;
+    let contents = String::from_utf8_lossy(&contents);
+    let (name, listed) = match format {
+        PlaylistFormat::M3u8 => read_m3u8(&contents),
+        PlaylistFormat::Xspf => read_xspf(&contents)?,
+    };
+    let name = name.unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+
+    // the tracks of a cue sheet all have its file's path
+    let mut tracks: HashMap<Box<str>, Vec<Arc<RwLock<Track>>>> = HashMap::new();
+    for lock in library.get_tracks() {
+        if let Ok(track) = lock.read() {
+            tracks
+                .entry(track.path.clone())
+                .or_default()
+                .push(Arc::clone(lock));
+        }
+    }
+    let library_folder = full_path(Path::new(library.path.as_ref()))?;
+    let folder = full_path(folder_of(path))?;
+    let mut unresolved = Vec::new();
+
+    let mut items = Vec::new();
+    let mut block: Option<(usize, Vec<PlaylistItem>)> = None;
+    for listed in listed {
+        let track = library_path(&library_folder, &folder, &listed.location)
+            .and_then(|path| tracks.get(path.as_str()))
+            .and_then(|tracks| find_track(tracks, &listed, &library_folder, &folder));
+        let Some(track) = track else {
+            warn!("{} isn't in the library, leaving it out", listed.location);
+            unresolved.push(listed.location);
+            continue;
+        };
+        let item = PlaylistItem::Track(track);
+        match (&mut block, listed.block) {
+            (Some((current, items)), Some(number)) if *current == number => items.push(item),
+            (_, number) => {
+                if let Some((_, block_items)) = block.take() {
+                    items.push(PlaylistItem::Block(block_items));
+                }
+                match number {
+                    Some(number) => block = Some((number, vec![item])),
+                    None => items.push(item),
+                }
+            }
+        }
+    }
+    if let Some((_, block_items)) = block {
+        items.push(PlaylistItem::Block(block_items));
+    }
+
+    let playlist = library.create_playlist(&name);
+    let Ok(uuid) = playlist.read().map(|playlist| playlist.uuid()) else {
+        bail!("couldn't read playlist");
+    };
+    for (i, item) in items.into_iter().enumerate() {
+        library.insert_item(uuid, &[i], item)?;
+    }
+    Ok(Import {
+        playlist,
+        unresolved,
+    })
+}
+
+/// Which of the tracks with a listed file's path it means. A track from a cue sheet is found by
+/// its number, or failing that where it starts, and a file that isn't split up by its only
+/// track.
+fn find_track(
+    tracks: &[Arc<RwLock<Track>>],
+    listed: &Listed,
+    library_folder: &Path,
+    folder: &Path,
+) -> Option<Arc<RwLock<Track>>> {
+    let cue = listed.cue.as_ref().and_then(|cue| {
+        let (sheet, number) = cue.rsplit_once('#')?;
+        Some((
+            library_path(library_folder, folder, sheet),
+            number.parse::<u32>().ok()?,
+        ))
+    });
+    let score = |track: &Track| match (&track.cue, &cue, listed.start) {
+        // another sheet could split up the same file
+        (Some(range), Some((sheet, number)), _) if range.number == *number => {
+            Some(if sheet.as_deref() == Some(&*range.sheet) {
+                2
+            } else {
+                1
+            })
+        }
+        (Some(range), None, Some(start)) => {
+            (range.start.abs_diff(start) < Duration::from_millis(500)).then_some(1)
+        }
+        (None, None, None) => Some(1),
+        _ => None,
+    };
+    let best = tracks
+        .iter()
+        .filter_map(|lock| Some((score(&*lock.read().ok()?)?, lock)))
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, lock)| Arc::clone(lock));
+    // a whole file that's been split up since, or the other way around
+    best.or_else(|| match tracks {
+        [only] => Some(Arc::clone(only)),
+        _ => None,
+    })
+}
+
+fn flatten(
+    items: &[PlaylistItem],
+    block: Option<usize>,
+    blocks: &mut usize,
+    visited: &mut HashSet<Uuid>,
+    locate: &impl Fn(&str) -> String,
+    entries: &mut Vec<Entry>,
+) {
+    for item in items {
+        match item {
+            PlaylistItem::Track(lock) => {
+                let Ok(track) = lock.read() else {
+                    continue;
+                };
+                entries.push(Entry {
+                    track: Arc::clone(lock),
+                    location: locate(&track.path),
+                    cue: track
+                        .cue
+                        .as_ref()
+                        .map(|cue| format!("{}#{}", locate(&cue.sheet), cue.number)),
+                    block,
+                });
+            }
+            PlaylistItem::Playlist(weak) => {
+                let Some(lock) = weak.upgrade() else {
+                    continue;
+                };
+                let Ok(playlist) = lock.read() else {
+                    continue;
+                };
+                if !visited.insert(playlist.uuid()) {
+                    continue;
+                }
+                flatten(playlist.items(), block, blocks, visited, locate, entries);
+                visited.remove(&playlist.uuid());
+            }
+            PlaylistItem::Block(items) => {
+                // blocks inside a block are all part of the outer one
+                let block = block.unwrap_or_else(|| {
+                    *blocks += 1;
+                    *blocks
+                });
+                flatten(items, Some(block), blocks, visited, locate, entries);
+            }
+        }
+    }
+}
+
+fn write_m3u8(name: &str, entries: &[Entry]) -> String {
+    let mut m3u = format!("#EXTM3U\n#PLAYLIST:{name}\n");
+    let mut block = None;
+    for entry in entries {
+        let Ok(track) = entry.track.read() else {
+            continue;
+        };
+        if entry.block != block {
+            if block.is_some() {
+                m3u.push_str(M3U_BLOCK_END);
+                m3u.push('\n');
+            }
+            if entry.block.is_some() {
+                m3u.push_str(M3U_BLOCK_START);
+                m3u.push('\n');
+            }
+            block = entry.block;
+        }
+        // we don't know how long tracks are, which -1 says
+        match &track.artist {
+            Some(artist) => m3u.push_str(&format!("#EXTINF:-1,{artist} - {}\n", track.name)),
+            None => m3u.push_str(&format!("#EXTINF:-1,{}\n", track.name)),
+        }
+        if let (Some(cue), Some(range)) = (&entry.cue, &track.cue) {
+            m3u.push_str(&format!("{M3U_CUE}{cue}\n"));
+            m3u.push_str(&format!("{M3U_START}{}\n", range.start.as_secs_f64()));
+            if let Some(end) = range.end {
+                m3u.push_str(&format!("{M3U_STOP}{}\n", end.as_secs_f64()));
+            }
+        }
+        m3u.push_str(&entry.location);
+        m3u.push('\n');
+    }
+    if block.is_some() {
+        m3u.push_str(M3U_BLOCK_END);
+        m3u.push('\n');
+    }
+    m3u
+}
+
+/// A track as it's read back, before it's been found in the library.
+#[derive(Debug, Default)]
+struct Listed {
+    location: String,
+    block: Option<usize>,
+    /// the cue sheet and track number, as `Entry` has it
+    cue: Option<String>,
+    /// where playback starts, for players that write that but not which cue track it is
+    start: Option<Duration>,
+}
+
+/// the playlist's name if it has one, and its tracks
+type Locations = (Option<String>, Vec<Listed>);
+
+fn read_m3u8(contents: &str) -> Locations {
+    let mut name = None;
+    let mut listed = Vec::new();
+    let mut blocks = 0;
+    let mut block = None;
+    // what the directives before a location said about it
+    let mut next = Listed::default();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line == M3U_BLOCK_START {
+            blocks += 1;
+            block = Some(blocks);
+        } else if line == M3U_BLOCK_END {
+            block = None;
+        } else if let Some(playlist) = line.strip_prefix("#PLAYLIST:") {
+            name = Some(playlist.trim().to_string());
+        } else if let Some(cue) = line.strip_prefix(M3U_CUE) {
+            next.cue = Some(cue.to_string());
+        } else if let Some(start) = line.strip_prefix(M3U_START) {
+            next.start = start
+                .parse()
+                .ok()
+                .and_then(|start| Duration::try_from_secs_f64(start).ok());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            listed.push(Listed {
+                location: line.to_string(),
+                block,
+                ..std::mem::take(&mut next)
+            });
+        }
+    }
+    (name, listed)
+}
+
+fn write_xspf(name: &str, entries: &[Entry]) -> Result<String> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("playlist")
+        .with_attributes([("version", "1"), ("xmlns", XSPF_NAMESPACE)])
+        .write_inner_content(|writer| {
+            writer
+                .create_element("title")
+                .write_text_content(BytesText::new(name))?;
+            writer
+                .create_element("trackList")
+                .write_inner_content(|writer| {
+                    for entry in entries {
+                        let Ok(track) = entry.track.read() else {
+                            continue;
+                        };
+                        writer
+                            .create_element("track")
+                            .write_inner_content(|writer| {
+                                let location = utf8_percent_encode(&entry.location, URI_ESCAPES);
+                                writer
+                                    .create_element("location")
+                                    .write_text_content(BytesText::new(&location.to_string()))?;
+                                writer
+                                    .create_element("title")
+                                    .write_text_content(BytesText::new(&track.name))?;
+                                if let Some(artist) = &track.artist {
+                                    writer
+                                        .create_element("creator")
+                                        .write_text_content(BytesText::new(artist))?;
+                                }
+                                if let Some(album) = &track.album {
+                                    writer
+                                        .create_element("album")
+                                        .write_text_content(BytesText::new(album))?;
+                                }
+                                if let Some(number) = track.track_number {
+                                    writer
+                                        .create_element("trackNum")
+                                        .write_text_content(BytesText::new(&number.to_string()))?;
+                                }
+                                if let Some(block) = entry.block {
+                                    writer
+                                        .create_element("meta")
+                                        .with_attribute(("rel", XSPF_BLOCK_REL))
+                                        .write_text_content(BytesText::new(&block.to_string()))?;
+                                }
+                                if let Some(cue) = &entry.cue {
+                                    writer
+                                        .create_element("meta")
+                                        .with_attribute(("rel", XSPF_CUE_REL))
+                                        .write_text_content(BytesText::new(cue))?;
+                                }
+                                Ok(())
+                            })?;
+                    }
+                    Ok(())
+                })?;
+            Ok(())
+        })?;
+    let mut xspf = String::from_utf8(writer.into_inner())?;
+    xspf.push('\n');
+    Ok(xspf)
+}
+
+fn read_xspf(contents: &str) -> Result<Locations> {
+    let mut reader = Reader::from_str(contents);
+    reader.config_mut().trim_text(true);
+
+    let mut name = None;
+    let mut listed = Vec::new();
+    // the elements we're in, what we've got so far of the track we're in, and what the
+    // `<meta>` we're in is
+    let mut open: Vec<Vec<u8>> = Vec::new();
+    let mut location = None;
+    let mut track = Listed::default();
+    let mut meta = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => {
+                let element = start.local_name().as_ref().to_vec();
+                if element == b"meta" {
+                    meta = start
+                        .try_get_attribute("rel")?
+                        .map(|rel| rel.value.into_owned());
+                }
+                open.push(element);
+            }
+            Event::End(_) => {
+                let element = open.pop();
+                if element.as_deref() == Some(b"track") {
+                    let track = std::mem::take(&mut track);
+                    if let Some(location) = location.take() {
+                        listed.push(Listed { location, ..track });
+                    }
+                }
+            }
+            Event::Text(text) => {
+                let text = text.unescape()?.into_owned();
+                match open.as_slice() {
+                    [.., parent, element] if parent == b"playlist" && element == b"title" => {
+                        name = Some(text);
+                    }
+                    // file uris are decoded along with m3u paths, relative ones have to be here
+                    [.., parent, element] if parent == b"track" && element == b"location" => {
+                        location = Some(if text.starts_with("file://") {
+                            text
+                        } else {
+                            percent_decode_str(&text).decode_utf8_lossy().into_owned()
+                        });
+                    }
+                    [.., parent, element] if parent == b"track" && element == b"meta" => {
+                        match meta.as_deref() {
+                            Some(rel) if rel == XSPF_BLOCK_REL.as_bytes() => {
+                                track.block = text.parse().ok();
+                            }
+                            Some(rel) if rel == XSPF_CUE_REL.as_bytes() => track.cue = Some(text),
+                            _ => (),
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok((name, listed))
+}
+
+/// A location from a playlist file in `folder` as a path relative to the library folder, which
+/// is how tracks know where they are. Locations can be relative or absolute paths, or file uris.
+fn library_path(library_folder: &Path, folder: &Path, location: &str) -> Option<String> {
+    let decoded;
+    let location = match location.strip_prefix("file://") {
+        Some(uri) => {
+            decoded = percent_decode_str(uri).decode_utf8().ok()?;
+            decoded.as_ref()
+        }
+        None => location,
+    };
+    // an absolute location replaces `folder`
+    let path = normalize(&folder.join(location));
+    let path = path.strip_prefix(library_folder).ok()?;
+    Some(path.to_string_lossy().into_owned())
+}
+
+/// the folder a file is in, which is `.` for a bare file name
+fn folder_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn full_path(path: &Path) -> Result<PathBuf> {
+    Ok(normalize(&absolute(path)?))
+}
+
+/// `path` with its `.` and `..` taken out, going by the path alone rather than the disk
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normal.pop() {
+                    normal.push(component);
+                }
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+/// The path to `to` from the folder `from`, both of them absolute. There's none when they
+/// don't share a root, like on different drives.
+fn path_from(from: &Path, to: &Path) -> Option<PathBuf> {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return None;
+    }
+    let mut path: PathBuf = from[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .collect();
+    path.extend(&to[common..]);
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::{playlist_with, temp_dir, track_at, write_wav};
+
+    /// the playlist's tracks by `label`, with blocks in brackets
+    fn names(playlist: &Arc<RwLock<Playlist>>, label: fn(&Track) -> &str) -> Vec<String> {
+        let playlist = playlist.read().unwrap();
+        let label = |track: &Arc<RwLock<Track>>| label(&track.read().unwrap()).to_string();
+        let mut tracks = Vec::new();
+        for item in playlist.items() {
+            match item {
+                PlaylistItem::Track(track) => tracks.push(label(track)),
+                PlaylistItem::Block(items) => {
+                    let block: Vec<String> = items
+                        .iter()
+                        .map(|item| match item {
+                            PlaylistItem::Track(track) => label(track),
+                            item => panic!("{item:?}"),
+                        })
+                        .collect();
+                    tracks.push(format!("({})", block.join(" ")));
+                }
+                PlaylistItem::Playlist(_) => panic!("nested playlist"),
+            }
+        }
+        tracks
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_playlist_file() {
+        let dir = temp_dir("playlist-files");
+        let music = dir.join("music");
+        fs::create_dir_all(music.join("album")).unwrap();
+        write_wav(&music.join("a.wav"), 44_100, 1, &[0; 64]);
+        write_wav(&music.join("album/b c.wav"), 44_100, 1, &[1; 64]);
+        write_wav(&music.join("album/d.wav"), 44_100, 1, &[2; 64]);
+        let mut library = MusicLibrary::new_from_path(music.to_str().unwrap()).unwrap();
+
+        let track = |path| PlaylistItem::Track(track_at(&library, path));
+        let items = [
+            track("a.wav"),
+            PlaylistItem::Block(vec![track("album/b c.wav"), track("album/d.wav")]),
+        ];
+        let uuid = playlist_with(&mut library, "set", items)
+            .read()
+            .unwrap()
+            .uuid();
+
+        // next to the library, not in it
+        let shared = dir.join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        let m3u = shared.join("set.m3u8");
+        export_playlist(&library, uuid, &m3u).unwrap();
+        let contents = fs::read_to_string(&m3u).unwrap();
+        let paths: Vec<&str> = contents
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "../music/a.wav",
+                "../music/album/b c.wav",
+                "../music/album/d.wav"
+            ]
+        );
+
+        let xspf = music.join("album/set.xspf");
+        export_playlist(&library, uuid, &xspf).unwrap();
+        let contents = fs::read_to_string(&xspf).unwrap();
+        assert!(
+            contents.contains("<location>../a.wav</location>"),
+            "{contents}"
+        );
+        assert!(
+            contents.contains("<location>b%20c.wav</location>"),
+            "{contents}"
+        );
+
+        // and they read back as the same tracks
+        let expected = ["a.wav", "(album/b c.wav album/d.wav)"];
+        for file in [m3u, xspf] {
+            let import = import_playlist(&mut library, &file).unwrap();
+            assert!(import.unresolved.is_empty(), "{:?}", import.unresolved);
+            assert_eq!(names(&import.playlist, |track| &track.path), expected);
+        }
+    }
+
+    #[test]
+    fn cue_tracks_keep_their_place_in_the_file() {
+        let dir = temp_dir("playlist-files-cue");
+        write_wav(&dir.join("a.wav"), 44_100, 1, &[0; 64]);
+        write_wav(&dir.join("mix.wav"), 44_100, 1, &vec![0; 44_100 * 3]);
+        let sheet = [
+            "FILE \"mix.wav\" WAVE",
+            "  TRACK 01 AUDIO",
+            "    TITLE \"One\"",
+            "    INDEX 01 00:00:00",
+            "  TRACK 02 AUDIO",
+            "    TITLE \"Two\"",
+            "    INDEX 01 00:01:00",
+            "  TRACK 03 AUDIO",
+            "    TITLE \"Three\"",
+            "    INDEX 01 00:02:00",
+        ];
+        fs::write(dir.join("mix.cue"), sheet.join("\n")).unwrap();
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+
+        // they all share mix.wav, so they're told apart by name
+        let track = |name: &str| {
+            let track = library
+                .get_tracks()
+                .iter()
+                .find(|track| &*track.read().unwrap().name == name);
+            PlaylistItem::Track(Arc::clone(track.unwrap()))
+        };
+        let items = [
+            track("a.wav"),
+            track("Three"),
+            track("One"),
+            PlaylistItem::Block(vec![track("Two"), track("Three")]),
+        ];
+        let uuid = playlist_with(&mut library, "set", items)
+            .read()
+            .unwrap()
+            .uuid();
+
+        let m3u = dir.join("set.m3u8");
+        export_playlist(&library, uuid, &m3u).unwrap();
+        let contents = fs::read_to_string(&m3u).unwrap();
+        let two = [
+            "#SEGUE-CUE:mix.cue#2",
+            "#EXTVLCOPT:start-time=1",
+            "#EXTVLCOPT:stop-time=2",
+            "mix.wav",
+        ];
+        assert!(contents.contains(&two.join("\n")), "{contents}");
+        let xspf = dir.join("set.xspf");
+        export_playlist(&library, uuid, &xspf).unwrap();
+
+        let expected = ["a.wav", "Three", "One", "(Two Three)"];
+        for file in [&m3u, &xspf] {
+            let import = import_playlist(&mut library, file).unwrap();
+            assert!(import.unresolved.is_empty(), "{:?}", import.unresolved);
+            assert_eq!(names(&import.playlist, |track| &track.name), expected);
+        }
+
+        // a player that only knows where each one starts
+        let vlc = contents
+            .lines()
+            .filter(|line| !line.starts_with(M3U_CUE))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&m3u, vlc).unwrap();
+        let import = import_playlist(&mut library, &m3u).unwrap();
+        assert_eq!(names(&import.playlist, |track| &track.name), expected);
+    }
+}
@@ -0,0 +1,687 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    music_library::{MusicLibrary, Playlist, PlaylistItem, Track, walk_playlist},
+    playback::{BlockEntry, PlaybackItem, PlaybackStatus, Transition},
+};
+
+/// where entry ids come from, so no two entries ever share one, even across queues
+static NEXT_ENTRY_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub track: Arc<RwLock<Track>>,
+    /// entries of the same block always sit next to each other in the queue
+    pub block: Option<usize>,
+    /// how to go into this entry from the one before it, the default if `None`
+    pub transition: Option<Transition>,
+    /// the top level playlist item this entry was expanded from
+    pub group: usize,
+
+    /// stays the same however the queue is shuffled or edited
+    id: u64,
+    /// position before any shuffling, no two entries share one
+    order: usize,
+}
+
+impl QueueEntry {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// What shuffling moves around. Blocks are never split or reordered inside in any mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    /// every track and every block on its own
+    Tracks,
+    /// blocks, and the runs of tracks between them
+    Blocks,
+    /// the top level items of the playlist, so nested playlists stay in order
+    Playlists,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+    /// loops the block being played, or plays on as normal outside of one
+    Block,
+}
+
+impl ShuffleMode {
+    /// the next one along, for cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Tracks,
+            Self::Tracks => Self::Blocks,
+            Self::Blocks => Self::Playlists,
+            Self::Playlists => Self::Off,
+        }
+    }
+}
+
+impl Display for ShuffleMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Off => "off",
+            Self::Tracks => "tracks",
+            Self::Blocks => "blocks",
+            Self::Playlists => "playlists",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl RepeatMode {
+    /// the next one along, for cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::All,
+            Self::All => Self::One,
+            Self::One => Self::Block,
+            Self::Block => Self::Off,
+        }
+    }
+}
+
+impl Display for RepeatMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Off => "off",
+            Self::One => "one",
+            Self::All => "all",
+            Self::Block => "block",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Track(usize),
+    Block(usize),
+    Run,
+    Group(usize),
+}
+
+/// A playlist expanded into the tracks it plays, in order.
+#[derive(Debug, Default)]
+pub struct Queue {
+    entries: Vec<QueueEntry>,
+    current: Option<usize>,
+    blocks: usize,
+    groups: usize,
+    shuffle: ShuffleMode,
+    /// the engine generation the last item `feed` handed out was for
+    fed: Option<u64>,
+
+    pub block_entry: BlockEntry,
+    pub repeat: RepeatMode,
+}
+
+impl Queue {
+    pub fn from_playlist(playlist: &Arc<RwLock<Playlist>>) -> Self {
+        let mut queue = Self::default();
+        queue.append_playlist(playlist, None);
+        queue.current = (!queue.entries.is_empty()).then_some(0);
+        queue
+    }
+
+    pub fn append_playlist(
+        &mut self,
+        playlist: &Arc<RwLock<Playlist>>,
+        transition: Option<Transition>,
+    ) {
+        let mut visited = HashSet::new();
+        let Ok(playlist) = playlist.read() else {
+            return;
+        };
+        visited.insert(playlist.uuid());
+
+        for (i, item) in playlist.items().iter().enumerate() {
+            let transition = if i == 0 {
+                transition
+            } else {
+                playlist.transition_after(i - 1)
+            };
+            self.expand_item(item, transition, &mut visited);
+            self.groups += 1;
+        }
+    }
+
+    fn expand_item(
+        &mut self,
+        item: &PlaylistItem,
+        transition: Option<Transition>,
+        visited: &mut HashSet<Uuid>,
+    ) {
+        match item {
+            PlaylistItem::Track(track) => self.push(QueueEntry {
+                track: Arc::clone(track),
+                block: None,
+                transition,
+                group: self.groups,
+                id: 0,
+                order: 0,
+            }),
+            PlaylistItem::Playlist(weak) => walk_playlist(weak, visited, |playlist, visited| {
+                for (i, item) in playlist.items().iter().enumerate() {
+                    let transition = if i == 0 {
+                        transition
+                    } else {
+                        playlist.transition_after(i - 1)
+                    };
+                    self.expand_item(item, transition, visited);
+                }
+            }),
+            PlaylistItem::Block(_) => {
+                let mut tracks = Vec::new();
+                item.collect_tracks(visited, &mut tracks);
+                if tracks.is_empty() {
+                    return;
+                }
+
+                let block = self.blocks;
+                self.blocks += 1;
+                for (i, track) in tracks.into_iter().enumerate() {
+                    self.push(QueueEntry {
+                        track,
+                        block: Some(block),
+                        transition: if i == 0 { transition } else { None },
+                        group: self.groups,
+                        id: 0,
+                        order: 0,
+                    });
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, mut entry: QueueEntry) {
+        entry.id = NEXT_ENTRY_ID.fetch_add(1, Ordering::Relaxed);
+        entry.order = self.entries.len();
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn current(&self) -> Option<&QueueEntry> {
+        self.entries.get(self.current?)
+    }
+
+    /// first entry of the block `index` is in, or `index` itself
+    fn block_start(&self, index: usize) -> usize {
+        let Some(block) = self.entries.get(index).and_then(|entry| entry.block) else {
+            return index;
+        };
+        let mut start = index;
+        while start > 0 && self.entries[start - 1].block == Some(block) {
+            start -= 1;
+        }
+        start
+    }
+
+    /// one past the last entry of the block `index` is in, or `index + 1`
+    fn block_end(&self, index: usize) -> usize {
+        let Some(block) = self.entries.get(index).and_then(|entry| entry.block) else {
+            return index + 1;
+        };
+        let mut end = index + 1;
+        while end < self.entries.len() && self.entries[end].block == Some(block) {
+            end += 1;
+        }
+        end
+    }
+
+    /// where skipping to `index` actually lands, given `block_entry`
+    fn landing(&self, index: usize) -> usize {
+        match self.block_entry {
+            BlockEntry::Start => self.block_start(index),
+            BlockEntry::Anywhere => index,
+        }
+    }
+
+    /// What plays once `index` ends by itself, following `repeat`.
+    pub fn following(&self, index: usize) -> Option<usize> {
+        let last = self.entries.len().checked_sub(1)?;
+        match self.repeat {
+            RepeatMode::Off => (index < last).then_some(index + 1),
+            RepeatMode::One => (index <= last).then_some(index),
+            RepeatMode::All => Some(if index < last { index + 1 } else { 0 }),
+            RepeatMode::Block => {
+                let next = index + 1;
+                if self.entries.get(index)?.block.is_some() && self.block_end(index) == next {
+                    Some(self.block_start(index))
+                } else {
+                    (index < last).then_some(next)
+                }
+            }
+        }
+    }
+
+    /// moves on as if the current entry ended by itself
+    pub fn advance(&mut self) -> Option<&QueueEntry> {
+        self.current = self.following(self.current?);
+        self.current()
+    }
+
+    /// skips to the next entry, only repeat all has any say here
+    pub fn next_entry(&mut self) -> Option<&QueueEntry> {
+        let next = match (self.current?, self.block_entry) {
+            (current, BlockEntry::Start) => self.block_end(current),
+            (current, BlockEntry::Anywhere) => current + 1,
+        };
+        if next < self.entries.len() {
+            self.current = Some(next);
+        } else if self.repeat == RepeatMode::All && !self.entries.is_empty() {
+            self.current = Some(0);
+        } else {
+            self.current = None;
+        }
+        self.current()
+    }
+
+    pub fn previous_entry(&mut self) -> Option<&QueueEntry> {
+        let current = self.current?;
+        let previous = match self.block_entry {
+            BlockEntry::Start => self.block_start(current).checked_sub(1),
+            BlockEntry::Anywhere => current.checked_sub(1),
+        };
+        self.current = Some(previous.map_or(0, |previous| self.landing(previous)));
+        self.current()
+    }
+
+    pub fn jump(&mut self, index: usize) -> Option<&QueueEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        self.current = Some(self.landing(index));
+        self.current()
+    }
+
+    /// Queues a track to play after the current one, or after the end of the current block so
+    /// the block isn't split. Returns where it went.
+    pub fn insert_next(&mut self, track: Arc<RwLock<Track>>) -> usize {
+        let index = self
+            .current
+            .map_or(self.entries.len(), |current| self.block_end(current));
+        // sorts right after the entry it follows when unshuffling, everything after that moves
+        // up one
+        let order = index
+            .checked_sub(1)
+            .and_then(|previous| self.entries.get(previous))
+            .map_or(0, |previous| previous.order + 1);
+        for entry in &mut self.entries {
+            if entry.order >= order {
+                entry.order += 1;
+            }
+        }
+        self.entries.insert(
+            index,
+            QueueEntry {
+                track,
+                block: None,
+                transition: None,
+                group: self.groups,
+                id: NEXT_ENTRY_ID.fetch_add(1, Ordering::Relaxed),
+                order,
+            },
+        );
+        self.groups += 1;
+        if self.current.is_none() {
+            self.current = Some(index);
+        }
+        index
+    }
+
+    /// Removes an entry. Removing the current one moves on to whatever came after it.
+    pub fn remove(&mut self, index: usize) -> Option<QueueEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        let entry = self.entries.remove(index);
+        if let Some(current) = self.current {
+            if index < current {
+                self.current = Some(current - 1);
+            } else if current >= self.entries.len() {
+                self.current = None;
+            }
+        }
+        Some(entry)
+    }
+
+    pub fn shuffle_mode(&self) -> ShuffleMode {
+        self.shuffle
+    }
+
+    /// Shuffles everything but the current entry, which moves to the front along with the rest
+    /// of its unit. The same seed always gives the same order.
+    pub fn shuffle(&mut self, mode: ShuffleMode, seed: u64) {
+        self.unshuffle();
+        self.shuffle = mode;
+        if mode == ShuffleMode::Off {
+            return;
+        }
+
+        let current_id = self.current().map(QueueEntry::id);
+        let mut units: Vec<Vec<QueueEntry>> = Vec::new();
+        let mut previous_unit = None;
+        for (i, entry) in std::mem::take(&mut self.entries).into_iter().enumerate() {
+            let unit = match (mode, entry.block) {
+                (ShuffleMode::Playlists, _) => Unit::Group(entry.group),
+                (_, Some(block)) => Unit::Block(block),
+                (ShuffleMode::Blocks, None) => Unit::Run,
+                _ => Unit::Track(i),
+            };
+            match units.last_mut() {
+                Some(last) if previous_unit == Some(unit) => last.push(entry),
+                _ => units.push(vec![entry]),
+            }
+            previous_unit = Some(unit);
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let current_unit = current_id.and_then(|id| {
+            units
+                .iter()
+                .position(|unit| unit.iter().any(|entry| entry.id == id))
+        });
+        if let Some(current_unit) = current_unit {
+            units.swap(0, current_unit);
+            units[1..].shuffle(&mut rng);
+        } else {
+            units.shuffle(&mut rng);
+        }
+
+        self.entries = units.into_iter().flatten().collect();
+        self.current = current_id.and_then(|id| self.position_of(id));
+    }
+
+    fn unshuffle(&mut self) {
+        let current_id = self.current().map(QueueEntry::id);
+        self.entries.sort_by_key(|entry| entry.order);
+        self.current = current_id.and_then(|id| self.position_of(id));
+        self.shuffle = ShuffleMode::Off;
+    }
+
+    /// where the entry with this id is now
+    pub fn position_of(&self, id: u64) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.id == id)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.current = None;
+    }
+
+    pub fn playback_item(&self, library: &MusicLibrary, index: usize) -> Option<PlaybackItem> {
+        let entry = self.entries.get(index)?;
+        let track = entry.track.read().ok()?;
+        let mut item = PlaybackItem::new(library, &track);
+        item.transition = entry.transition;
+        item.block = entry.block.map(|block| block as u64);
+        item.queue_entry = Some(entry.id);
+        Some(item)
+    }
+
+    /// The current entry and the ones straight after it, up to where repeating would jump back,
+    /// ready to hand to `PlaybackEngine::play_all`. The rest comes from `feed`.
+    pub fn playback_items(&self, library: &MusicLibrary) -> Vec<PlaybackItem> {
+        let mut items = Vec::new();
+        let mut index = self.current;
+        while let Some(current) = index {
+            items.extend(self.playback_item(library, current));
+            index = self.following(current).filter(|next| *next == current + 1);
+        }
+        items
+    }
+
+    /// Once the engine has nothing left after what it's playing, this is what it should get
+    /// next, so repeats and wrap arounds still go straight in. It's only handed out once for
+    /// each item the engine plays, it'll be a moment before the engine's status shows it.
+    pub fn feed(
+        &mut self,
+        library: &MusicLibrary,
+        status: &PlaybackStatus,
+    ) -> Option<PlaybackItem> {
+        if !status.queue.is_empty() || self.fed == Some(status.generation) {
+            return None;
+        }
+        let playing = self.position_of(status.item.as_ref()?.queue_entry?)?;
+        let item = self.playback_item(library, self.following(playing)?)?;
+        self.fed = Some(status.generation);
+        Some(item)
+    }
+
+    /// moves the current entry along with what the engine is playing
+    pub fn sync(&mut self, status: &PlaybackStatus) {
+        if let Some(index) = status
+            .item
+            .as_ref()
+            .and_then(|item| item.queue_entry)
+            .and_then(|id| self.position_of(id))
+        {
+            self.current = Some(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        music_library::stored::{StoredItem, StoredLibrary},
+        test_util::{playlist_with, track_at, wav_library},
+    };
+
+    /// a library of tracks named a to k, and y and z
+    fn library(test: &str) -> MusicLibrary {
+        let paths = [
+            "a.wav", "b.wav", "c.wav", "d.wav", "e.wav", "f.wav", "g.wav", "h.wav", "i.wav",
+            "j.wav", "k.wav", "y.wav", "z.wav",
+        ];
+        wav_library(test, &paths)
+    }
+
+    fn track(library: &MusicLibrary, name: &str) -> Arc<RwLock<Track>> {
+        track_at(library, &format!("{name}.wav"))
+    }
+
+    /// a, b, then a block of c d e, then f to i, then a block of j k
+    fn queue(library: &mut MusicLibrary) -> Queue {
+        let track = |name| PlaylistItem::Track(track(library, name));
+        let items = [
+            track("a"),
+            track("b"),
+            PlaylistItem::Block(vec![track("c"), track("d"), track("e")]),
+            track("f"),
+            track("g"),
+            track("h"),
+            track("i"),
+            PlaylistItem::Block(vec![track("j"), track("k")]),
+        ];
+        Queue::from_playlist(&playlist_with(library, "queue", items))
+    }
+
+    fn names(queue: &Queue) -> String {
+        queue
+            .entries()
+            .iter()
+            .map(|entry| entry.track.read().unwrap().name.replace(".wav", ""))
+            .collect()
+    }
+
+    fn current_name(queue: &Queue) -> Option<String> {
+        Some(
+            queue
+                .current()?
+                .track
+                .read()
+                .unwrap()
+                .name
+                .replace(".wav", ""),
+        )
+    }
+
+    #[test]
+    fn shuffle_is_seeded() {
+        let mut library = library("shuffle_is_seeded");
+        let (mut first, mut second) = (queue(&mut library), queue(&mut library));
+        first.jump(3);
+        second.jump(3);
+
+        first.shuffle(ShuffleMode::Tracks, 7);
+        second.shuffle(ShuffleMode::Tracks, 7);
+        let shuffled = names(&first);
+        assert_eq!(shuffled, names(&second));
+        assert_ne!(shuffled, "abcdefghijk");
+        // the current entry's block leads, and blocks stay whole and in order
+        assert!(shuffled.starts_with("cde"));
+        assert!(shuffled.contains("jk"));
+        assert_eq!(current_name(&first).as_deref(), Some("c"));
+
+        second.shuffle(ShuffleMode::Tracks, 8);
+        assert_ne!(names(&second), shuffled);
+
+        first.shuffle(ShuffleMode::Off, 7);
+        assert_eq!(names(&first), "abcdefghijk");
+        assert_eq!(current_name(&first).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn inserted_entries_keep_their_place() {
+        let mut library = library("inserted_entries_keep_their_place");
+        let mut queue = queue(&mut library);
+        // play it out, so nothing is current
+        while queue.next_entry().is_some() {}
+
+        queue.insert_next(track(&library, "y"));
+        queue.insert_next(track(&library, "z"));
+        assert_eq!(names(&queue), "abcdefghijkyz");
+        assert_eq!(current_name(&queue).as_deref(), Some("y"));
+
+        queue.shuffle(ShuffleMode::Tracks, 3);
+        assert_eq!(current_name(&queue).as_deref(), Some("y"));
+        queue.shuffle(ShuffleMode::Off, 3);
+        assert_eq!(names(&queue), "abcdefghijkyz");
+        assert_eq!(current_name(&queue).as_deref(), Some("y"));
+
+        let ids: HashSet<u64> = queue.entries().iter().map(QueueEntry::id).collect();
+        assert_eq!(ids.len(), queue.len());
+    }
+
+    #[test]
+    fn sync_follows_the_entry() {
+        let mut library = library("sync_follows_the_entry");
+        let mut queue = queue(&mut library);
+        let status = PlaybackStatus {
+            item: queue.playback_item(&library, 5),
+            ..Default::default()
+        };
+
+        queue.shuffle(ShuffleMode::Tracks, 11);
+        queue.remove(0);
+        queue.sync(&status);
+        assert_eq!(current_name(&queue).as_deref(), Some("f"));
+
+        // and feeds what follows it once per item the engine plays
+        let next = queue.current_index().unwrap() + 1;
+        let next_name = queue.entries()[next].track.read().unwrap().name.clone();
+        let fed = queue.feed(&library, &status).unwrap();
+        assert_eq!(fed.name, next_name);
+        assert!(queue.feed(&library, &status).is_none());
+    }
+
+    #[test]
+    fn nested_playlists_are_expanded_in_place() {
+        let mut library = library("nested_playlists_are_expanded_in_place");
+        let track = |name| PlaylistItem::Track(track(&library, name));
+        let (a, b, c, d, e, f) = (
+            track("a"),
+            track("b"),
+            track("c"),
+            track("d"),
+            track("e"),
+            track("f"),
+        );
+        let inner = playlist_with(
+            &mut library,
+            "inner",
+            [b, PlaylistItem::Block(vec![c, d]), e],
+        );
+        let nested = PlaylistItem::Playlist(Arc::downgrade(&inner));
+        let outer = playlist_with(&mut library, "outer", [a, nested, f]);
+
+        let queue = Queue::from_playlist(&outer);
+        assert_eq!(names(&queue), "abcdef");
+        let blocks: Vec<_> = queue.entries().iter().map(|entry| entry.block).collect();
+        assert_eq!(blocks, [None, None, Some(0), Some(0), None, None]);
+        // the nested playlist is one item of the outer one
+        let groups: Vec<_> = queue.entries().iter().map(|entry| entry.group).collect();
+        assert_eq!(groups, [0, 1, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn playlists_inside_themselves_are_played_once() {
+        let mut library = library("playlists_inside_themselves_are_played_once");
+        let track = |name| PlaylistItem::Track(track(&library, name));
+        let (a, b, c, d) = (track("a"), track("b"), track("c"), track("d"));
+        let inner = playlist_with(&mut library, "inner", [b, PlaylistItem::Block(vec![c, d])]);
+        let nested = PlaylistItem::Playlist(Arc::downgrade(&inner));
+        let outer = playlist_with(&mut library, "outer", [a, nested]);
+        let inner_uuid = inner.read().unwrap().uuid();
+        let outer_uuid = outer.read().unwrap().uuid();
+        let cycle = PlaylistItem::Playlist(Arc::downgrade(&outer));
+        assert!(library.insert_item(inner_uuid, &[2], cycle).is_err());
+
+        // a library file can still have one, inner holds outer at its end and in its block
+        let mut stored = StoredLibrary::from(&library);
+        let inner = stored
+            .playlists
+            .iter_mut()
+            .find(|playlist| playlist.uuid == inner_uuid)
+            .unwrap();
+        inner.items.push(StoredItem::Playlist(outer_uuid));
+        let StoredItem::Block(block) = &mut inner.items[1] else {
+            panic!("the block moved");
+        };
+        block.push(StoredItem::Playlist(outer_uuid));
+        let library = MusicLibrary::from(stored);
+
+        let outer = library.playlist(outer_uuid).unwrap();
+        assert_eq!(names(&Queue::from_playlist(&outer)), "abcd");
+        // outer is there twice over, but never goes back into inner
+        let inner = library.playlist(inner_uuid).unwrap();
+        assert_eq!(names(&Queue::from_playlist(&inner)), "bcdaa");
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use color_eyre::{Report, Result};
+
+use crate::{analysis::Key, music_library::Track};
+
+/// A search over the tracks, as typed. Words match anywhere in a track's name, artist, album
+/// or tags. `key:8A` or `key:Am` only keeps tracks in that key, and `key:8A~` tracks in keys
+/// that mix well with it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackQuery {
+    words: Vec<String>,
+    keys: Vec<KeyFilter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyFilter {
+    Exactly(Key),
+    CompatibleWith(Key),
+}
+
+impl FromStr for TrackQuery {
+    type Err = Report;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut query = Self::default();
+        for term in text.split_whitespace() {
+            let Some(key) = term.strip_prefix("key:") else {
+                query.words.push(term.to_lowercase());
+                continue;
+            };
+            query.keys.push(match key.strip_suffix('~') {
+                Some(key) => KeyFilter::CompatibleWith(key.parse()?),
+                None => KeyFilter::Exactly(key.parse()?),
+            });
+        }
+        Ok(query)
+    }
+}
+
+impl TrackQuery {
+    /// whether the track fits every part of the query
+    pub fn matches(&self, track: &Track) -> bool {
+        let keys = self.keys.iter().all(|filter| match (filter, track.key) {
+            (KeyFilter::Exactly(key), Some(track_key)) => *key == track_key,
+            (KeyFilter::CompatibleWith(key), Some(track_key)) => key.is_compatible(&track_key),
+            (_, None) => false,
+        });
+        keys && self.words.iter().all(|word| {
+            let found = |text: &str| text.to_lowercase().contains(word.as_str());
+            found(&track.name)
+                || track.artist.as_deref().is_some_and(found)
+                || track.album.as_deref().is_some_and(found)
+                || track.tags.iter().any(|tag| found(tag))
+        })
+    }
+}
+
+/// What the track list is in order of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrackSort {
+    /// the order they were added in
+    #[default]
+    Library,
+    Name,
+    Artist,
+    Bpm,
+    /// around the Camelot wheel, so keys that mix well end up together
+    Key,
+}
+
+impl TrackSort {
+    /// the next one along, for cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Library => Self::Name,
+            Self::Name => Self::Artist,
+            Self::Artist => Self::Bpm,
+            Self::Bpm => Self::Key,
+            Self::Key => Self::Library,
+        }
+    }
+
+    fn compare(self, a: &Track, b: &Track) -> Ordering {
+        // tracks without the value go last
+        fn missing_last<T>(
+            a: Option<T>,
+            b: Option<T>,
+            compare: impl Fn(T, T) -> Ordering,
+        ) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => compare(a, b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        }
+        match self {
+            Self::Library => Ordering::Equal,
+            Self::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            Self::Artist => missing_last(a.artist.as_deref(), b.artist.as_deref(), |a, b| {
+                a.to_lowercase().cmp(&b.to_lowercase())
+            }),
+            Self::Bpm => missing_last(a.bpm, b.bpm, |a, b| a.total_cmp(&b)),
+            Self::Key => missing_last(a.key, b.key, |a, b| {
+                a.wheel_position().cmp(&b.wheel_position())
+            }),
+        }
+    }
+}
+
+impl Display for TrackSort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Library => "library order",
+            Self::Name => "name",
+            Self::Artist => "artist",
+            Self::Bpm => "bpm",
+            Self::Key => "key",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// The tracks that match `query`, in `sort` order. Ties keep the order they came in.
+pub fn search(
+    tracks: &[Arc<RwLock<Track>>],
+    query: &TrackQuery,
+    sort: TrackSort,
+) -> Vec<Arc<RwLock<Track>>> {
+    let mut found: Vec<Arc<RwLock<Track>>> = tracks
+        .iter()
+        .filter(|track| track.read().is_ok_and(|track| query.matches(&track)))
+        .cloned()
+        .collect();
+    if sort != TrackSort::Library {
+        found.sort_by(|a, b| match (a.read(), b.read()) {
+            (Ok(a), Ok(b)) => sort.compare(&a, &b),
+            _ => Ordering::Equal,
+        });
+    }
+    found
+}
@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+use color_eyre::Result;
+
+use crate::music_library::MusicLibrary;
+
+mod json;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+/// Where a library is kept between runs.
+pub trait Storage: Debug + Send {
+    /// reads the library back, or `None` if nothing has been saved yet
+    fn load(&mut self) -> Result<Option<MusicLibrary>>;
+
+    /// Saves the library. Saving it again without changes shouldn't write anything.
+    fn save(&mut self, library: &MusicLibrary) -> Result<()>;
+}
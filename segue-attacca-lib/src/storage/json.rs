@@ -0,0 +1,120 @@
+use std::{
+    fs::{File, copy, rename},
+    io::{BufReader, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+
+use super::Storage;
+use crate::{
+    files::write_atomically,
+    migrations,
+    music_library::{BACKUPS, LIBRARY_FILE, MusicLibrary},
+};
+
+/// The whole library in one pretty printed json file, rewritten on every save.
+#[derive(Debug)]
+pub struct JsonStorage {
+    path: PathBuf,
+    /// what was last saved, so saving again without changes doesn't churn the backups
+    saved: Option<blake3::Hash>,
+}
+
+impl JsonStorage {
+    /// keeps the library in `music_library.json` in `folder`
+    pub fn new(folder: &Path) -> Self {
+        Self {
+            path: folder.join(LIBRARY_FILE),
+            saved: None,
+        }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&mut self) -> Result<Option<MusicLibrary>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut library = serde_json::from_reader(BufReader::new(file))?;
+        migrations::migrate(&mut library)?;
+        let library: MusicLibrary = serde_json::from_value(library)?;
+        // what's on disk already counts as saved, or the first save of every run would push
+        // an identical copy into the backups
+        self.saved = Some(blake3::hash(&serde_json::to_vec_pretty(&library)?));
+        Ok(Some(library))
+    }
+
+    /// The new file is written next to the old one and renamed over it, and the old one moves
+    /// into the backups.
+    fn save(&mut self, library: &MusicLibrary) -> Result<()> {
+        let json = serde_json::to_vec_pretty(library)?;
+        let hash = blake3::hash(&json);
+        // unless the file's been deleted from under us since
+        if self.saved == Some(hash) && self.path.exists() {
+            return Ok(());
+        }
+
+        if self.path.exists() {
+            rotate_backups(&self.path)?;
+        }
+        write_atomically(&self.path, &json)?;
+        self.saved = Some(hash);
+        Ok(())
+    }
+}
+
+/// `music_library.json.2` becomes `.3` and so on, the oldest falls off, and a copy of the
+/// current file becomes `.1`. The current file stays put until the new one replaces it.
+fn rotate_backups(path: &Path) -> Result<()> {
+    let backup = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    };
+    for n in (1..BACKUPS).rev() {
+        let from = backup(n);
+        if from.exists() {
+            rename(&from, backup(n + 1))?;
+        }
+    }
+    copy(path, backup(1))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::{temp_dir, write_wav};
+
+    #[test]
+    fn unchanged_libraries_dont_churn_the_backups() {
+        let dir = temp_dir("json-backups");
+        write_wav(&dir.join("a.wav"), 44_100, 1, &[0; 64]);
+        let backup = dir.join(format!("{LIBRARY_FILE}.1"));
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        library.save().unwrap();
+        drop(library);
+
+        for _ in 0..=BACKUPS {
+            let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+            library.save().unwrap();
+        }
+        assert!(!backup.exists());
+
+        let before = fs::read(dir.join(LIBRARY_FILE)).unwrap();
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        library.create_playlist("changed");
+        library.save().unwrap();
+        assert_eq!(fs::read(&backup).unwrap(), before);
+
+        // an unchanged library is still written if its file has gone
+        fs::remove_file(dir.join(LIBRARY_FILE)).unwrap();
+        library.save().unwrap();
+        assert!(dir.join(LIBRARY_FILE).exists());
+    }
+}
@@ -0,0 +1,425 @@
+use std::{collections::HashMap, path::Path};
+
+use color_eyre::{Result, eyre::bail};
+use rusqlite::{Connection, params};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use super::Storage;
+use crate::{
+    migrations,
+    music_library::{MusicLibrary, stored::StoredLibrary},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS library (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tracks (
+        uuid TEXT PRIMARY KEY,
+        path TEXT NOT NULL,
+        artist TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tracks_path ON tracks (path);
+    CREATE INDEX IF NOT EXISTS tracks_artist ON tracks (artist);
+    CREATE TABLE IF NOT EXISTS track_tags (
+        track TEXT NOT NULL REFERENCES tracks (uuid) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (track, tag)
+    );
+    CREATE INDEX IF NOT EXISTS track_tags_tag ON track_tags (tag);
+    CREATE TABLE IF NOT EXISTS playlists (
+        uuid TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS analysis (
+        hash TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+";
+
+/// everything in a library besides its tracks and playlists, a row each
+const LIBRARY_KEYS: [&str; 4] = ["version", "path", "tags", "playback"];
+
+/// The library in an SQLite database, one row per track, playlist and analysed file. Saving only
+/// writes the rows that changed since the last load or save, which keeps big libraries quick to
+/// save, and tracks can be looked up by path, artist or tag without loading the lot.
+///
+/// Rows hold the same json as the json file does, so the two share `migrations`.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    connection: Connection,
+    /// hashes of the rows as they are in the database
+    saved: Saved,
+}
+
+#[derive(Debug, Default)]
+struct Saved {
+    library: HashMap<&'static str, blake3::Hash>,
+    tracks: HashMap<Uuid, blake3::Hash>,
+    playlists: HashMap<Uuid, blake3::Hash>,
+    analysis: HashMap<Box<str>, blake3::Hash>,
+}
+
+impl SqliteStorage {
+    /// opens the database at `path`, creating it if it isn't there
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection,
+            saved: Saved::default(),
+        })
+    }
+
+    /// the track saved at `path` in the library folder, if there is one
+    pub fn track_at(&self, path: &str) -> Result<Option<Uuid>> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT uuid FROM tracks WHERE path = ?1")?;
+        let mut rows = statement.query([path])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get::<_, String>(0)?.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// the saved tracks by `artist`
+    pub fn tracks_by(&self, artist: &str) -> Result<Vec<Uuid>> {
+        self.uuids(
+            "SELECT uuid FROM tracks WHERE artist = ?1 ORDER BY rowid",
+            artist,
+        )
+    }
+
+    /// the saved tracks tagged `tag`
+    pub fn tracks_tagged(&self, tag: &str) -> Result<Vec<Uuid>> {
+        self.uuids("SELECT track FROM track_tags WHERE tag = ?1", tag)
+    }
+
+    fn uuids(&self, query: &str, argument: &str) -> Result<Vec<Uuid>> {
+        let mut statement = self.connection.prepare_cached(query)?;
+        let mut rows = statement.query([argument])?;
+        let mut uuids = Vec::new();
+        while let Some(row) = rows.next()? {
+            uuids.push(row.get::<_, String>(0)?.parse()?);
+        }
+        Ok(uuids)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<Option<MusicLibrary>> {
+        let mut saved = Saved::default();
+        let mut library = Map::new();
+
+        let mut statement = self.connection.prepare("SELECT key, value FROM library")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: String = row.get(0)?;
+            let value: String = row.get(1)?;
+            // anything we don't know of is left for the migrations to deal with
+            if let Some(key) = LIBRARY_KEYS.iter().find(|known| **known == key) {
+                saved.library.insert(key, blake3::hash(value.as_bytes()));
+            }
+            library.insert(key, serde_json::from_str(&value)?);
+        }
+        if library.is_empty() {
+            return Ok(None);
+        }
+
+        let mut tracks = Vec::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT uuid, data FROM tracks ORDER BY rowid")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let uuid: String = row.get(0)?;
+            let data: String = row.get(1)?;
+            saved
+                .tracks
+                .insert(uuid.parse()?, blake3::hash(data.as_bytes()));
+            tracks.push(serde_json::from_str(&data)?);
+        }
+
+        let mut playlists = Vec::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT uuid, position, data FROM playlists ORDER BY position")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let uuid: String = row.get(0)?;
+            let position: usize = row.get(1)?;
+            let data: String = row.get(2)?;
+            saved
+                .playlists
+                .insert(uuid.parse()?, playlist_hash(position, &data));
+            playlists.push(serde_json::from_str(&data)?);
+        }
+
+        let mut analysis = Map::new();
+        let mut statement = self.connection.prepare("SELECT hash, data FROM analysis")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let hash: String = row.get(0)?;
+            let data: String = row.get(1)?;
+            saved
+                .analysis
+                .insert(hash.as_str().into(), blake3::hash(data.as_bytes()));
+            analysis.insert(hash, serde_json::from_str(&data)?);
+        }
+
+        library.insert("tracks".into(), Value::Array(tracks));
+        library.insert("playlists".into(), Value::Array(playlists));
+        library.insert("analysis".into(), Value::Object(analysis));
+        let mut library = Value::Object(library);
+        migrations::migrate(&mut library)?;
+        let library = serde_json::from_value(library)?;
+
+        self.saved = saved;
+        Ok(Some(library))
+    }
+
+    fn save(&mut self, library: &MusicLibrary) -> Result<()> {
+        let stored = StoredLibrary::from(library);
+        let mut saved = Saved::default();
+        let transaction = self.connection.transaction()?;
+
+        let values = [
+            serde_json::to_string(&stored.version)?,
+            serde_json::to_string(&stored.path)?,
+            serde_json::to_string(&stored.tags)?,
+            serde_json::to_string(&stored.playback)?,
+        ];
+        for (key, value) in LIBRARY_KEYS.into_iter().zip(values) {
+            let hash = blake3::hash(value.as_bytes());
+            if self.saved.library.get(key) != Some(&hash) {
+                transaction.execute(
+                    "INSERT INTO library (key, value) VALUES (?1, ?2)
+                     ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                    params![key, value],
+                )?;
+            }
+            saved.library.insert(key, hash);
+        }
+
+        for lock in &stored.tracks {
+            let Ok(track) = lock.read() else {
+                bail!("couldn't read track");
+            };
+            let data = serde_json::to_string(&*track)?;
+            let hash = blake3::hash(data.as_bytes());
+            if self.saved.tracks.get(&track.uuid) != Some(&hash) {
+                let uuid = track.uuid.to_string();
+                transaction.execute(
+                    "INSERT INTO tracks (uuid, path, artist, data) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (uuid) DO UPDATE
+                     SET path = excluded.path, artist = excluded.artist, data = excluded.data",
+                    params![uuid, track.path, track.artist.as_deref(), data],
+                )?;
+                transaction.execute("DELETE FROM track_tags WHERE track = ?1", [&uuid])?;
+                for tag in &track.tags {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO track_tags (track, tag) VALUES (?1, ?2)",
+                        params![uuid, tag.as_ref()],
+                    )?;
+                }
+            }
+            saved.tracks.insert(track.uuid, hash);
+        }
+
+        for (position, playlist) in stored.playlists.iter().enumerate() {
+            let data = serde_json::to_string(playlist)?;
+            let hash = playlist_hash(position, &data);
+            if self.saved.playlists.get(&playlist.uuid) != Some(&hash) {
+                transaction.execute(
+                    "INSERT INTO playlists (uuid, position, data) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (uuid) DO UPDATE
+                     SET position = excluded.position, data = excluded.data",
+                    params![playlist.uuid.to_string(), position, data],
+                )?;
+            }
+            saved.playlists.insert(playlist.uuid, hash);
+        }
+
+        for (hash, analysis) in &stored.analysis {
+            let data = serde_json::to_string(analysis)?;
+            let data_hash = blake3::hash(data.as_bytes());
+            if self.saved.analysis.get(hash) != Some(&data_hash) {
+                transaction.execute(
+                    "INSERT INTO analysis (hash, data) VALUES (?1, ?2)
+                     ON CONFLICT (hash) DO UPDATE SET data = excluded.data",
+                    params![hash, data],
+                )?;
+            }
+            saved.analysis.insert(hash.clone(), data_hash);
+        }
+
+        for uuid in self.saved.tracks.keys() {
+            if !saved.tracks.contains_key(uuid) {
+                transaction.execute("DELETE FROM tracks WHERE uuid = ?1", [uuid.to_string()])?;
+            }
+        }
+        for uuid in self.saved.playlists.keys() {
+            if !saved.playlists.contains_key(uuid) {
+                transaction.execute("DELETE FROM playlists WHERE uuid = ?1", [uuid.to_string()])?;
+            }
+        }
+        for hash in self.saved.analysis.keys() {
+            if !saved.analysis.contains_key(hash) {
+                transaction.execute("DELETE FROM analysis WHERE hash = ?1", [hash])?;
+            }
+        }
+
+        transaction.commit()?;
+        self.saved = saved;
+        Ok(())
+    }
+}
+
+fn playlist_hash(position: usize, data: &str) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&position.to_le_bytes());
+    hasher.update(data.as_bytes());
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        music_library::{LIBRARY_DB, LIBRARY_FILE},
+        test_util::{sine, temp_dir, track_at, write_wav},
+    };
+
+    /// the v1 fixture as the current version, not saved anywhere yet
+    fn library() -> MusicLibrary {
+        let mut library: Value =
+            serde_json::from_str(include_str!("../../fixtures/library_v1.json")).unwrap();
+        migrations::migrate(&mut library).unwrap();
+        serde_json::from_value(library).unwrap()
+    }
+
+    fn uuid(library: &MusicLibrary, path: &str) -> Uuid {
+        track_at(library, path).read().unwrap().uuid
+    }
+
+    fn json(library: &MusicLibrary) -> Value {
+        serde_json::to_value(library).unwrap()
+    }
+
+    #[test]
+    fn libraries_load_as_they_were_saved() {
+        let db = temp_dir("sqlite-round-trip").join(LIBRARY_DB);
+        let library = library();
+        SqliteStorage::open(&db).unwrap().save(&library).unwrap();
+
+        let loaded = SqliteStorage::open(&db).unwrap().load().unwrap().unwrap();
+        assert_eq!(json(&loaded), json(&library));
+        // an empty database has no library in it rather than an empty one
+        let empty = temp_dir("sqlite-empty").join(LIBRARY_DB);
+        assert!(
+            SqliteStorage::open(&empty)
+                .unwrap()
+                .load()
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn saves_only_write_what_changed() {
+        let db = temp_dir("sqlite-incremental").join(LIBRARY_DB);
+        let mut storage = SqliteStorage::open(&db).unwrap();
+        let library = library();
+        storage.save(&library).unwrap();
+
+        let before = storage.connection.total_changes();
+        storage.save(&library).unwrap();
+        assert_eq!(storage.connection.total_changes(), before);
+
+        track_at(&library, "a.wav").write().unwrap().name = "Renamed".into();
+        storage.save(&library).unwrap();
+        assert_eq!(storage.connection.total_changes(), before + 1);
+
+        let set = library.playlists()[0].read().unwrap().uuid();
+        library.rename_playlist(set, "Opening").unwrap();
+        storage.save(&library).unwrap();
+        assert_eq!(storage.connection.total_changes(), before + 2);
+
+        let loaded = SqliteStorage::open(&db).unwrap().load().unwrap().unwrap();
+        assert_eq!(json(&loaded), json(&library));
+        assert_eq!(&*track_at(&loaded, "a.wav").read().unwrap().name, "Renamed");
+    }
+
+    #[test]
+    fn tracks_are_looked_up_through_the_indexes() {
+        let db = temp_dir("sqlite-indexes").join(LIBRARY_DB);
+        let mut storage = SqliteStorage::open(&db).unwrap();
+        let library = library();
+        storage.save(&library).unwrap();
+        let (a, b) = (uuid(&library, "a.wav"), uuid(&library, "b.wav"));
+
+        assert_eq!(storage.track_at("b.wav").unwrap(), Some(b));
+        assert_eq!(storage.track_at("c.wav").unwrap(), None);
+        assert_eq!(storage.tracks_by("Someone").unwrap(), [a, b]);
+        assert!(storage.tracks_by("No one").unwrap().is_empty());
+        assert_eq!(storage.tracks_tagged("late").unwrap(), [b]);
+
+        for (query, index) in [
+            (
+                "SELECT uuid FROM tracks WHERE path = 'a.wav'",
+                "tracks_path",
+            ),
+            (
+                "SELECT uuid FROM tracks WHERE artist = 'Someone' ORDER BY rowid",
+                "tracks_artist",
+            ),
+            (
+                "SELECT track FROM track_tags WHERE tag = 'late'",
+                "track_tags_tag",
+            ),
+        ] {
+            let plan: Vec<String> = storage
+                .connection
+                .prepare(&format!("EXPLAIN QUERY PLAN {query}"))
+                .unwrap()
+                .query_map([], |row| row.get(3))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert!(plan.iter().any(|step| step.contains(index)), "{plan:?}");
+        }
+    }
+
+    #[test]
+    fn json_libraries_move_into_sqlite() {
+        let dir = temp_dir("sqlite-move");
+        for name in ["a.wav", "b.wav"] {
+            write_wav(&dir.join(name), 44_100, 2, &sine(44_100, 0.1, 440.0));
+        }
+        fs::write(
+            dir.join(LIBRARY_FILE),
+            include_str!("../../fixtures/library_v1.json"),
+        )
+        .unwrap();
+
+        let mut library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        library.move_to_sqlite().unwrap();
+        // a second move would lose whatever is in the database
+        assert!(library.move_to_sqlite().is_err());
+
+        // and it's the database the library is loaded from now
+        fs::remove_file(dir.join(LIBRARY_FILE)).unwrap();
+        let moved = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        assert_eq!(json(&moved), json(&library));
+        assert_eq!(moved.playlists().len(), 2);
+        assert_eq!(track_at(&moved, "b.wav").read().unwrap().tags.len(), 1);
+    }
+}
@@ -0,0 +1,598 @@
+use std::{
+    fs::{File, copy, read, remove_file, rename},
+    path::Path,
+};
+
+use color_eyre::{Result, eyre::bail};
+use id3::{TagLike, frame::ExtendedText};
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey, StandardVisualKey},
+    probe::Hint,
+};
+
+use crate::{
+    analysis::ReplayGain,
+    files::{temp_path, write_atomically},
+    music_library::{Track, TrackField},
+};
+
+/// What the tags embedded in an audio file say about it. ID3v2, Vorbis comments and RIFF INFO
+/// all end up here.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EmbeddedTags {
+    pub title: Option<Box<str>>,
+    pub artist: Option<Box<str>>,
+    pub album: Option<Box<str>>,
+    pub album_artist: Option<Box<str>>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<Box<str>>,
+    pub cover: Option<EmbeddedPicture>,
+    pub replay_gain: ReplayGain,
+}
+
+/// A picture from an ID3v2 APIC frame or a FLAC PICTURE block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedPicture {
+    pub media_type: Box<str>,
+    pub data: Box<[u8]>,
+}
+
+impl EmbeddedTags {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+
+        let mut tags = Self::default();
+        // tags in the container itself, like vorbis comments, win over ones found in front of
+        // it, like an ID3v2 tag on a flac file
+        if let Some(revision) = probed.format.metadata().current() {
+            tags.fill(revision);
+        }
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            tags.fill(revision);
+        }
+        Ok(tags)
+    }
+
+    /// takes whatever this doesn't have yet from `revision`
+    fn fill(&mut self, revision: &MetadataRevision) {
+        if self.cover.is_none() {
+            let visuals = revision.visuals();
+            let front = visuals
+                .iter()
+                .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+                .or(visuals.first());
+            self.cover = front.map(|visual| EmbeddedPicture {
+                media_type: visual.media_type.as_str().into(),
+                data: visual.data.clone(),
+            });
+        }
+
+        for tag in revision.tags() {
+            let Some(key) = tag.std_key else {
+                continue;
+            };
+            let value = tag.value.to_string();
+            // RIFF INFO values come padded with nuls
+            let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+            if value.is_empty() {
+                continue;
+            }
+
+            match key {
+                StandardTagKey::TrackTitle => fill_text(&mut self.title, value),
+                StandardTagKey::Artist => fill_text(&mut self.artist, value),
+                StandardTagKey::Album => fill_text(&mut self.album, value),
+                StandardTagKey::AlbumArtist => fill_text(&mut self.album_artist, value),
+                StandardTagKey::Genre => fill_text(&mut self.genre, value),
+                StandardTagKey::TrackNumber => {
+                    self.track_number = self.track_number.or_else(|| leading_number(value));
+                }
+                StandardTagKey::DiscNumber => {
+                    self.disc_number = self.disc_number.or_else(|| leading_number(value));
+                }
+                StandardTagKey::Date
+                | StandardTagKey::ReleaseDate
+                | StandardTagKey::OriginalDate => {
+                    self.year = self.year.or_else(|| leading_number(value));
+                }
+                StandardTagKey::ReplayGainTrackGain => {
+                    fill_number(&mut self.replay_gain.track_gain, value);
+                }
+                StandardTagKey::ReplayGainTrackPeak => {
+                    fill_number(&mut self.replay_gain.track_peak, value);
+                }
+                StandardTagKey::ReplayGainAlbumGain => {
+                    fill_number(&mut self.replay_gain.album_gain, value);
+                }
+                StandardTagKey::ReplayGainAlbumPeak => {
+                    fill_number(&mut self.replay_gain.album_peak, value);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn fill_text(field: &mut Option<Box<str>>, value: &str) {
+    if field.is_none() {
+        *field = Some(value.into());
+    }
+}
+
+/// for ReplayGain values, like `-6.54 dB` or `0.988373`
+fn fill_number(field: &mut Option<f64>, value: &str) {
+    if field.is_none() {
+        *field = value
+            .split_whitespace()
+            .next()
+            .and_then(|number| number.parse().ok())
+            .filter(|number: &f64| number.is_finite());
+    }
+}
+
+/// the number at the start of values like `3/12` or `2019-03-01`
+fn leading_number<T: std::str::FromStr>(value: &str) -> Option<T> {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+/// A field writing tags would change, with what the file has now and what it would get.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagChange {
+    /// the vorbis comment name of the field
+    pub field: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// what our user tags are stored as, a TXXX frame in ID3v2
+const TAGS_FIELD: &str = "TAGS";
+
+/// Writes the track's fields into the tags of the file at `path`, returning what changed. With
+/// `dry_run` the file is left alone and it only says what would change.
+///
+/// The file is never changed in place. The new one is written next to it and renamed over it,
+/// so it's either the old file or the new one, never something in between.
+pub fn write_tags(path: &Path, track: &Track, dry_run: bool) -> Result<Vec<TagChange>> {
+    let fields = wanted_fields(path, track);
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("mp3") => write_id3(path, &fields, dry_run),
+        Some("flac") => write_vorbis_comments(path, &fields, dry_run),
+        _ => bail!("writing tags to {} isn't supported", path.display()),
+    }
+}
+
+/// What the file should say for each field, `None` meaning the field should go.
+fn wanted_fields(path: &Path, track: &Track) -> Vec<(&'static str, Option<String>)> {
+    let text = |value: &Option<std::sync::Arc<str>>| value.as_ref().map(|value| value.to_string());
+    let mut fields = Vec::new();
+
+    // a name that's just the file name isn't worth writing as a title
+    let file_name = path.file_name().and_then(|name| name.to_str());
+    if track.edited.contains(&TrackField::Name) || file_name != Some(track.name.as_ref()) {
+        fields.push(("TITLE", Some(track.name.to_string())));
+    }
+    fields.push(("ARTIST", text(&track.artist)));
+    fields.push(("ALBUM", text(&track.album)));
+    fields.push(("ALBUMARTIST", text(&track.album_artist)));
+    fields.push(("TRACKNUMBER", track.track_number.map(|n| n.to_string())));
+    fields.push(("DISCNUMBER", track.disc_number.map(|n| n.to_string())));
+    fields.push(("DATE", track.year.map(|year| year.to_string())));
+    fields.push(("GENRE", text(&track.genre)));
+    let tags = track.tags.join("; ");
+    fields.push((TAGS_FIELD, (!tags.is_empty()).then_some(tags)));
+
+    fields
+}
+
+fn change(field: &'static str, old: Option<String>, new: &Option<String>) -> Option<TagChange> {
+    let same = match (old.as_deref(), new.as_deref()) {
+        (Some(old), Some(new)) => match field {
+            // `3/12` is still track 3 and `2019-03-01` is still 2019, so those stay as they are
+            "TRACKNUMBER" | "DISCNUMBER" | "DATE" => {
+                leading_number::<i64>(old) == leading_number(new)
+            }
+            _ => old == new,
+        },
+        (old, new) => old == new,
+    };
+    (!same).then(|| TagChange {
+        field,
+        old,
+        new: new.clone(),
+    })
+}
+
+/// the ID3v2 text frame each field goes in
+fn id3_frame(field: &str) -> Option<&'static str> {
+    Some(match field {
+        "TITLE" => "TIT2",
+        "ARTIST" => "TPE1",
+        "ALBUM" => "TALB",
+        "ALBUMARTIST" => "TPE2",
+        "TRACKNUMBER" => "TRCK",
+        "DISCNUMBER" => "TPOS",
+        "DATE" => "TDRC",
+        "GENRE" => "TCON",
+        _ => return None,
+    })
+}
+
+fn write_id3(
+    path: &Path,
+    fields: &[(&'static str, Option<String>)],
+    dry_run: bool,
+) -> Result<Vec<TagChange>> {
+    let mut tag = id3::no_tag_ok(id3::Tag::read_from_path(path))?.unwrap_or_default();
+
+    let mut changes = Vec::new();
+    for (field, new) in fields {
+        let old = match id3_frame(field) {
+            Some(frame) => tag
+                .get(frame)
+                // ID3v2.3 files keep the year in TYER instead
+                .or_else(|| (frame == "TDRC").then(|| tag.get("TYER")).flatten())
+                .and_then(|frame| frame.content().text())
+                .map(str::to_string),
+            None => tag
+                .extended_texts()
+                .find(|text| text.description == *field)
+                .map(|text| text.value.clone()),
+        };
+        let Some(change) = change(field, old, new) else {
+            continue;
+        };
+
+        match (id3_frame(field), new) {
+            (Some(frame), Some(new)) => {
+                tag.set_text(frame, new);
+            }
+            (Some(frame), None) => {
+                tag.remove(frame);
+            }
+            (None, new) => {
+                tag.remove_extended_text(Some(field), None);
+                if let Some(new) = new {
+                    tag.add_frame(ExtendedText {
+                        description: field.to_string(),
+                        value: new.clone(),
+                    });
+                }
+            }
+        }
+        if *field == "DATE" {
+            tag.remove("TYER");
+        }
+        changes.push(change);
+    }
+
+    if dry_run || changes.is_empty() {
+        return Ok(changes);
+    }
+
+    let temp = temp_path(path);
+    let written = copy(path, &temp)
+        .map_err(Into::into)
+        .and_then(|_| Ok(tag.write_to_path(&temp, id3::Version::Id3v24)?))
+        .and_then(|_| Ok(File::open(&temp)?.sync_all()?))
+        .and_then(|_| Ok(rename(&temp, path)?));
+    if written.is_err() {
+        let _ = remove_file(&temp);
+    }
+    written.map(|_| changes)
+}
+
+const FLAC_STREAMINFO: u8 = 0;
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const FLAC_MAX_BLOCK: usize = (1 << 24) - 1;
+
+fn write_vorbis_comments(
+    path: &Path,
+    fields: &[(&'static str, Option<String>)],
+    dry_run: bool,
+) -> Result<Vec<TagChange>> {
+    let bytes = read(path)?;
+    let (blocks, audio) = flac_blocks(&bytes)?;
+
+    let (vendor, mut comments) = match blocks.iter().find(|(kind, _)| *kind == FLAC_VORBIS_COMMENT)
+    {
+        Some((_, block)) => parse_vorbis_comment(block)?,
+        None => (b"segue-attacca".to_vec(), Vec::new()),
+    };
+
+    let mut changes = Vec::new();
+    for (field, new) in fields {
+        let old: Vec<String> = comments
+            .iter()
+            .filter_map(|comment| comment_value(comment, field))
+            .collect();
+        let old = (!old.is_empty()).then(|| old.join("; "));
+        let Some(change) = change(field, old, new) else {
+            continue;
+        };
+
+        comments.retain(|comment| comment_value(comment, field).is_none());
+        if let Some(new) = new {
+            comments.push(format!("{field}={new}").into_bytes());
+        }
+        changes.push(change);
+    }
+
+    if dry_run || changes.is_empty() {
+        return Ok(changes);
+    }
+
+    let comment_block = vorbis_comment(&vendor, &comments);
+    if comment_block.len() > FLAC_MAX_BLOCK {
+        bail!("tags are too big for a flac metadata block");
+    }
+
+    let mut new_blocks: Vec<FlacBlock> = Vec::with_capacity(blocks.len() + 1);
+    for (kind, block) in &blocks {
+        match *kind {
+            FLAC_VORBIS_COMMENT => (),
+            FLAC_STREAMINFO => {
+                new_blocks.push((*kind, block));
+                new_blocks.push((FLAC_VORBIS_COMMENT, &comment_block));
+            }
+            _ => new_blocks.push((*kind, block)),
+        }
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() + comment_block.len());
+    out.extend_from_slice(b"fLaC");
+    for (i, (kind, block)) in new_blocks.iter().enumerate() {
+        let last = if i + 1 == new_blocks.len() { 0x80 } else { 0 };
+        out.push(kind | last);
+        out.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(audio);
+
+    write_atomically(path, &out)?;
+    Ok(changes)
+}
+
+/// a flac metadata block's type and contents
+type FlacBlock<'a> = (u8, &'a [u8]);
+
+/// the metadata blocks of a flac file and the audio after them
+fn flac_blocks(bytes: &[u8]) -> Result<(Vec<FlacBlock<'_>>, &[u8])> {
+    if !bytes.starts_with(b"fLaC") {
+        bail!("not a flac file");
+    }
+    let mut blocks = Vec::new();
+    let mut at = 4;
+    loop {
+        let Some(header) = bytes.get(at..at + 4) else {
+            bail!("flac metadata ends early");
+        };
+        let kind = header[0] & 0x7f;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let Some(block) = bytes.get(at + 4..at + 4 + length) else {
+            bail!("flac metadata ends early");
+        };
+        blocks.push((kind, block));
+        at += 4 + length;
+
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    if blocks.first().map(|(kind, _)| *kind) != Some(FLAC_STREAMINFO) {
+        bail!("flac file doesn't start with a STREAMINFO block");
+    }
+    Ok((blocks, &bytes[at..]))
+}
+
+/// The vendor string and the comments, as raw bytes so the ones we don't touch are written back
+/// exactly as they were, even if they aren't valid UTF-8 or are missing their `=`.
+type VorbisComments = (Vec<u8>, Vec<Vec<u8>>);
+
+fn parse_vorbis_comment(block: &[u8]) -> Result<VorbisComments> {
+    let mut at = 0;
+    let vendor_length = take_u32(block, &mut at)?;
+    let vendor = take(block, &mut at, vendor_length)?.to_vec();
+    let count = take_u32(block, &mut at)?;
+
+    let mut comments = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let length = take_u32(block, &mut at)?;
+        comments.push(take(block, &mut at, length)?.to_vec());
+    }
+    Ok((vendor, comments))
+}
+
+/// the value of a comment if it's `field`, going by the name before its `=`
+fn comment_value(comment: &[u8], field: &str) -> Option<String> {
+    let equals = comment.iter().position(|byte| *byte == b'=')?;
+    comment[..equals]
+        .eq_ignore_ascii_case(field.as_bytes())
+        .then(|| String::from_utf8_lossy(&comment[equals + 1..]).into_owned())
+}
+
+fn take<'a>(block: &'a [u8], at: &mut usize, length: usize) -> Result<&'a [u8]> {
+    let Some(bytes) = block.get(*at..*at + length) else {
+        bail!("vorbis comment block ends early");
+    };
+    *at += length;
+    Ok(bytes)
+}
+
+fn take_u32(block: &[u8], at: &mut usize) -> Result<usize> {
+    let bytes = take(block, at, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+fn vorbis_comment(vendor: &[u8], comments: &[Vec<u8>]) -> Vec<u8> {
+    let mut block = Vec::new();
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor);
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        block.extend_from_slice(comment);
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::{Arc, RwLock},
+    };
+
+    use super::*;
+    use crate::{
+        music_library::MusicLibrary,
+        test_util::{temp_dir, write_flac, write_mp3},
+    };
+
+    /// the track the library made of the file called `name` when it scanned `dir`
+    fn scanned(dir: &Path, name: &str) -> Arc<RwLock<Track>> {
+        let library = MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap();
+        library
+            .get_tracks()
+            .iter()
+            .find(|track| track.read().unwrap().path.ends_with(name))
+            .cloned()
+            .unwrap()
+    }
+
+    /// edits a few fields of the track in `dir`, writes them and checks they read back
+    fn round_trip(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        let track = scanned(dir, name);
+        let mut track = track.write().unwrap();
+        assert_eq!(track.artist.as_deref(), Some("Old Artist"));
+
+        track.artist = Some("New Artist".into());
+        track.album = Some("An Album".into());
+        track.track_number = Some(3);
+        track.genre = None;
+        track.tags = vec!["warm".into(), "late".into()];
+        let changes = write_tags(&path, &track, false).unwrap();
+        let fields: Vec<_> = changes.iter().map(|change| change.field).collect();
+        assert_eq!(
+            fields,
+            ["ARTIST", "ALBUM", "TRACKNUMBER", "GENRE", TAGS_FIELD]
+        );
+
+        let tags = EmbeddedTags::read(&path).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Old Title"));
+        assert_eq!(tags.artist.as_deref(), Some("New Artist"));
+        assert_eq!(tags.album.as_deref(), Some("An Album"));
+        assert_eq!(tags.track_number, Some(3));
+        assert_eq!(tags.genre, None);
+
+        // everything that was written is what's there now
+        assert!(write_tags(&path, &track, true).unwrap().is_empty());
+        path
+    }
+
+    #[test]
+    fn flac_round_trip() {
+        let dir = temp_dir("flac-round-trip");
+        let comments: [&[u8]; 5] = [
+            b"TITLE=Old Title",
+            b"ARTIST=Old Artist",
+            b"GENRE=Ambient",
+            b"not a comment",
+            b"LYRICS=\xff\xfe not utf-8",
+        ];
+        write_flac(&dir.join("song.flac"), &comments);
+
+        let path = round_trip(&dir, "song.flac");
+        let bytes = fs::read(path).unwrap();
+        let (blocks, _) = flac_blocks(&bytes).unwrap();
+        let (_, block) = blocks
+            .iter()
+            .find(|(kind, _)| *kind == FLAC_VORBIS_COMMENT)
+            .unwrap();
+        let (vendor, comments) = parse_vorbis_comment(block).unwrap();
+        assert_eq!(vendor, b"test");
+        let comments: Vec<&[u8]> = comments.iter().map(|comment| comment.as_slice()).collect();
+        assert_eq!(
+            comments,
+            [
+                b"TITLE=Old Title".as_slice(),
+                b"not a comment",
+                b"LYRICS=\xff\xfe not utf-8",
+                b"ARTIST=New Artist",
+                b"ALBUM=An Album",
+                b"TRACKNUMBER=3",
+                b"TAGS=warm; late",
+            ]
+        );
+    }
+
+    #[test]
+    fn mp3_round_trip() {
+        let dir = temp_dir("mp3-round-trip");
+        write_mp3(
+            &dir.join("song.mp3"),
+            &[
+                ("TIT2", "Old Title"),
+                ("TPE1", "Old Artist"),
+                ("TCON", "Ambient"),
+                ("TCOM", "Someone"),
+            ],
+        );
+
+        let path = round_trip(&dir, "song.mp3");
+        let tag = id3::Tag::read_from_path(path).unwrap();
+        assert_eq!(
+            tag.get("TCOM").and_then(|frame| frame.content().text()),
+            Some("Someone")
+        );
+        let tags = tag
+            .extended_texts()
+            .find(|text| text.description == TAGS_FIELD)
+            .unwrap();
+        assert_eq!(tags.value, "warm; late");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writing_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("tag-permissions");
+        write_flac(&dir.join("song.flac"), &[b"ARTIST=Old Artist"]);
+        write_mp3(&dir.join("song.mp3"), &[("TPE1", "Old Artist")]);
+        for name in ["song.flac", "song.mp3"] {
+            let path = dir.join(name);
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            let track = scanned(&dir, name);
+            let mut track = track.write().unwrap();
+            track.artist = Some("New Artist".into());
+            write_tags(&path, &track, false).unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640, "{name}");
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Fixtures for tests. They're generated as the tests run rather than checked in.
+
+use std::{
+    f32::consts::TAU,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::music_library::{MusicLibrary, Playlist, PlaylistItem, Track};
+
+/// an empty directory of its own for a test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("segue-attacca-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// a library in a directory of its own, with a short wav at each of `paths` and no two alike
+pub fn wav_library(name: &str, paths: &[&str]) -> MusicLibrary {
+    let dir = temp_dir(name);
+    for (i, path) in paths.iter().enumerate() {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_wav(&path, 44_100, 1, &[i as i16; 64]);
+    }
+    MusicLibrary::new_from_path(dir.to_str().unwrap()).unwrap()
+}
+
+/// the track at `path`, relative to the library
+pub fn track_at(library: &MusicLibrary, path: &str) -> Arc<RwLock<Track>> {
+    let track = library
+        .get_tracks()
+        .iter()
+        .find(|track| *track.read().unwrap().path == *path);
+    Arc::clone(track.unwrap())
+}
+
+/// a new playlist of `items`, put in one by one the way they would be from the ui
+pub fn playlist_with(
+    library: &mut MusicLibrary,
+    name: &str,
+    items: impl IntoIterator<Item = PlaylistItem>,
+) -> Arc<RwLock<Playlist>> {
+    let playlist = library.create_playlist(name);
+    let uuid = playlist.read().unwrap().uuid();
+    for (i, item) in items.into_iter().enumerate() {
+        library.insert_item(uuid, &[i], item).unwrap();
+    }
+    playlist
+}
+
+/// writes interleaved samples as 16 bit pcm
+pub fn write_wav(path: &Path, sample_rate: u32, channels: u16, samples: &[i16]) {
+    let data_len = samples.len() as u32 * 2;
+    let mut bytes = b"RIFF".to_vec();
+    bytes.extend((36 + data_len).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16u32.to_le_bytes());
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(channels.to_le_bytes());
+    bytes.extend(sample_rate.to_le_bytes());
+    bytes.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend((channels * 2).to_le_bytes());
+    bytes.extend(16u16.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend(data_len.to_le_bytes());
+    for sample in samples {
+        bytes.extend(sample.to_le_bytes());
+    }
+    fs::write(path, bytes).unwrap();
+}
+
+/// a stereo sine at about half of full scale
+pub fn sine(sample_rate: u32, seconds: f32, frequency: f32) -> Vec<i16> {
+    (0..(sample_rate as f32 * seconds) as usize)
+        .flat_map(|i| {
+            let sample = (i as f32 / sample_rate as f32 * frequency * TAU).sin() * 16000.0;
+            [sample as i16; 2]
+        })
+        .collect()
+}
+
+/// a flac file of silence with a vorbis comment block holding `comments` as they are
+pub fn write_flac(path: &Path, comments: &[&[u8]]) {
+    let mut bytes = b"fLaC".to_vec();
+
+    // one block of 192 samples, mono at 44.1kHz and 16 bits
+    let mut streaminfo = Vec::new();
+    streaminfo.extend(192u16.to_be_bytes());
+    streaminfo.extend(192u16.to_be_bytes());
+    streaminfo.extend([0; 6]);
+    streaminfo.extend(((44_100u64 << 44) | (15 << 36) | 192).to_be_bytes());
+    streaminfo.extend([0; 16]);
+    bytes.push(0);
+    bytes.extend(&(streaminfo.len() as u32).to_be_bytes()[1..]);
+    bytes.extend(streaminfo);
+
+    let mut block = Vec::new();
+    block.extend(4u32.to_le_bytes());
+    block.extend(b"test");
+    block.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        block.extend((comment.len() as u32).to_le_bytes());
+        block.extend(*comment);
+    }
+    // the last metadata block, a vorbis comment
+    bytes.push(0x84);
+    bytes.extend(&(block.len() as u32).to_be_bytes()[1..]);
+    bytes.extend(block);
+
+    // a frame of 192 samples with a constant subframe of zero
+    let mut frame = vec![0xFF, 0xF8, 0x19, 0x08, 0x00];
+    frame.push(crc8(&frame));
+    frame.extend([0; 3]);
+    let crc = crc16(&frame);
+    frame.extend(crc.to_be_bytes());
+    bytes.extend(frame);
+
+    fs::write(path, bytes).unwrap();
+}
+
+/// an mp3 of silent frames with an ID3v2.3 tag holding the text `frames`
+pub fn write_mp3(path: &Path, frames: &[(&str, &str)]) {
+    let mut tag = Vec::new();
+    for (id, text) in frames {
+        // latin-1 encoded text
+        let mut data = vec![0];
+        data.extend(text.as_bytes());
+        tag.extend(id.as_bytes());
+        tag.extend((data.len() as u32).to_be_bytes());
+        tag.extend([0, 0]);
+        tag.extend(data);
+    }
+    let mut bytes = b"ID3\x03\x00\x00".to_vec();
+    let size = tag.len();
+    bytes.extend([21, 14, 7, 0].map(|shift| (size >> shift) as u8 & 0x7f));
+    bytes.extend(tag);
+
+    // mpeg 1 layer 3 at 128kbps and 44.1kHz
+    for _ in 0..40 {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+        frame.resize(417, 0);
+        bytes.extend(frame);
+    }
+    fs::write(path, bytes).unwrap();
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            (crc << 1) ^ if crc & 0x80 != 0 { 0x07 } else { 0 }
+        })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| {
+            (crc << 1) ^ if crc & 0x8000 != 0 { 0x8005 } else { 0 }
+        })
+    })
+}
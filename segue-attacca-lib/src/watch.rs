@@ -0,0 +1,91 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use color_eyre::Result;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, event::ModifyKind};
+use tracing::warn;
+
+use crate::{
+    album_art::ART_CACHE,
+    cue::is_cue_sheet,
+    music_library::{MusicLibrary, is_audio_file, is_hidden},
+};
+
+/// how long things have to be quiet before changes are passed on, so copying in a whole album
+/// is one change instead of one per file
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Watches a library's folder for audio files being added, removed, renamed or changed, using
+/// inotify on Linux. It stops when dropped.
+pub struct LibraryWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl LibraryWatcher {
+    /// `on_change` gets the paths that changed once things settle down. It's called from the
+    /// watcher's own thread, and a `MusicLibrary::scanner` for those paths picks the changes up.
+    pub fn new(
+        library: &MusicLibrary,
+        on_change: impl FnMut(Vec<PathBuf>) + Send + 'static,
+    ) -> Result<Self> {
+        let root = PathBuf::from(library.path.as_ref());
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        thread::spawn(move || settle(rx, &root, on_change));
+        Ok(Self { _watcher: watcher })
+    }
+}
+
+fn settle(
+    rx: Receiver<notify::Result<Event>>,
+    root: &Path,
+    mut on_change: impl FnMut(Vec<PathBuf>),
+) {
+    let mut changed: Vec<PathBuf> = Vec::new();
+    loop {
+        let event = if changed.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(SETTLE)
+        };
+        match event {
+            Ok(Ok(event)) => {
+                if matters(&event.kind) {
+                    let paths = event.paths.into_iter();
+                    changed.extend(paths.filter(|path| is_library_file(root, path)));
+                }
+            }
+            Ok(Err(e)) => warn!("library watcher: {e}"),
+            Err(RecvTimeoutError::Timeout) => {
+                changed.sort();
+                changed.dedup();
+                on_change(std::mem::take(&mut changed));
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+fn matters(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        EventKind::Any | EventKind::Access(_) | EventKind::Other => false,
+    }
+}
+
+/// Audio files, cue sheets and folders, since a folder moved in doesn't say what's in it. Our
+/// own files, like the album art cache and files being written before a rename, don't count.
+fn is_library_file(root: &Path, path: &Path) -> bool {
+    if path.starts_with(root.join(ART_CACHE)) {
+        return false;
+    }
+    !is_hidden(root, path)
+        && (path.extension().is_none() || is_audio_file(path) || is_cue_sheet(path))
+}
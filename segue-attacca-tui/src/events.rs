@@ -1,7 +1,19 @@
+use std::path::PathBuf;
+
+use color_eyre::Result;
+use segue_attacca_lib::music_library::Scan;
+
 #[expect(dead_code)]
 pub enum Event {
     KeyPressed(KeyCode, Modifiers),
     Redraw,
+    /// files in the library folder changed on disk
+    LibraryChanged(Vec<PathBuf>),
+    /// a scan started by `AppState::rescan` is done
+    LibraryScanned(Result<Scan>),
+    Autosave,
+    /// the app was asked to stop from outside, by SIGTERM
+    Quit,
 }
 
 pub enum KeyCode {
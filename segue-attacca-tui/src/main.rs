@@ -1,32 +1,54 @@
 mod assets;
 mod events;
+mod playlist_panel;
 mod terminal_events;
 mod track_inspector;
 mod track_list;
 
-use std::{collections::HashMap, ops::Deref, sync::Arc, thread};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
 
 use assets::Asset;
-use color_eyre::Result;
+use color_eyre::{Result, eyre::WrapErr};
 use events::{Event, KeyCode};
+use playlist_panel::{handle_playlist_events, playlist_tree};
 use ratatui::{
     DefaultTerminal, Frame,
     layout::{Constraint, Layout},
     style::{Color, Style, Stylize},
+    text::Line,
     widgets::{Block, BorderType, List, ListState},
 };
 use ratatui_image::{picker::Picker, protocol::StatefulProtocol};
-use segue_attacca_lib::music_library::MusicLibrary;
+use segue_attacca_lib::{
+    music_library::Playlist,
+    music_library::{MusicLibrary, Track},
+    playback::{PlaybackEngine, PlaybackOutput, PlaybackState},
+    queue::{Queue, RepeatMode, ShuffleMode},
+    search::{TrackQuery, TrackSort, search},
+    watch::LibraryWatcher,
+};
 use terminal_events::handle_terminal_events;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::mpsc::{Receiver, Sender, channel};
-use tracing::warn;
+use tracing::{info, warn};
 use track_inspector::{TrackInspector, handle_inspector_events};
 use track_list::handle_track_list_events;
+use tui_tree_widget::{Tree, TreeState};
 
 const DEFAULT_COLOR: Color = Color::LightBlue;
 const FOCUS_COLOR: Color = Color::LightMagenta;
 const SELECT_COLOR: Color = Color::Green;
 
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
@@ -41,38 +63,121 @@ async fn main() -> Result<()> {
 }
 
 async fn run(mut terminal: DefaultTerminal, state: &mut AppState) -> Result<()> {
-    state.list = state
-        .library
-        .get_tracks()
-        .iter()
-        .map(|track| TrackInspector::new(Arc::downgrade(track)))
-        .collect();
+    state.refresh_list();
+    state.playback.set_settings(state.library.playback);
 
     let tx = state.event_tx.clone();
     thread::spawn(move || handle_terminal_events(tx));
 
+    let tx = state.event_tx.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(AUTOSAVE_INTERVAL);
+        // the first tick is right away, and there's nothing to save yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if tx.send(Event::Autosave).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // SIGTERM quits the same way `q` does, so the library's saved
+    #[cfg(unix)]
+    {
+        let tx = state.event_tx.clone();
+        tokio::spawn(async move {
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    warn!("couldn't listen for SIGTERM: {e}");
+                    return;
+                }
+            };
+            if terminate.recv().await.is_some() {
+                let _ = tx.send(Event::Quit).await;
+            }
+        });
+    }
+
+    let tx = state.event_tx.clone();
+    let _watcher = LibraryWatcher::new(&state.library, move |paths| {
+        let _ = tx.blocking_send(Event::LibraryChanged(paths));
+    })
+    .inspect_err(|e| warn!("couldn't watch the library folder: {e}"));
+
+    state.analyze();
+
+    // keeps the playback position on screen moving
+    let tx = state.event_tx.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+        loop {
+            interval.tick().await;
+            if tx.send(Event::Redraw).await.is_err() {
+                break;
+            }
+        }
+    });
+
     loop {
+        state.follow_queue();
         terminal.draw(|f| render(f, state))?;
         if let Some(event) = state.event_rx.recv().await {
             let handled = match state.selected_panel {
                 SelectedPanel::TrackList => handle_track_list_events(&event, state),
                 SelectedPanel::Inspector => handle_inspector_events(&event, state),
+                SelectedPanel::Playlists => handle_playlist_events(&event, state),
             };
             if handled {
                 continue;
             }
             match event {
+                // the error's reported once the terminal's back to normal
                 Event::KeyPressed(KeyCode::Escape, _)
-                | Event::KeyPressed(KeyCode::Char('q'), _) => {
-                    break Ok(());
-                }
+                | Event::KeyPressed(KeyCode::Char('q'), _)
+                | Event::Quit => break state.library.save().wrap_err("couldn't save library"),
 
                 Event::KeyPressed(KeyCode::Char(c), _) => match c {
                     '1' => state.selected_panel = SelectedPanel::TrackList,
                     '2' => state.selected_panel = SelectedPanel::Inspector,
+                    '3' => state.selected_panel = SelectedPanel::Playlists,
+                    'z' => {
+                        let mode = state.queue.shuffle_mode().next();
+                        state.queue.shuffle(mode, seed());
+                        state.requeue();
+                    }
+                    'r' => {
+                        state.queue.repeat = state.queue.repeat.next();
+                        state.requeue();
+                    }
 
                     _ => continue,
                 },
+                Event::LibraryChanged(paths) => {
+                    state.rescan(paths);
+                    continue;
+                }
+                Event::LibraryScanned(scan) => {
+                    state.scanning = false;
+                    match scan {
+                        Ok(scan) => {
+                            let summary = state.library.apply_scan(scan);
+                            info!("rescanned library: {summary}");
+                        }
+                        Err(e) => warn!("couldn't rescan library: {e}"),
+                    }
+                    state.refresh_list();
+                    state.analyze();
+                    // whatever changed while that scan was going
+                    state.rescan(Vec::new());
+                }
+                Event::Autosave => {
+                    if let Err(e) = state.library.save() {
+                        warn!("couldn't autosave library: {e}");
+                    }
+                    continue;
+                }
                 _ => continue,
             }
         }
@@ -80,13 +185,65 @@ async fn run(mut terminal: DefaultTerminal, state: &mut AppState) -> Result<()>
 }
 
 fn render(frame: &mut Frame, state: &mut AppState) {
-    let layout = Layout::horizontal([Constraint::Fill(3), Constraint::Fill(1)]);
-    let [list_area, inspector_area] = layout.areas(frame.area());
+    let layout = Layout::horizontal([
+        Constraint::Fill(2),
+        Constraint::Fill(1),
+        Constraint::Fill(1),
+    ]);
+    let [list_area, inspector_area, playlists_area] = layout.areas(frame.area());
 
-    let mut list = List::new(state.list.deref())
+    let status = state.playback.status();
+    let now_playing = match (status.state, status.item) {
+        (PlaybackState::Stopped, _) | (_, None) => String::from(" stopped "),
+        (state, Some(item)) => {
+            let symbol = if state == PlaybackState::Paused {
+                "⏸"
+            } else {
+                "▶"
+            };
+            format!(" {symbol} {} {} ", item.name, clock(status.position))
+        }
+    };
+    let mut modes = String::new();
+    if state.queue.shuffle_mode() != ShuffleMode::Off {
+        modes.push_str(&format!(" shuffle {} ", state.queue.shuffle_mode()));
+    }
+    if state.queue.repeat != RepeatMode::Off {
+        modes.push_str(&format!(" repeat {} ", state.queue.repeat));
+    }
+
+    let rows: Vec<String> = state
+        .list
+        .iter()
+        .map(|item| {
+            let duration = item
+                .track
+                .upgrade()
+                .and_then(|lock| state.library.properties(&*lock.read().ok()?))
+                .map(|properties| format!("  {}", clock(properties.duration)));
+            let key = item
+                .track
+                .upgrade()
+                .and_then(|lock| lock.read().ok()?.key)
+                .map(|key| format!("  {}", key.camelot()));
+            format!(
+                "{item}{}{}",
+                duration.unwrap_or_default(),
+                key.unwrap_or_default()
+            )
+        })
+        .collect();
+    let mut order = format!(" by {} ", state.sort);
+    if state.searching || !state.search.is_empty() {
+        order = format!(" /{}{order}", state.search);
+    }
+    let mut list = List::new(rows)
         .block(
             Block::bordered()
                 .title(" [1] segue attacca ")
+                .title(Line::from(order).right_aligned())
+                .title_bottom(now_playing)
+                .title_bottom(Line::from(modes).right_aligned())
                 .border_type(BorderType::Rounded),
         )
         .fg(DEFAULT_COLOR)
@@ -97,9 +254,15 @@ fn render(frame: &mut Frame, state: &mut AppState) {
         .border_type(BorderType::Rounded)
         .fg(DEFAULT_COLOR);
 
+    let mut playlists = Block::bordered()
+        .title(" [3] playlists ")
+        .border_type(BorderType::Rounded)
+        .fg(DEFAULT_COLOR);
+
     match state.selected_panel {
         SelectedPanel::TrackList => list = list.fg(FOCUS_COLOR),
         SelectedPanel::Inspector => inspector = inspector.fg(FOCUS_COLOR),
+        SelectedPanel::Playlists => playlists = playlists.fg(FOCUS_COLOR),
     }
 
     let inspector_inner = inspector.inner(inspector_area);
@@ -107,6 +270,17 @@ fn render(frame: &mut Frame, state: &mut AppState) {
     frame.render_stateful_widget(list, list_area, state.list_state_mut());
     frame.render_widget(inspector, inspector_area);
 
+    let playlist_items = playlist_tree(&state.library);
+    match Tree::new(&playlist_items) {
+        Ok(tree) => {
+            let tree = tree
+                .block(playlists)
+                .highlight_style(Style::new().fg(SELECT_COLOR));
+            frame.render_stateful_widget(tree, playlists_area, &mut state.playlist_state);
+        }
+        Err(e) => warn!("couldn't show playlists: {e}"),
+    }
+
     if let Some(track_inspector) = state.track_inspector.as_ref() {
         frame.render_stateful_widget(track_inspector.clone(), inspector_inner, state);
     } else {
@@ -123,14 +297,28 @@ pub struct AppState {
     pub library: MusicLibrary,
     list: Vec<TrackInspector>,
     list_state: ListState,
+    /// what the track list is filtered by, see `TrackQuery`
+    pub search: String,
+    /// whether keys go into `search` rather than doing things
+    pub searching: bool,
+    pub sort: TrackSort,
+    playlist_state: TreeState<usize>,
     pub track_inspector: Option<TrackInspector>,
     pub images: HashMap<String, Asset<StatefulProtocol>>,
     pub selected_panel: SelectedPanel,
 
+    pub playback: PlaybackEngine,
+    /// what's playing when a playlist is, with its shuffle and repeat modes
+    pub queue: Queue,
     pub picker: Picker,
 
     pub shift: bool,
 
+    /// whether a scan is reading files, see `rescan`
+    scanning: bool,
+    /// files that changed while it was, for the next one
+    changed: Vec<PathBuf>,
+
     event_rx: Receiver<Event>,
     event_tx: Sender<Event>,
 }
@@ -149,11 +337,19 @@ impl Default for AppState {
             library: Default::default(),
             list: Default::default(),
             list_state: Default::default(),
+            search: Default::default(),
+            searching: Default::default(),
+            sort: Default::default(),
+            playlist_state: Default::default(),
             track_inspector: Default::default(),
             images: Default::default(),
             selected_panel: Default::default(),
+            playback: PlaybackEngine::new(PlaybackOutput::Device),
+            queue: Default::default(),
             picker,
             shift: Default::default(),
+            scanning: Default::default(),
+            changed: Default::default(),
             event_rx,
             event_tx,
         }
@@ -172,6 +368,89 @@ impl AppState {
         }
     }
 
+    /// Rebuilds the track list from the library, after it's been scanned or the search or
+    /// sort changed. A search that doesn't make sense yet, like `key:` half typed, shows
+    /// nothing.
+    pub fn refresh_list(&mut self) {
+        let tracks = match self.search.parse::<TrackQuery>() {
+            Ok(query) => search(self.library.get_tracks(), &query, self.sort),
+            Err(_) => Vec::new(),
+        };
+        self.list = tracks
+            .iter()
+            .map(|track| TrackInspector::new(Arc::downgrade(track)))
+            .collect();
+    }
+
+    /// the track that's selected in the track list
+    pub fn selected_track(&self) -> Option<Arc<RwLock<Track>>> {
+        self.list.get(self.list_state.selected()?)?.track.upgrade()
+    }
+
+    /// Picks up the files at `paths` changing on disk. They're read on a blocking thread and
+    /// the scan comes back as an `Event::LibraryScanned`, one at a time, so changes that come
+    /// in while one's going wait for the next.
+    pub fn rescan(&mut self, paths: Vec<PathBuf>) {
+        self.changed.extend(paths);
+        if self.scanning || self.changed.is_empty() {
+            return;
+        }
+        self.scanning = true;
+        let scanner = self
+            .library
+            .scanner(Some(std::mem::take(&mut self.changed)));
+        let tx = self.event_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = tx.blocking_send(Event::LibraryScanned(scanner.run()));
+        });
+    }
+
+    /// analyses whatever tracks haven't been yet, redrawing as they're done
+    pub fn analyze(&self) {
+        let tx = self.event_tx.clone();
+        self.library.analyze(move || {
+            // skipped when the queue's full, there's a redraw coming anyway
+            let _ = tx.try_send(Event::Redraw);
+        });
+    }
+
+    /// Plays a playlist from the start, keeping the shuffle and repeat modes.
+    pub fn play_playlist(&mut self, playlist: &Arc<RwLock<Playlist>>) {
+        let mut queue = Queue::from_playlist(playlist);
+        queue.block_entry = self.library.playback.block_entry;
+        queue.repeat = self.queue.repeat;
+        queue.shuffle(self.queue.shuffle_mode(), seed());
+        self.queue = queue;
+        self.playback
+            .play_all(self.queue.playback_items(&self.library));
+    }
+
+    /// Hands the engine what comes after the current entry again, after the queue's order or
+    /// repeat mode changed. Nothing happens unless the queue is what's playing.
+    pub fn requeue(&mut self) {
+        let status = self.playback.status();
+        let Some(id) = status.item.as_ref().and_then(|item| item.queue_entry) else {
+            return;
+        };
+        if self.queue.position_of(id).is_none() {
+            return;
+        }
+        self.queue.sync(&status);
+        self.playback.clear_queue();
+        for item in self.queue.playback_items(&self.library).into_iter().skip(1) {
+            self.playback.enqueue(item);
+        }
+    }
+
+    /// keeps the queue up with the engine, and the engine fed when repeating wraps around
+    pub fn follow_queue(&mut self) {
+        let status = self.playback.status();
+        self.queue.sync(&status);
+        if let Some(item) = self.queue.feed(&self.library, &status) {
+            self.playback.enqueue(item);
+        }
+    }
+
     pub fn list_state(&self) -> &ListState {
         &self.list_state
     }
@@ -181,9 +460,32 @@ impl AppState {
     }
 }
 
+/// a different seed for every shuffle
+fn seed() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// `m:ss`, or `h:mm:ss` past an hour
+pub fn clock(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
 #[derive(Default)]
 pub enum SelectedPanel {
     #[default]
     TrackList,
     Inspector,
+    Playlists,
 }
@@ -0,0 +1,269 @@
+use std::{collections::HashSet, sync::Arc};
+
+use segue_attacca_lib::music_library::{MusicLibrary, PlaylistItem};
+use tracing::warn;
+use tui_tree_widget::TreeItem;
+use uuid::Uuid;
+
+use crate::{
+    AppState, clock,
+    events::{Event, KeyCode},
+};
+
+/// The library's playlists as a tree. An item's identifier is its index in whatever it's in,
+/// so a selected path is the playlist's index followed by indices into its items, blocks and
+/// nested playlists.
+pub fn playlist_tree(library: &MusicLibrary) -> Vec<TreeItem<'static, usize>> {
+    library
+        .playlists()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, lock)| {
+            let playlist = lock.read().ok()?;
+            let mut visited = HashSet::from([playlist.uuid()]);
+            let children = tree_items(playlist.items(), &mut visited);
+            let duration = clock(library.playlist_duration(&playlist));
+            TreeItem::new(i, format!("{} ({duration})", playlist.name()), children).ok()
+        })
+        .collect()
+}
+
+fn tree_items(
+    items: &[PlaylistItem],
+    visited: &mut HashSet<Uuid>,
+) -> Vec<TreeItem<'static, usize>> {
+    items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| match item {
+            PlaylistItem::Track(lock) => {
+                let track = lock.read().ok()?;
+                Some(TreeItem::new_leaf(i, track.name.to_string()))
+            }
+            PlaylistItem::Playlist(weak) => {
+                let lock = weak.upgrade()?;
+                let playlist = lock.read().ok()?;
+                let name = format!("↳ {}", playlist.name());
+                // a playlist inside itself would go on forever, so it's shown without its items
+                if !visited.insert(playlist.uuid()) {
+                    return Some(TreeItem::new_leaf(i, name));
+                }
+                let children = tree_items(playlist.items(), visited);
+                visited.remove(&playlist.uuid());
+                TreeItem::new(i, name, children).ok()
+            }
+            PlaylistItem::Block(items) => {
+                let children = tree_items(items, visited);
+                TreeItem::new(i, format!("block ({})", items.len()), children).ok()
+            }
+        })
+        .collect()
+}
+
+/// What a selected path in the tree points at: the playlist the item is directly in, and the
+/// path to the item within it. Nested playlists are followed, so editing an item that's shown
+/// inside one edits that playlist.
+fn resolve(library: &MusicLibrary, selected: &[usize]) -> Option<(Uuid, Vec<usize>)> {
+    let (&first, mut rest) = selected.split_first()?;
+    let mut playlist = Arc::clone(library.playlists().get(first)?);
+    let mut path = Vec::new();
+    while let Some((&index, remaining)) = rest.split_first() {
+        path.push(index);
+        rest = remaining;
+        if rest.is_empty() {
+            break;
+        }
+        let next = {
+            let read = playlist.read().ok()?;
+            match item_at(read.items(), &path)? {
+                PlaylistItem::Playlist(weak) => Some(weak.upgrade()?),
+                PlaylistItem::Block(_) => None,
+                PlaylistItem::Track(_) => return None,
+            }
+        };
+        if let Some(next) = next {
+            playlist = next;
+            path.clear();
+        }
+    }
+    let uuid = playlist.read().ok()?.uuid();
+    Some((uuid, path))
+}
+
+fn item_at<'a>(items: &'a [PlaylistItem], path: &[usize]) -> Option<&'a PlaylistItem> {
+    let (&index, rest) = path.split_first()?;
+    let item = items.get(index)?;
+    match (item, rest.is_empty()) {
+        (_, true) => Some(item),
+        (PlaylistItem::Block(inner), false) => item_at(inner, rest),
+        _ => None,
+    }
+}
+
+fn read_item(library: &MusicLibrary, uuid: Uuid, path: &[usize]) -> Option<PlaylistItem> {
+    let lock = library.playlist(uuid)?;
+    let playlist = lock.read().ok()?;
+    item_at(playlist.items(), path).cloned()
+}
+
+pub fn handle_playlist_events(event: &Event, state: &mut AppState) -> bool {
+    match event {
+        Event::KeyPressed(KeyCode::Enter, _) => state.playlist_state.toggle_selected(),
+        Event::KeyPressed(KeyCode::Char(c), _) => match c {
+            'j' => state.playlist_state.key_down(),
+            'k' => state.playlist_state.key_up(),
+            'h' => state.playlist_state.key_left(),
+            'l' => state.playlist_state.key_right(),
+            'c' => {
+                let name = format!("playlist {}", state.library.playlists().len() + 1);
+                state.library.create_playlist(&name);
+                true
+            }
+            'D' => {
+                // only a whole playlist, not whatever's selected inside one
+                let &[index] = state.playlist_state.selected() else {
+                    return false;
+                };
+                let Some(uuid) = state
+                    .library
+                    .playlists()
+                    .get(index)
+                    .and_then(|lock| lock.read().ok().map(|playlist| playlist.uuid()))
+                else {
+                    return false;
+                };
+                if let Err(e) = state.library.delete_playlist(uuid) {
+                    warn!("couldn't delete playlist: {e}");
+                }
+                state.playlist_state.select(Vec::new());
+                true
+            }
+            'a' => add_selected_track(state),
+            'p' => {
+                let Some(playlist) = state
+                    .playlist_state
+                    .selected()
+                    .first()
+                    .and_then(|index| state.library.playlists().get(*index))
+                    .cloned()
+                else {
+                    return false;
+                };
+                state.play_playlist(&playlist);
+                true
+            }
+            'x' => {
+                let Some((uuid, path)) = resolve(&state.library, state.playlist_state.selected())
+                else {
+                    return false;
+                };
+                if path.is_empty() {
+                    return false;
+                }
+                if let Err(e) = state.library.remove_item(uuid, &path) {
+                    warn!("couldn't remove item: {e}");
+                }
+                true
+            }
+            'J' => move_selected(state, true),
+            'K' => move_selected(state, false),
+            'b' => group_selected(state),
+            'u' => ungroup_selected(state),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// adds the track selected in the track list after the selected item, or at the end of the
+/// selected playlist
+fn add_selected_track(state: &mut AppState) -> bool {
+    let Some(track) = state.selected_track() else {
+        return false;
+    };
+    let Some((uuid, mut path)) = resolve(&state.library, state.playlist_state.selected()) else {
+        return false;
+    };
+    if let Some(last) = path.last_mut() {
+        *last += 1;
+    } else {
+        let Some(len) = state
+            .library
+            .playlist(uuid)
+            .and_then(|lock| lock.read().ok().map(|playlist| playlist.items().len()))
+        else {
+            return false;
+        };
+        path.push(len);
+    }
+    if let Err(e) = state
+        .library
+        .insert_item(uuid, &path, PlaylistItem::Track(track))
+    {
+        warn!("couldn't add track to playlist: {e}");
+    }
+    true
+}
+
+/// moves the selected item one place down or up within whatever it's in
+fn move_selected(state: &mut AppState, down: bool) -> bool {
+    let selected = state.playlist_state.selected().to_vec();
+    let Some((uuid, from)) = resolve(&state.library, &selected) else {
+        return false;
+    };
+    let Some(&index) = from.last() else {
+        return false;
+    };
+    let Some(index) = (if down {
+        index.checked_add(1)
+    } else {
+        index.checked_sub(1)
+    }) else {
+        return true;
+    };
+    let to = [&from[..from.len() - 1], &[index]].concat();
+    if state.library.move_item(uuid, &from, &to).is_ok() {
+        let selected = [&selected[..selected.len() - 1], &[index]].concat();
+        state.playlist_state.select(selected);
+    }
+    true
+}
+
+/// Groups the selected item with the one after it into a block. If either of them is a block
+/// already, the other joins it, so pressing it again grows the block.
+fn group_selected(state: &mut AppState) -> bool {
+    let selected = state.playlist_state.selected().to_vec();
+    let Some((uuid, path)) = resolve(&state.library, &selected) else {
+        return false;
+    };
+    let Some((&index, parent)) = path.split_last() else {
+        return false;
+    };
+    let next_path = [parent, &[index + 1]].concat();
+    if read_item(&state.library, uuid, &next_path).is_none() {
+        return true;
+    }
+
+    if let Err(e) = state.library.group(uuid, &path) {
+        warn!("couldn't group items into a block: {e}");
+    }
+    state.playlist_state.select(selected);
+    true
+}
+
+/// puts the items of the selected block back where the block was
+fn ungroup_selected(state: &mut AppState) -> bool {
+    let Some((uuid, path)) = resolve(&state.library, state.playlist_state.selected()) else {
+        return false;
+    };
+    if !matches!(
+        read_item(&state.library, uuid, &path),
+        Some(PlaylistItem::Block(_))
+    ) {
+        return false;
+    }
+    if let Err(e) = state.library.ungroup(uuid, &path) {
+        warn!("couldn't ungroup block: {e}");
+    }
+    true
+}
@@ -1,6 +1,8 @@
 use std::{
     fmt::Display,
+    path::Path,
     sync::{Arc, RwLock, Weak},
+    time::Duration,
 };
 
 use fuzzy_matcher::{FuzzyMatcher, skim::SkimMatcherV2};
@@ -13,10 +15,14 @@ use ratatui::{
 };
 use ratatui_image::{StatefulImage, protocol::StatefulProtocol};
 use rfd::FileDialog;
-use segue_attacca_lib::music_library::Track;
+use segue_attacca_lib::{
+    formats,
+    music_library::{MusicLibrary, Track, TrackField},
+};
 use tokio::sync::oneshot;
+use tracing::warn;
 
-use crate::{AppState, Event, KeyCode, assets::Asset};
+use crate::{AppState, Event, KeyCode, assets::Asset, clock};
 
 #[derive(Clone, Default)]
 pub struct TrackInspector {
@@ -34,6 +40,11 @@ pub enum TrackInspectorSelectedField {
     Art,
     Artist,
     Tags,
+    Bpm,
+    Downbeat,
+    Key,
+    CueIn,
+    CueOut,
 }
 
 impl TrackInspectorSelectedField {
@@ -43,17 +54,27 @@ impl TrackInspectorSelectedField {
             Self::Name => Self::Art,
             Self::Art => Self::Artist,
             Self::Artist => Self::Tags,
-            Self::Tags => Self::None,
+            Self::Tags => Self::Bpm,
+            Self::Bpm => Self::Downbeat,
+            Self::Downbeat => Self::Key,
+            Self::Key => Self::CueIn,
+            Self::CueIn => Self::CueOut,
+            Self::CueOut => Self::None,
         }
     }
 
     pub fn prev(self) -> Self {
         match self {
-            Self::None => Self::Tags,
+            Self::None => Self::CueOut,
             Self::Name => Self::None,
             Self::Art => Self::Name,
             Self::Artist => Self::Art,
             Self::Tags => Self::Artist,
+            Self::Bpm => Self::Tags,
+            Self::Downbeat => Self::Bpm,
+            Self::Key => Self::Downbeat,
+            Self::CueIn => Self::Key,
+            Self::CueOut => Self::CueIn,
         }
     }
 }
@@ -71,7 +92,11 @@ impl Display for TrackInspector {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(lock) = self.track.upgrade() {
             if let Ok(track) = lock.read() {
-                write!(f, "{}", track.name)
+                if track.missing {
+                    write!(f, "{} (missing)", track.name)
+                } else {
+                    write!(f, "{}", track.name)
+                }
             } else {
                 write!(f, "error")
             }
@@ -97,7 +122,7 @@ impl StatefulWidget for TrackInspector {
     ) {
         let width = area.width;
 
-        let (name, artist, path, tags_list);
+        let (name, artist, path, tags_list, properties, loudness, bpm, downbeat, key, cue_points);
         let art = if let Some(lock) = self.track.upgrade() {
             let track = if let Ok(track) = lock.read() {
                 track
@@ -108,6 +133,15 @@ impl StatefulWidget for TrackInspector {
             artist = track.artist.clone();
             path = track.path.clone();
             tags_list = track.tags.clone();
+            properties = state.library.properties(&track);
+            loudness = (
+                state.library.loudness(&track),
+                state.library.album_loudness(&track),
+            );
+            bpm = track.bpm;
+            downbeat = track.downbeat;
+            key = track.key;
+            cue_points = [("cue in", track.cue_in), ("cue out", track.cue_out)];
             let art_path = track.album_art.as_ref();
 
             if let Some(path) = art_path {
@@ -182,6 +216,12 @@ impl StatefulWidget for TrackInspector {
             artist = None;
             path = "".into();
             tags_list = Vec::new();
+            properties = None;
+            loudness = (None, None);
+            bpm = None;
+            downbeat = None;
+            key = None;
+            cue_points = [("cue in", None), ("cue out", None)];
             None
         };
         let tags = tags_list.join(", ");
@@ -201,8 +241,65 @@ impl StatefulWidget for TrackInspector {
         };
         let tags_text = format!("tags: {tags}");
         let tags_wrapped = textwrap::wrap(&tags_text, width as usize);
+        let bpm_text = match bpm {
+            Some(bpm) => format!("bpm: {bpm:.2}"),
+            None => String::from("bpm:"),
+        };
+        let downbeat_text = match downbeat {
+            Some(downbeat) => format!("downbeat: {:.3} s", downbeat.as_secs_f64()),
+            None => String::from("downbeat:"),
+        };
+        let key_text = match key {
+            Some(key) => format!("key: {key} ({})", key.camelot()),
+            None => String::from("key:"),
+        };
+        let [cue_in_text, cue_out_text] = cue_points.map(|(label, cue_point)| match cue_point {
+            Some(cue_point) => format!("{label}: {:.3} s", cue_point.as_secs_f64()),
+            None => format!("{label}:"),
+        });
         let path_text = format!("path: {path}");
         let path_wrapped = textwrap::wrap(path_text.as_ref(), width as usize);
+        // read only, they come from analysing the file
+        let properties_text = match properties {
+            Some(properties) => {
+                let mut format = format!(
+                    "{}, {} Hz, {} channels",
+                    properties.codec, properties.sample_rate, properties.channels
+                );
+                if let Some(bit_depth) = properties.bit_depth {
+                    format.push_str(&format!(", {bit_depth} bit"));
+                }
+                let mut lines = vec![
+                    format!("duration: {}", clock(properties.duration)),
+                    format!("format: {format}"),
+                ];
+                if let Some(bitrate) = properties.bitrate {
+                    lines.push(format!("bitrate: {} kbps", bitrate / 1000));
+                }
+                let (track_loudness, album_loudness) = loudness;
+                for (label, loudness) in [("loudness", track_loudness), ("album", album_loudness)] {
+                    if let Some(loudness) = loudness {
+                        lines.push(format!(
+                            "{label}: {:.1} LUFS, range {:.1} LU, peak {:.1} dBTP",
+                            loudness.integrated, loudness.range, loudness.true_peak
+                        ));
+                    }
+                }
+                lines.join("\n")
+            }
+            None => String::from("not analysed yet"),
+        };
+        // scanned, but playback would refuse it
+        let properties_text = match formats::by_extension(Path::new(path.as_ref()))
+            .filter(|format| !format.playable)
+        {
+            Some(format) => format!(
+                "{properties_text}\ncan't be played, no {} decoder",
+                format.name
+            ),
+            None => properties_text,
+        };
+        let properties_wrapped = textwrap::wrap(&properties_text, width as usize);
 
         let editing_tags = self.selected_field == TrackInspectorSelectedField::Tags
             && self.editing_value.is_some();
@@ -243,7 +340,13 @@ impl StatefulWidget for TrackInspector {
             art_area,
             artist_area,
             tags_area,
+            bpm_area,
+            downbeat_area,
+            key_area,
+            cue_in_area,
+            cue_out_area,
             path_area,
+            properties_area,
             _,
             tag_editor_area,
             edit_area,
@@ -252,7 +355,13 @@ impl StatefulWidget for TrackInspector {
             c::Length(art_constraint),
             c::Length(artist_wrapped_len as u16),
             c::Length(tags_wrapped.len() as u16),
+            c::Length(1),
+            c::Length(1),
+            c::Length(1),
+            c::Length(1),
+            c::Length(1),
             c::Length(path_wrapped.len() as u16),
+            c::Length(properties_wrapped.len() as u16),
             c::Fill(1),
             c::Length(tag_editor_constraint as u16),
             c::Length(3),
@@ -267,9 +376,17 @@ impl StatefulWidget for TrackInspector {
             Paragraph::new("artist:").wrap(Wrap { trim: false })
         };
         let mut tags = Paragraph::new(tags_text).wrap(Wrap { trim: false });
+        let mut bpm = Paragraph::new(bpm_text);
+        let mut downbeat = Paragraph::new(downbeat_text);
+        let mut key = Paragraph::new(key_text);
+        let mut cue_in = Paragraph::new(cue_in_text);
+        let mut cue_out = Paragraph::new(cue_out_text);
         let path = Paragraph::new(path_text)
             .wrap(Wrap { trim: false })
             .fg(Color::Gray);
+        let properties = Paragraph::new(properties_text)
+            .wrap(Wrap { trim: false })
+            .fg(Color::Gray);
 
         let mut edit_message = vec![
             Span::from("press "),
@@ -297,6 +414,11 @@ impl StatefulWidget for TrackInspector {
                     Span::from(" to clear all tags"),
                 ];
             }
+            TrackInspectorSelectedField::Bpm => bpm = bpm.fg(Color::Green),
+            TrackInspectorSelectedField::Downbeat => downbeat = downbeat.fg(Color::Green),
+            TrackInspectorSelectedField::Key => key = key.fg(Color::Green),
+            TrackInspectorSelectedField::CueIn => cue_in = cue_in.fg(Color::Green),
+            TrackInspectorSelectedField::CueOut => cue_out = cue_out.fg(Color::Green),
         }
         if self.selected_field != TrackInspectorSelectedField::None {
             if let Some(value) = self.editing_value {
@@ -331,7 +453,13 @@ impl StatefulWidget for TrackInspector {
 
         title.render(title_area, buf);
         artist.render(artist_area, buf);
+        bpm.render(bpm_area, buf);
+        downbeat.render(downbeat_area, buf);
+        key.render(key_area, buf);
+        cue_in.render(cue_in_area, buf);
+        cue_out.render(cue_out_area, buf);
         path.render(path_area, buf);
+        properties.render(properties_area, buf);
         ratatui::prelude::StatefulWidget::render(known_tags, tag_editor_area, buf, &mut list_state);
         tags.render(tags_area, buf);
 
@@ -380,6 +508,7 @@ pub fn handle_inspector_events(event: &Event, state: &mut AppState) -> bool {
                                 TrackInspectorSelectedField::None => return false,
                                 TrackInspectorSelectedField::Name => {
                                     track.name = value.as_str().into();
+                                    track.edited.insert(TrackField::Name);
                                 }
                                 TrackInspectorSelectedField::Art => return false,
                                 TrackInspectorSelectedField::Artist => {
@@ -388,6 +517,7 @@ pub fn handle_inspector_events(event: &Event, state: &mut AppState) -> bool {
                                     } else {
                                         track.artist = None;
                                     }
+                                    track.edited.insert(TrackField::Artist);
                                 }
                                 TrackInspectorSelectedField::Tags => {
                                     if value.as_str() != "" {
@@ -395,6 +525,33 @@ pub fn handle_inspector_events(event: &Event, state: &mut AppState) -> bool {
                                         state.library.add_tag(&lock, value);
                                     }
                                 }
+                                TrackInspectorSelectedField::Bpm => edit_beat_grid(
+                                    &mut track,
+                                    TrackField::Bpm,
+                                    value,
+                                    &state.library,
+                                ),
+                                TrackInspectorSelectedField::Downbeat => edit_beat_grid(
+                                    &mut track,
+                                    TrackField::Downbeat,
+                                    value,
+                                    &state.library,
+                                ),
+                                TrackInspectorSelectedField::Key => {
+                                    edit_key(&mut track, value, &state.library)
+                                }
+                                TrackInspectorSelectedField::CueIn => edit_cue_point(
+                                    &mut track,
+                                    TrackField::CueIn,
+                                    value,
+                                    &state.library,
+                                ),
+                                TrackInspectorSelectedField::CueOut => edit_cue_point(
+                                    &mut track,
+                                    TrackField::CueOut,
+                                    value,
+                                    &state.library,
+                                ),
                             }
                         }
                     }
@@ -411,6 +568,7 @@ pub fn handle_inspector_events(event: &Event, state: &mut AppState) -> bool {
                                 } else {
                                     track.album_art = None;
                                 }
+                                track.edited.insert(TrackField::AlbumArt);
                                 return true;
                             }
                             TrackInspectorSelectedField::Artist => {
@@ -421,6 +579,24 @@ pub fn handle_inspector_events(event: &Event, state: &mut AppState) -> bool {
                                 }
                             }
                             TrackInspectorSelectedField::Tags => String::new(),
+                            TrackInspectorSelectedField::Bpm => {
+                                track.bpm.map(|bpm| bpm.to_string()).unwrap_or_default()
+                            }
+                            TrackInspectorSelectedField::Downbeat => track
+                                .downbeat
+                                .map(|downbeat| downbeat.as_secs_f64().to_string())
+                                .unwrap_or_default(),
+                            TrackInspectorSelectedField::Key => {
+                                track.key.map(|key| key.to_string()).unwrap_or_default()
+                            }
+                            TrackInspectorSelectedField::CueIn => track
+                                .cue_in
+                                .map(|cue_in| cue_in.as_secs_f64().to_string())
+                                .unwrap_or_default(),
+                            TrackInspectorSelectedField::CueOut => track
+                                .cue_out
+                                .map(|cue_out| cue_out.as_secs_f64().to_string())
+                                .unwrap_or_default(),
                         };
 
                         inspector.editing_value = Some(value);
@@ -527,3 +703,114 @@ pub fn handle_inspector_events(event: &Event, state: &mut AppState) -> bool {
         _ => false,
     }
 }
+
+/// Sets the bpm, or the downbeat in seconds, to what was typed. Clearing it goes back to what
+/// analysis found.
+fn edit_beat_grid(track: &mut Track, field: TrackField, value: &str, library: &MusicLibrary) {
+    let value = value.trim();
+    if value.is_empty() {
+        track.edited.remove(&field);
+        match field {
+            TrackField::Bpm => track.bpm = None,
+            _ => track.downbeat = None,
+        }
+        if let Some(analysis) = library.analysis(track) {
+            track.apply_analysis(&analysis);
+        }
+        return;
+    }
+    let Some(number) = value
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite() && *number >= 0.0)
+    else {
+        return;
+    };
+    match field {
+        TrackField::Bpm if number > 0.0 => track.bpm = Some(number),
+        TrackField::Downbeat => {
+            let Ok(downbeat) = Duration::try_from_secs_f64(number) else {
+                return;
+            };
+            if track_length(track, library).is_some_and(|length| downbeat > length) {
+                warn!("the downbeat has to be inside the track");
+                return;
+            }
+            track.downbeat = Some(downbeat);
+        }
+        _ => return,
+    }
+    track.edited.insert(field);
+}
+
+/// How long the whole track is, cue points and all, as far as analysis knows.
+fn track_length(track: &Track, library: &MusicLibrary) -> Option<Duration> {
+    let properties = library.analysis(track)?.properties;
+    let (start, end) = track
+        .cue
+        .as_ref()
+        .map_or((Duration::ZERO, None), |cue| (cue.start, cue.end));
+    Some(properties.range(start, end).duration)
+}
+
+/// Sets the key to what was typed, in either notation, like `Am` or `8A`. Clearing it goes back
+/// to what analysis found.
+fn edit_key(track: &mut Track, value: &str, library: &MusicLibrary) {
+    let value = value.trim();
+    if value.is_empty() {
+        track.edited.remove(&TrackField::Key);
+        track.key = None;
+        if let Some(analysis) = library.analysis(track) {
+            track.apply_analysis(&analysis);
+        }
+        return;
+    }
+    match value.parse() {
+        Ok(key) => {
+            track.key = Some(key);
+            track.edited.insert(TrackField::Key);
+        }
+        Err(e) => warn!("{e}"),
+    }
+}
+
+/// Sets the cue in or cue out point, in seconds from the start of the track, to what was typed.
+/// Cue out has to come after cue in. Clearing it goes back to what analysis found.
+fn edit_cue_point(track: &mut Track, field: TrackField, value: &str, library: &MusicLibrary) {
+    let value = value.trim();
+    if value.is_empty() {
+        track.edited.remove(&field);
+        match field {
+            TrackField::CueIn => track.cue_in = None,
+            _ => track.cue_out = None,
+        }
+        if let Some(analysis) = library.analysis(track) {
+            track.apply_analysis(&analysis);
+        }
+        return;
+    }
+    let Some(time) = value
+        .parse::<f64>()
+        .ok()
+        .and_then(|number| Duration::try_from_secs_f64(number).ok())
+    else {
+        return;
+    };
+    let time = match track_length(track, library) {
+        Some(length) => time.min(length),
+        None => time,
+    };
+    let (cue_in, cue_out) = match field {
+        TrackField::CueIn => (time, track.cue_out),
+        _ => (track.cue_in.unwrap_or_default(), Some(time)),
+    };
+    if cue_out.is_some_and(|cue_out| cue_out <= cue_in) {
+        warn!("cue out has to come after cue in");
+        return;
+    }
+    match field {
+        TrackField::CueIn => track.cue_in = Some(time),
+        _ => track.cue_out = Some(time),
+    }
+    track.edited.insert(field);
+}
@@ -1,4 +1,6 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
+
+use segue_attacca_lib::playback::PlaybackItem;
 
 use crate::{
     AppState,
@@ -6,37 +8,110 @@ use crate::{
     track_inspector::TrackInspector,
 };
 
+const SEEK_STEP: Duration = Duration::from_secs(10);
+
 pub fn handle_track_list_events(event: &Event, state: &mut AppState) -> bool {
+    if state.searching {
+        return handle_search_events(event, state);
+    }
     match event {
+        Event::KeyPressed(KeyCode::Enter, _) => {
+            let Some(track) = state.selected_track() else {
+                return false;
+            };
+            if let Ok(track) = track.read() {
+                state
+                    .playback
+                    .play(PlaybackItem::new(&state.library, &track));
+            }
+            true
+        }
         Event::KeyPressed(KeyCode::Char(c), _) => match c {
             'j' => {
                 state.list_state.select_next();
-                if let Some(track) = state
-                    .library
-                    .get_tracks()
-                    .get(state.list_state().selected().unwrap_or(0))
-                {
-                    state.track_inspector = Some(TrackInspector::new(Arc::downgrade(track)));
-                } else {
-                    state.track_inspector = None;
-                }
+                inspect_selected(state);
                 true
             }
             'k' => {
                 state.list_state.select_previous();
-                if let Some(track) = state
-                    .library
-                    .get_tracks()
-                    .get(state.list_state().selected().unwrap_or(0))
-                {
-                    state.track_inspector = Some(TrackInspector::new(Arc::downgrade(track)));
-                } else {
-                    state.track_inspector = None;
+                inspect_selected(state);
+                true
+            }
+            'a' => {
+                let Some(track) = state.selected_track() else {
+                    return false;
+                };
+                if let Ok(track) = track.read() {
+                    state
+                        .playback
+                        .enqueue(PlaybackItem::new(&state.library, &track));
                 }
                 true
             }
+            'n' => {
+                state.playback.next();
+                true
+            }
+            ' ' => {
+                state.playback.toggle_pause();
+                true
+            }
+            's' => {
+                state.playback.stop();
+                true
+            }
+            'h' => {
+                let position = state.playback.position().saturating_sub(SEEK_STEP);
+                state.playback.seek(position);
+                true
+            }
+            'l' => {
+                let position = state.playback.position() + SEEK_STEP;
+                state.playback.seek(position);
+                true
+            }
+            '/' => {
+                state.searching = true;
+                true
+            }
+            'o' => {
+                state.sort = state.sort.next();
+                state.refresh_list();
+                true
+            }
             _ => false,
         },
         _ => false,
     }
 }
+
+/// Typing a search, which filters the list as it goes. Enter keeps it, escape clears it.
+fn handle_search_events(event: &Event, state: &mut AppState) -> bool {
+    match event {
+        Event::KeyPressed(KeyCode::Char(c), _) => state.search.push(*c),
+        Event::KeyPressed(KeyCode::Backspace, _) => {
+            state.search.pop();
+        }
+        Event::KeyPressed(KeyCode::Enter, _) => {
+            state.searching = false;
+            return true;
+        }
+        Event::KeyPressed(KeyCode::Escape, _) => {
+            state.search.clear();
+            state.searching = false;
+        }
+        _ => return false,
+    }
+    state.refresh_list();
+    state
+        .list_state
+        .select((!state.list.is_empty()).then_some(0));
+    inspect_selected(state);
+    true
+}
+
+fn inspect_selected(state: &mut AppState) {
+    state.track_inspector = state
+        .selected_track()
+        .map(|track| TrackInspector::new(Arc::downgrade(&track)));
+}